@@ -0,0 +1,34 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![feature(slicing_syntax)]
+#![allow(unstable)]
+
+extern crate reprint;
+
+use reprint::{Change, ChangeSet, ReprintOptions};
+
+// Replaces bytes 3..8 of the file given on the command line (e.g., the `Hello`
+// of `// Hello world`) with `Goodbye cruel`.
+fn main() {
+    let args = std::os::args();
+    if args.len() != 2 {
+        let _ = writeln!(&mut std::io::stderr(), "Usage: {} <file>", args[0]);
+        std::os::set_exit_status(1);
+        return;
+    }
+
+    let change = Change::new(Path::new(&args[1][]), 3, 8, "Goodbye cruel".to_string());
+    let changes = ChangeSet::from_vec(vec![change]);
+    if let Err(e) = reprint::reprint_with(changes, ReprintOptions::default()) {
+        let _ = writeln!(&mut std::io::stderr(), "{}", e);
+        std::os::set_exit_status(1);
+    }
+}
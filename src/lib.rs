@@ -12,226 +12,8035 @@
 #![feature(slicing_syntax)]
 #![allow(unstable)]
 
-use std::io::{File, FileMode, FileAccess};
+#[cfg(feature = "sha256")]
+extern crate "rust-crypto" as crypto;
+#[cfg(feature = "flate2")]
+extern crate flate2;
+#[cfg(feature = "regex")]
+extern crate regex;
+#[cfg(feature = "serialize")]
+extern crate "rustc-serialize" as rustc_serialize;
+#[cfg(feature = "serialize")]
+extern crate time;
+#[cfg(all(feature = "xattr", target_os = "linux"))]
+extern crate xattr;
+#[cfg(all(any(feature = "lock", feature = "fd"), unix))]
+extern crate libc;
+#[cfg(feature = "toml")]
+extern crate toml;
+#[cfg(feature = "encoding")]
+extern crate encoding;
+
+use std::borrow::Cow;
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::iter::{FromIterator, IntoIterator};
+use std::mem;
+use std::num::Int;
+use std::ops::{Deref, DerefMut, Range};
+use std::str;
+use std::io::{File, FileMode, FileAccess, IoError, IoErrorKind, TempDir};
 use std::path::GenericPath;
+use std::string::CowString;
+use std::time::Duration;
 use std::io::fs::{self, PathExtensions};
+#[cfg(not(feature = "sha256"))]
+use std::hash::{Hasher, SipHasher};
+#[cfg(not(feature = "sha256"))]
+use std::hash::Writer as HashWriter;
+
+#[cfg(feature = "sha256")]
+use crypto::digest::Digest;
+#[cfg(feature = "sha256")]
+use crypto::sha2::Sha256;
 
 
+#[derive(Clone, Show)]
 pub struct Change {
     path: Path,
     start_byte: u32,
     end_byte: u32,
-    text: String
+    text: String,
+    // The offsets count back from the end of the file (see Change::from_end).
+    from_end: bool,
+    // `text` is a template (see Change::template).
+    template: bool,
+    // What produced the change, if known (see Change::with_source).
+    source: Option<String>,
+    // The text the change must replace, if any (see Change::guarded).
+    guard: Option<String>,
 }
 
-pub type ChangeSet = Vec<Change>;
+// A set of changes, possibly to several files. Derefs to the underlying Vec.
+pub struct ChangeSet(Vec<Change>);
 
-pub fn reprint(mut changes: ChangeSet) {
-    changes.sort();
-    let paths = paths(&changes);
-    for file in paths.iter() {
-        if let Err(msg) = verify(&changes) {
-            println!("Verification error: {}",  msg);
-            return;
+impl ChangeSet {
+    pub fn new() -> ChangeSet {
+        ChangeSet(vec![])
+    }
+
+    pub fn from_vec(changes: Vec<Change>) -> ChangeSet {
+        ChangeSet(changes)
+    }
+
+    pub fn into_vec(self) -> Vec<Change> {
+        self.0
+    }
+
+    // Changes to `path` replacing each `(start_byte, end_byte)` key of `map`
+    // with its value. The map's order is the change order, so no sort is
+    // needed, but the result is still verified.
+    pub fn from_map(path: &Path,
+                    map: BTreeMap<(u32, u32), String>)
+    -> Result<ChangeSet, ReprintError> {
+        let changes: Vec<Change> = map.into_iter().map(|((start, end), text)| {
+            Change::new(path.clone(), start, end, text)
+        }).collect();
+        try!(verify(&changes[]));
+        Ok(ChangeSet(changes))
+    }
+
+    // Checks the changes to each file are well formed and don't overlap, without
+    // applying them. The set must already be sorted (e.g., by normalize);
+    // otherwise an out of order pair may be reported as an overlap, or missed.
+    pub fn verify(&self) -> Result<(), ReprintError> {
+        for &(_, changes) in files(self).iter() {
+            try!(verify(changes));
         }
+        Ok(())
+    }
 
-        let input = match read_file(*file) {
-            Ok(i) => i,
-            Err(msg) => {
-                println!("Error reading file: {}",  msg);
-                return;
-            }
-        };
+    // Normalizes the set in place, as normalize_with with `opts.dedup_key`,
+    // then tidies it up without changing what applying it would do: empty
+    // changes are dropped if `opts.drop_empty_changes` is set, and changes
+    // which meet, one ending where the next starts, are coalesced into one.
+    // Changes relative to the end, templates, guarded changes, and changes from
+    // different sources are not coalesced.
+    // Reports what was done; if the set doesn't verify, it is left sorted and
+    // without duplicates.
+    pub fn normalize_in_place(&mut self,
+                              opts: &ReprintOptions)
+    -> Result<NormalizeReport, ReprintError> {
+        let mut report = NormalizeReport::default();
+        report.reordered = self.0.windows(2).any(|w| w[0] > w[1]);
+        let len = self.0.len();
+        try!(dedup(&mut self.0, opts.dedup_key));
+        report.duplicates = len - self.0.len();
+        for &(_, changes) in files(&*self).iter() {
+            try!(verify(changes));
+        }
 
-        let changes_size = changes.iter().fold(0i64, |a, c| a + c.delta());
-        let mut buf: Vec<u8> = Vec::with_capacity((input.as_bytes().len() as i64 +
-                                                   changes_size) as usize);
-        match process(input, &changes, &mut buf) {
-            Ok(()) => {
-                if let Err(msg) = write_file(*file, buf) {
-                    println!("Error writing file: {}",  msg);
-                    return;
+        if opts.drop_empty_changes {
+            let len = self.0.len();
+            self.0.retain(|c| !c.is_noop());
+            report.dropped = len - self.0.len();
+        }
+
+        let mut result: Vec<Change> = Vec::with_capacity(self.0.len());
+        for ch in mem::replace(&mut self.0, vec![]).into_iter() {
+            if let Some(last) = result.last_mut() {
+                if last.path == ch.path &&
+                   last.end_byte == ch.start_byte &&
+                   !last.from_end && !ch.from_end &&
+                   !last.template && !ch.template &&
+                   last.guard.is_none() && ch.guard.is_none() &&
+                   last.source == ch.source {
+                    last.end_byte = ch.end_byte;
+                    last.text.push_str(&ch.text[]);
+                    report.coalesced += 1;
+                    continue;
                 }
             }
-            Err(msg) => {
-                println!("Error processing changes: {}",  msg);
-                return;
-            }
+            result.push(ch);
         }
+        self.0 = result;
+        Ok(report)
     }
-}
 
-fn paths<'a>(changes: &'a ChangeSet) -> Vec<&'a Path> {
-    let mut result = vec![];
-    let mut cur: Option<&Path> = None;
-    for c in changes.iter() {
-        if cur.is_none() || cur.unwrap() != &c.path {
-            cur = Some(&c.path);
-            result.push(&c.path);
-        }
+    // See apply.
+    pub fn apply(self, input: &str) -> Result<String, ReprintError> {
+        apply(input, &self)
     }
-    result
-}
 
-// Assumes changes is sorted.
-fn verify(changes: &ChangeSet) -> Result<(), String> {
-    let mut prev_start = 0;
-    let mut prev_end = 0;
-    for ch in changes.iter() {
-        if ch.end_byte < ch.start_byte {
-            return Err(format!("Bad change at {}", ch.start_byte));
+    // Edits `path`, as reprint_with with the default options. Every change must
+    // be for `path`.
+    pub fn apply_to_file(self, path: &Path) -> Result<ReprintSummary, ReprintError> {
+        try!(check_paths(path, &self));
+        let changes = try!(normalize(self));
+        reprint_file(path, &changes[], &ReprintOptions::default())
+    }
+
+    // Inserts `change` in order, assuming the set is already sorted and
+    // verified (e.g., because it was built with try_push). Only the changes
+    // either side of the new one need to be checked for overlap. As with
+    // normalize, a duplicate of a change already in the set is dropped.
+    pub fn try_push(&mut self, change: Change) -> Result<(), ReprintError> {
+        if change.from_end {
+            return Err(ReprintError::Verification(
+                "Changes relative to the end must be resolved before try_push".to_string()));
         }
-        if ch.start_byte < prev_end {
-            return Err(format!("Overlapping changes: {}--{} overlaps {}--{} ",
-                               prev_start,
-                               prev_end,
-                               ch.start_byte,
-                               ch.end_byte));            
+        try!(verify_change(&change));
+        let i = match self.0.binary_search_by(|c| c.cmp(&change)) {
+            Ok(_) => return Ok(()),
+            Err(i) => i
+        };
+        if i > 0 {
+            try!(verify_pair((i - 1, &self.0[i - 1]), (i, &change)));
+        }
+        if i < self.0.len() {
+            try!(verify_pair((i, &change), (i + 1, &self.0[i])));
         }
-        prev_start = ch.start_byte;
-        prev_end = ch.end_byte;
+        self.0.insert(i, change);
+        Ok(())
     }
+}
 
-    Ok(())
+impl Deref for ChangeSet {
+    type Target = Vec<Change>;
+
+    fn deref(&self) -> &Vec<Change> {
+        &self.0
+    }
 }
 
-fn read_file(file: &Path) -> Result<String, String> {
-    let file = File::open(file);
-    let mut file = match file {
-        Ok(f) => f,
-        Err(e) => return Err(e.desc.to_string())
-    };
+impl DerefMut for ChangeSet {
+    fn deref_mut(&mut self) -> &mut Vec<Change> {
+        &mut self.0
+    }
+}
 
-    match file.read_to_string() {
-        Ok(contents) => Ok(contents),
-        Err(e) => Err(e.desc.to_string())
+// Collecting into a change set keeps the changes in the order they come, it
+// doesn't sort or verify them (see normalize).
+impl FromIterator<Change> for ChangeSet {
+    fn from_iter<I: Iterator<Item=Change>>(iter: I) -> ChangeSet {
+        ChangeSet(iter.collect())
     }
 }
 
-// precondition: changes == changes.sort() && verify(changes)
-fn process(input: String,
-           changes: &ChangeSet,
-           buf: &mut Vec<u8>)
--> Result<(), String> {
-    let input = input.as_bytes();
-    // Current position in the input.
-    let mut in_pos = 0us;
-    for ch in changes.iter() {
-        if in_pos >= input.len() {
-            return Err(format!("Input out of range. {} >= {}", in_pos, input.len()));
-        }
-        if ch.start_byte as usize >= input.len() {
-            return Err(format!("Change out of range for input. {} >= {}",
-                               ch.start_byte,
-                               input.len()));
+impl Extend<Change> for ChangeSet {
+    fn extend<I: Iterator<Item=Change>>(&mut self, iter: I) {
+        self.0.extend(iter)
+    }
+}
+
+impl IntoIterator for ChangeSet {
+    type Item = Change;
+    type IntoIter = std::vec::IntoIter<Change>;
+
+    fn into_iter(self) -> std::vec::IntoIter<Change> {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a ChangeSet {
+    type Item = &'a Change;
+    type IntoIter = std::slice::Iter<'a, Change>;
+
+    fn into_iter(self) -> std::slice::Iter<'a, Change> {
+        self.0.iter()
+    }
+}
+
+// What ChangeSet::normalize_in_place did.
+#[derive(Clone, Copy, Default, Show)]
+pub struct NormalizeReport {
+    // Whether the changes had to be sorted.
+    pub reordered: bool,
+    // The number of exact duplicates removed.
+    pub duplicates: usize,
+    // The number of empty changes dropped.
+    pub dropped: usize,
+    // The number of changes merged into the change before them.
+    pub coalesced: usize,
+}
+
+// A change set which is always sorted, verified and free of duplicates, since
+// changes can only be added by try_push. It can be reprinted without being
+// normalized (see reprint_sorted).
+pub struct SortedChangeSet(ChangeSet);
+
+impl SortedChangeSet {
+    pub fn new() -> SortedChangeSet {
+        SortedChangeSet(ChangeSet::new())
+    }
+
+    // Normalizes `changes`. As for try_push, changes relative to the end of the
+    // file are not allowed.
+    pub fn from_change_set(changes: ChangeSet) -> Result<SortedChangeSet, ReprintError> {
+        if changes.iter().any(|c| c.from_end) {
+            return Err(ReprintError::Verification(
+                "Changes relative to the end must be resolved first".to_string()));
         }
-        buf.push_all(&input[in_pos..ch.start_byte as usize]);
+        Ok(SortedChangeSet(try!(normalize(changes))))
+    }
 
-        let text = ch.text.as_bytes();
-        buf.push_all(text);
-        in_pos = ch.end_byte as usize;
+    // See ChangeSet::try_push.
+    pub fn try_push(&mut self, change: Change) -> Result<(), ReprintError> {
+        self.0.try_push(change)
     }
 
-    // Push the rest of the input onto the output.
-    buf.push_all(&input[in_pos..]);
-    Ok(())
+    pub fn into_change_set(self) -> ChangeSet {
+        self.0
+    }
 }
 
-fn write_file(input_path: &Path, buf: Vec<u8>) -> Result<(), String> {
-    // Prepare file names.
-    let input_name = match input_path.as_str() {
-        Some(n) => n.to_string(),
-        None => return Err(format!("Couldn't turn path '{}' into a string", input_path.display()))
-    };
+// Read only, so the invariants can't be broken.
+impl Deref for SortedChangeSet {
+    type Target = ChangeSet;
 
-    let tmp_path = Path::new(input_name.clone() + ".tmp");
-    let bk_path = Path::new(input_name.clone() + ".bk");
-    if tmp_path.exists() {
-        return Err(format!("File '{}' already exists", tmp_path.display()))
+    fn deref(&self) -> &ChangeSet {
+        &self.0
     }
-    if bk_path.exists() {
-        return Err(format!("File '{}' already exists", bk_path.display()))
+}
+
+#[derive(Clone, PartialEq, Show)]
+pub enum ReprintError {
+    // The change set is malformed, e.g., changes overlap.
+    Verification(String),
+    // Reading or writing a file failed.
+    Io(String),
+    // The changes could not be applied to a file's contents.
+    Process(String),
+    // A file's contents don't match ReprintOptions::expected_len/expected_hash.
+    InputChanged,
+    // A file we need to create (e.g., the temp file) already exists.
+    AlreadyExists(Path),
+    // Couldn't lock a file within ReprintOptions::lock_timeout.
+    LockTimeout(Path),
+    // The change set is bigger than ReprintOptions::max_changes.
+    TooManyChanges { count: usize, limit: usize },
+    // The output would be too long for byte offsets (u32) to address; holds
+    // the output's length.
+    OutputTooLarge(u64),
+    // The text of the `index`th change to a file contains a NUL byte (see
+    // ReprintOptions::reject_nul).
+    ContainsNul { index: usize },
+    // Two changes to a file replace text starting at the same offset.
+    SameStartConflict { start: u32 },
+    // Adding up the changes' effect on the size of a file overflowed.
+    SizeOverflow,
+    // Line `line` of the output would be `len` characters long, more than
+    // ReprintOptions::max_line_length (see ReprintOptions::strict).
+    LineTooLong { line: u32, len: usize },
+    // ReprintOptions::post_validate rejected the output, with this message.
+    PostValidationFailed(String),
+    // The output isn't UTF-8, from byte `pos` (see ReprintOptions::validate_utf8).
+    InvalidUtf8 { pos: usize },
+    // The `inner`th change to a file is wholly inside the `outer`th, counting
+    // from 0 in sorted order. A more specific kind of overlap.
+    ContainedChange { outer: usize, inner: usize },
+    // The edit would change `lines` lines of a file, more than
+    // ReprintOptions::max_lines_changed, `limit`.
+    ThresholdExceeded { lines: usize, limit: usize },
+    // The text replaced by the `index`th change to a file, as applied, isn't
+    // what the change's guard expects (see Change::guarded).
+    GuardFailed { index: usize, expected: String, found: String },
+    // reprint_transaction failed with `cause`, then restoring some of the files
+    // it had already replaced failed with `rollback`, so they are left edited.
+    RollbackFailed { cause: Box<ReprintError>, rollback: Vec<ReprintError> },
+}
+
+impl fmt::String for ReprintError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReprintError::Verification(ref msg) => write!(f, "Verification error: {}", msg),
+            ReprintError::Io(ref msg) => write!(f, "I/O error: {}", msg),
+            ReprintError::Process(ref msg) => write!(f, "Error processing changes: {}", msg),
+            ReprintError::InputChanged => {
+                write!(f, "File has changed since the changes were computed")
+            }
+            ReprintError::AlreadyExists(ref path) => {
+                write!(f, "File '{}' already exists", path.display())
+            }
+            ReprintError::LockTimeout(ref path) => {
+                write!(f, "Timed out waiting to lock '{}'", path.display())
+            }
+            ReprintError::TooManyChanges { count, limit } => {
+                write!(f, "Too many changes: {} (the limit is {})", count, limit)
+            }
+            ReprintError::OutputTooLarge(len) => {
+                write!(f, "Output of {} bytes is too large", len)
+            }
+            ReprintError::ContainsNul { index } => {
+                write!(f, "The text of change {} contains a NUL byte", index)
+            }
+            ReprintError::SameStartConflict { start } => {
+                write!(f, "Two changes replace text starting at {}", start)
+            }
+            ReprintError::SizeOverflow => write!(f, "The size of the output overflowed"),
+            ReprintError::LineTooLong { line, len } => {
+                write!(f, "Line {} of the output would be too long ({} characters)", line, len)
+            }
+            ReprintError::PostValidationFailed(ref msg) => {
+                write!(f, "The output failed validation: {}", msg)
+            }
+            ReprintError::InvalidUtf8 { pos } => {
+                write!(f, "The output is not valid UTF-8 at byte {}", pos)
+            }
+            ReprintError::ContainedChange { outer, inner } => {
+                write!(f, "Change {} is inside change {}", inner, outer)
+            }
+            ReprintError::ThresholdExceeded { lines, limit } => {
+                write!(f, "The edit changes {} lines (the limit is {})", lines, limit)
+            }
+            ReprintError::GuardFailed { index, ref expected, ref found } => {
+                write!(f, "Change {} expected to replace '{}' but found '{}'",
+                       index,
+                       expected,
+                       found)
+            }
+            ReprintError::RollbackFailed { ref cause, ref rollback } => {
+                let rollback: Vec<String> = rollback.iter().map(|e| e.to_string()).collect();
+                write!(f, "{}; then rolling back failed: {}", cause, rollback.connect("; "))
+            }
+        }
     }
+}
 
-    // Write to temporary file.
-    let mut tmp_file = match File::open_mode(&tmp_path,
-                                             FileMode::Open,
-                                             FileAccess::Write) {
-        Ok(f) => f,
-        Err(e) => return Err(format!("Couldn't open '{}': {}", tmp_path.display(), e.desc))
-    };
-    match tmp_file.write(&buf[]) {
-        Ok(()) => {}
-        Err(e) => return Err(format!("Couldn't write to '{}': {}", tmp_path.display(), e.desc))
+#[derive(Default)]
+pub struct ReprintOptions {
+    // Compute a digest of each output file (see ReprintSummary::hash).
+    pub hash_output: bool,
+    // If set, refuse to edit a file unless its contents have this length, or
+    // this hash (as computed by content_hash). Intended for single file edits,
+    // since every file in the change set is checked against the same values.
+    pub expected_len: Option<u64>,
+    pub expected_hash: Option<String>,
+    // Applied to the text of every change before it is spliced in, e.g., to
+    // run inserted code through rustfmt. Sizes and offsets in the output come
+    // from the formatted text.
+    pub formatter: Option<Box<Fn(&str) -> String>>,
+    // Keep the backup gzipped as `<name>.bk.gz` rather than as a plain copy.
+    // Needs the `flate2` feature.
+    pub compress_backup: bool,
+    // Drop changes which would replace text with the same text, rather than
+    // rewriting it (see ReprintSummary::dropped).
+    pub drop_identity_changes: bool,
+    // Have ChangeSet::normalize_in_place drop empty changes, which neither
+    // remove nor insert anything (see NormalizeReport::dropped). Unlike
+    // drop_identity_changes, this needs no input to check against.
+    pub drop_empty_changes: bool,
+    // If set, every change must fall within this byte range of the input
+    // (once resolved, if relative to the end), or the file is not edited.
+    pub region: Option<Range<u32>>,
+    // Called on each edited file's contents just before they are written, e.g.,
+    // to fix up `#line` directives (see line_offset_map). The hash in the
+    // summary is of the buffer after this has run.
+    pub on_complete: Option<Box<Fn(&mut Vec<u8>)>>,
+    // If non-zero, keep this many backups, `<name>.bk.1` (the newest) to
+    // `<name>.bk.N`, rather than a single `<name>.bk`. Older backups are moved
+    // up one on each edit and the oldest is removed.
+    pub backup_rotation: u8,
+    // If set, hold an advisory lock on each file from reading it to replacing
+    // it, so concurrent reprints of a file don't interleave. Waits at most this
+    // long for the lock. Needs the `lock` feature and a Unix platform.
+    pub lock_timeout: Option<Duration>,
+    // If set, changes touching any line which starts with this prefix (e.g.,
+    // `//` for commented out code), after any leading spaces and tabs, are
+    // skipped (see ReprintSummary::skipped).
+    pub skip_line_prefix: Option<String>,
+    // If set, refuse a change set with more changes than this (across all
+    // files), before reading anything.
+    pub max_changes: Option<usize>,
+    // Write the edited contents into the existing file, rather than replacing
+    // it with a new one, so that hard links to it see the edit. This is not
+    // atomic: if writing fails, the file may be left partly written and must
+    // be restored from the backup.
+    pub preserve_hardlinks: bool,
+    // Thresholds for Lint::LargeChange: a change which removes or inserts more
+    // than this many bytes, or lines.
+    pub large_change_bytes: Option<usize>,
+    pub large_change_lines: Option<usize>,
+    // Remove trailing spaces and tabs from every line of the output which
+    // includes text from a change (see ReprintSummary::trimmed_lines). Other
+    // lines are left as they are.
+    pub trim_trailing_whitespace: bool,
+    // If set, write each file in this encoding (a WHATWG label, e.g.,
+    // "utf-16le") rather than UTF-8. Changes are still applied to, and offsets
+    // are into, the UTF-8 input. No byte order mark is added. Needs the
+    // `encoding` feature.
+    pub output_encoding: Option<String>,
+    // If set, append a line to this file recording each edit, which can be
+    // re-applied with replay. The changes are recorded as they were applied,
+    // i.e., after resolving, formatting and dropping changes, but the output is
+    // not post-processed (e.g., trimmed or re-encoded) on replay. Needs the
+    // `serialize` feature. A failure to log doesn't fail the edit, see
+    // ReprintSummary::log_error.
+    pub replay_log: Option<Path>,
+    // How edited files are written, see WriteMode.
+    pub write_mode: WriteMode,
+    // If set, put backups in this directory rather than next to the files. It
+    // is created (only readable by the user) if it doesn't exist.
+    pub backup_dir: Option<Path>,
+    // Refuse changes whose text contains a NUL byte.
+    pub reject_nul: bool,
+    // When a file doesn't end with a newline, changes which append to it are
+    // appended to its last line. With this set, a `\n` is inserted before the
+    // first such change's text instead (unless it starts with one), so that it
+    // is appended after the last line. Empty files are left alone.
+    pub ensure_newline_before_append: bool,
+    // Resolve each file's path to an absolute path with no symlinks before
+    // locking, reading or writing it, so that the same file always gets the
+    // same temp file, backup and lock whichever path it was named by. Since
+    // symlinks are followed, it is the file they point to which is replaced
+    // (and backed up, next to it), and the links are left alone. The path in
+    // the summary is the resolved one.
+    pub canonicalize: bool,
+    // Called with the number of changes applied so far and the total as each
+    // file is edited, every PROGRESS_INTERVAL changes and once all of them are
+    // done. The counts are for the file being edited, not the whole change set.
+    pub progress: Option<Box<Fn(usize, usize)>>,
+    // If set, rewrite the indentation of each line of the changes' text in this
+    // style, e.g., to turn tabs into spaces to match the file. Only whitespace
+    // at the start of a line is converted, and the start of a change's text
+    // only counts if the change starts a line. Applied after the formatter (see
+    // ReprintSummary::reindented_lines).
+    pub indent_style: Option<IndentStyle>,
+    // If set, lint lines of the output which include text from a change and
+    // are longer than this many characters (Lint::LineTooLong). Other lines are
+    // not checked. Lengths are measured before trailing whitespace is trimmed.
+    pub max_line_length: Option<usize>,
+    // Refuse to edit a file if the edit would make a line longer than
+    // max_line_length (ReprintError::LineTooLong), rather than only linting it.
+    pub strict: bool,
+    // Called on each edited file's contents, exactly as they will be written,
+    // once all other processing is done. If it returns an error, the file is
+    // left as it is and reprint fails with ReprintError::PostValidationFailed,
+    // e.g., if the edited code no longer parses.
+    pub post_validate: Option<Box<Fn(&[u8]) -> Result<(), String>>>,
+    // Retry writing, copying and renaming files up to this many times if they
+    // fail in a way which might be transient (e.g., EAGAIN or EBUSY on a network
+    // file system), waiting a little longer before each retry. Each step is
+    // retried on its own, so a retry never repeats a step which succeeded.
+    pub io_retries: u8,
+    // If set, expand `${NAME}` in the text of every change to the value of
+    // NAME, or with `${NAME:-default}` to `default` if NAME isn't set. A
+    // variable which isn't set and has no default is an error. `$$` stands for
+    // a literal `$`, and a `$` not followed by `{` or `$` is kept as it is.
+    // Expanded after templates and before the formatter.
+    pub variables: Option<HashMap<String, String>>,
+    // If set, refuse to replace a file unless, just before it is backed up and
+    // replaced, it still has this modification time (as FileStat::modified)
+    // and expected_len (if that is set). Complements expected_hash, which is
+    // checked when the file is read.
+    pub expected_modified: Option<u64>,
+    // If set, edited files are written with this rather than FsWriter, e.g.,
+    // to write them through a privileged helper. The file is still locked, if
+    // lock_timeout is set, while it is written.
+    pub writer: Option<Box<FileWriter>>,
+    // Check that each file's output is UTF-8, before any post-processing (see
+    // ReprintError::InvalidUtf8). Changes are checked to fall on character
+    // boundaries, so this is a safety net.
+    pub validate_utf8: bool,
+    // Name the temp file `<name>.<hash>.tmp`, after the first 16 hex digits of
+    // content_hash of the output, rather than `<name>.tmp`. If a temp file of
+    // that name already exists with exactly the same contents (e.g., another
+    // process is making the same edit), it is renamed into place rather than
+    // written again; one with different contents (a hash collision, or a
+    // partly written file) is an error, as for any existing temp file. If
+    // another process renames the shared temp file or makes the backup first
+    // and the file already has the new contents, the write succeeds; backups
+    // are never replaced, so the backup is of the file before either edit.
+    pub content_addressed_temp: bool,
+    // If set, refuse to edit a file if it would change more than this many
+    // lines of the output, counted as for changed_line_mask
+    // (ReprintError::ThresholdExceeded).
+    pub max_lines_changed: Option<usize>,
+    // Rewrite the line endings in each change's text to match the line of the
+    // input the change starts in, `\r\n` or `\n`, so that changes to a file
+    // with mixed line endings fit in where they go. The last line, if it has
+    // no line ending, takes the one before it. Applied after
+    // ensure_newline_before_append.
+    pub match_surrounding_eol: bool,
+    // The lines of context around each hunk from reprint_git_patch_with and
+    // reprint_hunks_with, as for `diff -U`. Changes whose context would
+    // overlap or meet share a hunk. 3 if not set.
+    pub context_lines: Option<usize>,
+    // Which changes reprint_with and ChangeSet::normalize_in_place drop as
+    // duplicates, see DedupKey.
+    pub dedup_key: DedupKey,
+    // If set, and any changes to a file are skipped (see skip_line_prefix),
+    // append them to this file just before the file is written, like the
+    // `.rej` files of `patch`: a `--- <file>` line, then for each change a
+    // `# <reason>` line and the change as written by to_fixture.
+    pub write_rejects: Option<Path>,
+}
+
+// Writes edited files, see ReprintOptions::writer.
+pub trait FileWriter {
+    // Replaces the contents of `file` with `contents`, the output of reprint.
+    // `opts` are the options reprint was called with.
+    fn write_file(&self,
+                  file: &Path,
+                  contents: Vec<u8>,
+                  opts: &ReprintOptions)
+    -> Result<(), ReprintError>;
+}
+
+// The default FileWriter: writes a temp file, backs up the original and
+// renames the temp file over it, as set by the options (see write_atomic).
+pub struct FsWriter;
+
+impl FileWriter for FsWriter {
+    fn write_file(&self,
+                  file: &Path,
+                  contents: Vec<u8>,
+                  opts: &ReprintOptions)
+    -> Result<(), ReprintError> {
+        write_file(file, contents, opts).map(|_| ())
     }
+}
+
+// How often ReprintOptions::progress is called, in changes.
+pub const PROGRESS_INTERVAL: usize = 1000;
 
-    // Rename input file to backup.
-    match fs::rename(input_path, &bk_path) {
-        Ok(()) => {},
-        Err(e) => return Err(format!("Couldn't rename '{}' to '{}': {}",
-                                     input_path.display(),
-                                     bk_path.display(),
-                                     e.desc))
+#[derive(Clone, Copy, PartialEq, Show)]
+pub enum WriteMode {
+    // Write a temp file, back up the original and rename the temp file over
+    // it, so the file is never seen partly written. The default.
+    Atomic,
+    // Truncate the file and write the output into it, with no temp file and no
+    // backup. Not atomic: if writing fails the file may be left empty or partly
+    // written, and there is nothing to restore it from. For scratch files.
+    Truncate,
+}
+
+impl Default for WriteMode {
+    fn default() -> WriteMode {
+        WriteMode::Atomic
     }
+}
 
-    // Rename temp file to input file.
-    match fs::rename(&tmp_path, input_path) {
-        Ok(()) => {},
-        Err(e) => return Err(format!("Couldn't rename '{}' to '{}': {}",
-                                     tmp_path.display(),
-                                     input_path.display(),
-                                     e.desc))
+// Which changes count as duplicates when normalizing, see
+// ReprintOptions::dedup_key.
+#[derive(Clone, Copy, PartialEq, Show)]
+pub enum DedupKey {
+    // Only identical changes are duplicates. Two replacements of the same
+    // range with different text conflict (ReprintError::SameStartConflict),
+    // and insertions at the same point with different text are all kept. The
+    // default.
+    Full,
+    // Changes to the same range of a file with the same text are duplicates,
+    // whatever else differs (e.g., their guards or sources), and only the first
+    // given is kept. Two replacements of the same range with different text
+    // still conflict (ReprintError::SameStartConflict). Insertions are only
+    // duplicates if they are identical, as for Full, so every insertion at a
+    // point is kept.
+    RangeOnly,
+}
+
+impl Default for DedupKey {
+    fn default() -> DedupKey {
+        DedupKey::Full
     }
+}
 
-    // Success!
-    Ok(())
+// How to indent inserted text, see ReprintOptions::indent_style. The width is
+// how many columns a tab stands for, both for converting tabs to spaces and
+// spaces to tabs; spaces left over after the last full tab are kept.
+#[derive(Clone, Copy, PartialEq, Show)]
+pub enum IndentStyle {
+    Tabs(usize),
+    Spaces(usize),
 }
 
-impl PartialEq for Change {
-    fn eq(&self, other: &Change) -> bool {
-        self.path == other.path && self.start_byte == other.start_byte
+impl ReprintOptions {
+    // Options as for ReprintOptions::default(), to be modified by chaining
+    // setters, e.g., `ReprintOptions::builder().hash_output(true).build()`.
+    pub fn builder() -> ReprintOptionsBuilder {
+        ReprintOptionsBuilder { opts: ReprintOptions::default() }
     }
 }
 
-impl Eq for Change {}
+// See ReprintOptions for the meaning of each setting.
+pub struct ReprintOptionsBuilder {
+    opts: ReprintOptions,
+}
 
-impl Ord for Change {
-    fn cmp(&self, other: &Change) -> std::cmp::Ordering {
-        match self.path.cmp(&other.path) {
-            std::cmp::Ordering::Equal => self.start_byte.cmp(&other.start_byte),
-            o => o
+impl ReprintOptionsBuilder {
+    pub fn hash_output(mut self, hash_output: bool) -> ReprintOptionsBuilder {
+        self.opts.hash_output = hash_output;
+        self
+    }
+
+    pub fn expected_len(mut self, len: u64) -> ReprintOptionsBuilder {
+        self.opts.expected_len = Some(len);
+        self
+    }
+
+    pub fn expected_hash(mut self, hash: String) -> ReprintOptionsBuilder {
+        self.opts.expected_hash = Some(hash);
+        self
+    }
+
+    pub fn formatter(mut self, formatter: Box<Fn(&str) -> String>) -> ReprintOptionsBuilder {
+        self.opts.formatter = Some(formatter);
+        self
+    }
+
+    pub fn compress_backup(mut self, compress_backup: bool) -> ReprintOptionsBuilder {
+        self.opts.compress_backup = compress_backup;
+        self
+    }
+
+    pub fn drop_identity_changes(mut self, drop: bool) -> ReprintOptionsBuilder {
+        self.opts.drop_identity_changes = drop;
+        self
+    }
+
+    pub fn drop_empty_changes(mut self, drop: bool) -> ReprintOptionsBuilder {
+        self.opts.drop_empty_changes = drop;
+        self
+    }
+
+    pub fn region(mut self, region: Range<u32>) -> ReprintOptionsBuilder {
+        self.opts.region = Some(region);
+        self
+    }
+
+    pub fn on_complete(mut self, on_complete: Box<Fn(&mut Vec<u8>)>) -> ReprintOptionsBuilder {
+        self.opts.on_complete = Some(on_complete);
+        self
+    }
+
+    pub fn backup_rotation(mut self, depth: u8) -> ReprintOptionsBuilder {
+        self.opts.backup_rotation = depth;
+        self
+    }
+
+    pub fn lock_timeout(mut self, timeout: Duration) -> ReprintOptionsBuilder {
+        self.opts.lock_timeout = Some(timeout);
+        self
+    }
+
+    pub fn skip_line_prefix(mut self, prefix: String) -> ReprintOptionsBuilder {
+        self.opts.skip_line_prefix = Some(prefix);
+        self
+    }
+
+    pub fn max_changes(mut self, limit: usize) -> ReprintOptionsBuilder {
+        self.opts.max_changes = Some(limit);
+        self
+    }
+
+    pub fn preserve_hardlinks(mut self, preserve: bool) -> ReprintOptionsBuilder {
+        self.opts.preserve_hardlinks = preserve;
+        self
+    }
+
+    pub fn large_change_bytes(mut self, bytes: usize) -> ReprintOptionsBuilder {
+        self.opts.large_change_bytes = Some(bytes);
+        self
+    }
+
+    pub fn large_change_lines(mut self, lines: usize) -> ReprintOptionsBuilder {
+        self.opts.large_change_lines = Some(lines);
+        self
+    }
+
+    pub fn trim_trailing_whitespace(mut self, trim: bool) -> ReprintOptionsBuilder {
+        self.opts.trim_trailing_whitespace = trim;
+        self
+    }
+
+    pub fn output_encoding(mut self, label: String) -> ReprintOptionsBuilder {
+        self.opts.output_encoding = Some(label);
+        self
+    }
+
+    pub fn replay_log(mut self, log: Path) -> ReprintOptionsBuilder {
+        self.opts.replay_log = Some(log);
+        self
+    }
+
+    pub fn write_mode(mut self, mode: WriteMode) -> ReprintOptionsBuilder {
+        self.opts.write_mode = mode;
+        self
+    }
+
+    pub fn backup_dir(mut self, dir: Path) -> ReprintOptionsBuilder {
+        self.opts.backup_dir = Some(dir);
+        self
+    }
+
+    pub fn reject_nul(mut self, reject: bool) -> ReprintOptionsBuilder {
+        self.opts.reject_nul = reject;
+        self
+    }
+
+    pub fn ensure_newline_before_append(mut self, ensure: bool) -> ReprintOptionsBuilder {
+        self.opts.ensure_newline_before_append = ensure;
+        self
+    }
+
+    pub fn canonicalize(mut self, canonicalize: bool) -> ReprintOptionsBuilder {
+        self.opts.canonicalize = canonicalize;
+        self
+    }
+
+    pub fn progress(mut self, progress: Box<Fn(usize, usize)>) -> ReprintOptionsBuilder {
+        self.opts.progress = Some(progress);
+        self
+    }
+
+    pub fn indent_style(mut self, style: IndentStyle) -> ReprintOptionsBuilder {
+        self.opts.indent_style = Some(style);
+        self
+    }
+
+    pub fn max_line_length(mut self, limit: usize) -> ReprintOptionsBuilder {
+        self.opts.max_line_length = Some(limit);
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> ReprintOptionsBuilder {
+        self.opts.strict = strict;
+        self
+    }
+
+    pub fn validate_utf8(mut self, validate: bool) -> ReprintOptionsBuilder {
+        self.opts.validate_utf8 = validate;
+        self
+    }
+
+    pub fn content_addressed_temp(mut self, content_addressed: bool) -> ReprintOptionsBuilder {
+        self.opts.content_addressed_temp = content_addressed;
+        self
+    }
+
+    pub fn max_lines_changed(mut self, limit: usize) -> ReprintOptionsBuilder {
+        self.opts.max_lines_changed = Some(limit);
+        self
+    }
+
+    pub fn match_surrounding_eol(mut self, match_eol: bool) -> ReprintOptionsBuilder {
+        self.opts.match_surrounding_eol = match_eol;
+        self
+    }
+
+    pub fn context_lines(mut self, lines: usize) -> ReprintOptionsBuilder {
+        self.opts.context_lines = Some(lines);
+        self
+    }
+
+    pub fn dedup_key(mut self, key: DedupKey) -> ReprintOptionsBuilder {
+        self.opts.dedup_key = key;
+        self
+    }
+
+    pub fn write_rejects(mut self, path: Path) -> ReprintOptionsBuilder {
+        self.opts.write_rejects = Some(path);
+        self
+    }
+
+    pub fn writer(mut self, writer: Box<FileWriter>) -> ReprintOptionsBuilder {
+        self.opts.writer = Some(writer);
+        self
+    }
+
+    pub fn expected_modified(mut self, modified: u64) -> ReprintOptionsBuilder {
+        self.opts.expected_modified = Some(modified);
+        self
+    }
+
+    pub fn variables(mut self, variables: HashMap<String, String>) -> ReprintOptionsBuilder {
+        self.opts.variables = Some(variables);
+        self
+    }
+
+    pub fn io_retries(mut self, retries: u8) -> ReprintOptionsBuilder {
+        self.opts.io_retries = retries;
+        self
+    }
+
+    pub fn post_validate(mut self,
+                         validate: Box<Fn(&[u8]) -> Result<(), String>>)
+    -> ReprintOptionsBuilder {
+        self.opts.post_validate = Some(validate);
+        self
+    }
+
+    pub fn build(self) -> ReprintOptions {
+        self.opts
+    }
+}
+
+#[derive(Clone, Show)]
+pub struct ReprintSummary {
+    pub path: Path,
+    // Hex digest of the edited file, if ReprintOptions::hash_output was set.
+    // SHA-256 with the `sha256` feature, SipHash otherwise.
+    pub hash: Option<String>,
+    pub stats: EditStats,
+    // The number of changes dropped by ReprintOptions::drop_identity_changes.
+    pub dropped: usize,
+    // Changes skipped because of ReprintOptions::skip_line_prefix.
+    pub skipped: Vec<Change>,
+    // The number of lines trimmed by ReprintOptions::trim_trailing_whitespace.
+    pub trimmed_lines: usize,
+    // The number of lines of changes' text reindented by
+    // ReprintOptions::indent_style.
+    pub reindented_lines: usize,
+    // The size of the file as written, after any post-processing, or 0 if it
+    // wasn't written.
+    pub bytes_written: u64,
+    // Whether the file was written. reprint_with leaves a file whose contents
+    // the changes (and post-processing) wouldn't change alone, without a
+    // backup, e.g., for an empty change set.
+    pub written: bool,
+    // Whether the temp file couldn't be renamed over the file (e.g., across
+    // file systems), so the file was written in place instead, which is not
+    // atomic. Always false with a custom ReprintOptions::writer.
+    pub wrote_in_place: bool,
+    // Why the edit couldn't be appended to ReprintOptions::replay_log, if it
+    // couldn't. The file has been edited regardless.
+    pub log_error: Option<ReprintError>,
+}
+
+// Counters gathered while splicing a file.
+#[derive(Clone, Copy, Default, Show)]
+pub struct EditStats {
+    // Bytes of the input copied to the output unchanged.
+    pub kept_bytes: u64,
+    // Bytes of text inserted by changes.
+    pub inserted_bytes: u64,
+    // Bytes of the input removed or replaced by changes.
+    pub removed_bytes: u64,
+    // The number of (non-empty) kept or inserted spans making up the output,
+    // and the size of the largest.
+    pub span_count: usize,
+    pub max_span: u64,
+}
+
+// As reprint_with, with the default options.
+pub fn reprint(changes: ChangeSet) -> Result<Vec<ReprintSummary>, ReprintError> {
+    reprint_with(changes, ReprintOptions::default())
+}
+
+pub fn reprint_with(changes: ChangeSet,
+                    opts: ReprintOptions)
+-> Result<Vec<ReprintSummary>, ReprintError> {
+    if let Some(limit) = opts.max_changes {
+        if changes.len() > limit {
+            return Err(ReprintError::TooManyChanges { count: changes.len(), limit: limit });
         }
     }
+
+    let changes = try!(normalize_with(changes, opts.dedup_key));
+    reprint_normalized(&changes, &opts)
 }
 
-impl PartialOrd for Change {
-    fn partial_cmp(&self, other: &Change) -> Option<std::cmp::Ordering> {
-        match self.path.cmp(&other.path) {
-            std::cmp::Ordering::Equal => self.start_byte.partial_cmp(&other.start_byte),
-            o => Some(o)
+// As reprint_with, but `changes` are already known to be normalized.
+pub fn reprint_sorted(changes: SortedChangeSet,
+                      opts: ReprintOptions)
+-> Result<Vec<ReprintSummary>, ReprintError> {
+    if let Some(limit) = opts.max_changes {
+        if changes.len() > limit {
+            return Err(ReprintError::TooManyChanges { count: changes.len(), limit: limit });
         }
     }
+
+    reprint_normalized(&*changes, &opts)
 }
 
-impl Change {
-    pub fn new(path: Path, start_byte: u32, end_byte: u32, text: String) -> Change {
-        Change {
-            path: path,
-            start_byte: start_byte,
-            end_byte: end_byte,
-            text: text
+fn reprint_normalized(changes: &ChangeSet,
+                      opts: &ReprintOptions)
+-> Result<Vec<ReprintSummary>, ReprintError> {
+    let mut summaries = vec![];
+    for &(file, changes) in files(changes).iter() {
+        summaries.push(try!(reprint_file(file, changes, opts)));
+    }
+    Ok(summaries)
+}
+
+fn reprint_file(file: &Path,
+                changes: &[Change],
+                opts: &ReprintOptions)
+-> Result<ReprintSummary, ReprintError> {
+    reprint_file_with_lints(file, changes, opts, None)
+}
+
+// As reprint_file, but if `lints` is given, also lints the changes against the
+// input as read for the edit (see reprint_checked).
+fn reprint_file_with_lints(file: &Path,
+                           changes: &[Change],
+                           opts: &ReprintOptions,
+                           lints: Option<&mut Vec<Lint>>)
+-> Result<ReprintSummary, ReprintError> {
+    let canonical = if opts.canonicalize {
+        Some(try!(canonicalize(file)))
+    } else {
+        None
+    };
+    let file = match canonical {
+        Some(ref path) => path,
+        None => file
+    };
+
+    // Released when we return, by which time the file has been replaced.
+    let _lock = match opts.lock_timeout {
+        Some(timeout) => Some(try!(lock_file(file, timeout))),
+        None => None
+    };
+    // For checking that a no-op edit leaves the file's backup alone.
+    let backup_before = if cfg!(debug_assertions) { read_plain_backup(file, opts) } else { None };
+
+    let contents = try!(read_input(file));
+    let input = &*contents;
+    if let Some(len) = opts.expected_len {
+        if input.len() as u64 != len {
+            return Err(ReprintError::InputChanged);
+        }
+    }
+    if let Some(ref hash) = opts.expected_hash {
+        if content_hash(input.as_bytes()) != *hash {
+            return Err(ReprintError::InputChanged);
         }
     }
 
-    fn delta(&self) -> i64 {
-        self.text.as_bytes().len() as i64 -
-            (self.end_byte as i64 - self.start_byte as i64)
+    if let Some(lints) = lints {
+        let resolved = try!(resolve_checked(changes, input.len()));
+        *lints = lint(&input[], &ChangeSet::from_vec(resolved), opts);
+    }
+
+    let Prepared { changes, skipped, dropped, reindented_lines } =
+        try!(prepare(&input[], changes, opts));
+    let changes = &changes[];
+
+    let mut out = Output::new(&input[], changes, opts.hash_output);
+    try!(process(&input[], changes, &mut out, opts.progress.as_ref().map(|p| &**p)));
+
+    let Output { mut buf, hasher, stats } = out;
+    if opts.validate_utf8 {
+        try!(check_utf8(&buf[]));
+    }
+    if let (Some(limit), true) = (opts.max_line_length, opts.strict) {
+        if let Some(&(line, len)) = long_lines(&buf[], &output_ranges(changes)[], limit).first() {
+            return Err(ReprintError::LineTooLong { line: line, len: len });
+        }
+    }
+    if let Some(limit) = opts.max_lines_changed {
+        let lines = lines_changed(&buf[], &output_ranges(changes)[]);
+        if lines > limit {
+            return Err(ReprintError::ThresholdExceeded { lines: lines, limit: limit });
+        }
+    }
+    let trimmed_lines = if opts.trim_trailing_whitespace {
+        trim_touched_lines(&mut buf, &output_ranges(changes)[])
+    } else {
+        0
+    };
+    if let Some(ref on_complete) = opts.on_complete {
+        on_complete(&mut buf);
+    }
+    if let Some(ref label) = opts.output_encoding {
+        buf = try!(encode(buf, &label[]));
+    }
+    // The hasher only saw the output of process.
+    let rewritten = trimmed_lines > 0 ||
+                    opts.on_complete.is_some() ||
+                    opts.output_encoding.is_some();
+    let hash = hasher.map(|h| if rewritten { content_hash(&buf[]) } else { h.result() });
+    if let Some(ref validate) = opts.post_validate {
+        if let Err(msg) = validate(&buf[]) {
+            return Err(ReprintError::PostValidationFailed(msg));
+        }
     }
+    // Rejects are written before the file, so that if they can't be, the file
+    // is left as it was.
+    if let (Some(rejects), false) = (opts.write_rejects.as_ref(), skipped.is_empty()) {
+        let prefix = opts.skip_line_prefix.as_ref().map_or("", |p| &p[]);
+        let reason = format!("touches a line starting with `{}`", prefix);
+        try!(append_rejects(rejects, file, &skipped[], &reason[]));
+    }
+
+    // If nothing changes, the file is left alone, with no backup.
+    let written = &buf[] != input.as_bytes();
+    let bytes_written = if written { buf.len() as u64 } else { 0 };
+    let wrote_in_place = match opts.writer {
+        _ if !written => false,
+        Some(ref writer) => {
+            try!(writer.write_file(file, buf, opts));
+            false
+        }
+        None => try!(write_file(file, buf, opts))
+    };
+    // A no-op edit leaves the file and its newest backup as they were, so a
+    // backup which matched the file still does.
+    debug_assert!(written || (has_contents(file, input.as_bytes()) &&
+                              read_plain_backup(file, opts) == backup_before));
+    // Only edits which were made are logged, so the log is written after the
+    // file, and by then failing would misreport the edit.
+    let log_error = match opts.replay_log {
+        Some(ref log) if written => append_to_log(log, file, changes).err(),
+        _ => None
+    };
+
+    Ok(ReprintSummary {
+        path: file.clone(),
+        hash: hash,
+        stats: stats,
+        dropped: dropped,
+        skipped: skipped,
+        trimmed_lines: trimmed_lines,
+        reindented_lines: reindented_lines,
+        bytes_written: bytes_written,
+        written: written,
+        wrote_in_place: wrote_in_place,
+        log_error: log_error,
+    })
 }
 
-fn main() {
-    let change = Change::new(Path::new("/home/ncameron/reprint/data/hello.rs"),
-                             3, 8,
-                             "Goodbye cruel".to_string());
-    reprint(vec![change]);
+// Edits `file` as reprint_with with the default options, but splices the
+// changes on up to `threads` threads, each working on one region of the file
+// (see partition). With the `mmap` feature, the input is mapped rather than
+// read. Worth it only for huge files with many changes. Every change must be
+// for `file`. In the summary, a kept span which crosses a region boundary is
+// counted as two spans. Needs the `parallel` feature.
+#[cfg(feature = "parallel")]
+pub fn reprint_parallel(file: &Path,
+                        changes: ChangeSet,
+                        threads: usize)
+-> Result<ReprintSummary, ReprintError> {
+    use std::thread::Thread;
+
+    try!(check_paths(file, &changes));
+    let changes = try!(normalize(changes));
+    let contents = try!(read_input(file));
+    let input = &*contents;
+    let changes = try!(prepare(input, &changes[], &ReprintOptions::default())).changes;
+    let changes = ChangeSet::from_vec(changes);
+
+    let guards: Vec<_> = partition(changes, input.len() as u32, threads).into_iter().map(|r| {
+        let (region, changes) = r;
+        // Regions needn't start on a character boundary, so work on bytes.
+        let input = &input.as_bytes()[region.start as usize..region.end as usize];
+        Thread::scoped(move || {
+            let mut out = Output::with_buf(Vec::new(), input.len(), &changes[], false);
+            let result = splice(input, &changes[], |kind, bytes| out.push_span(kind, bytes), None);
+            result.map(|()| (out.buf, out.stats))
+        })
+    }).collect();
+
+    let mut buf = Vec::with_capacity(input.len());
+    let mut stats = EditStats::default();
+    for guard in guards.into_iter() {
+        let (region_buf, region_stats) = match guard.join() {
+            Ok(result) => try!(result),
+            Err(_) => return Err(ReprintError::Process("A splicing thread panicked".to_string()))
+        };
+        buf.push_all(&region_buf[]);
+        stats.kept_bytes += region_stats.kept_bytes;
+        stats.inserted_bytes += region_stats.inserted_bytes;
+        stats.removed_bytes += region_stats.removed_bytes;
+        stats.span_count += region_stats.span_count;
+        stats.max_span = cmp::max(stats.max_span, region_stats.max_span);
+    }
+
+    // If nothing changes, the file is left alone, with no backup.
+    let written = &buf[] != input.as_bytes();
+    let bytes_written = if written { buf.len() as u64 } else { 0 };
+    let wrote_in_place = if written {
+        try!(write_file(file, buf, &ReprintOptions::default()))
+    } else {
+        false
+    };
+    Ok(ReprintSummary {
+        path: file.clone(),
+        hash: None,
+        stats: stats,
+        dropped: 0,
+        skipped: vec![],
+        trimmed_lines: 0,
+        reindented_lines: 0,
+        bytes_written: bytes_written,
+        written: written,
+        wrote_in_place: wrote_in_place,
+        log_error: None,
+    })
+}
+
+#[cfg(not(feature = "parallel"))]
+pub fn reprint_parallel(_: &Path, _: ChangeSet, _: usize) -> Result<ReprintSummary, ReprintError> {
+    Err(ReprintError::Process("Parallel reprinting requires the `parallel` feature".to_string()))
+}
+
+// Edits the file of each job with its changes, as reprint_with with the default
+// options, but all or nothing: every edit is worked out and written to its temp
+// file before any file is replaced. If writing a temp file fails, the temp
+// files are removed and nothing is replaced; if replacing a file fails, the
+// files already replaced are restored from their backups, as far as possible
+// (see ReprintError::RollbackFailed). A file the edit wouldn't change is left
+// alone, without a backup, as by reprint_with. Every change must be for its
+// job's file, and each file may only be in one job (once paths are
+// canonicalized). Each file is checked to still have the contents the edit was
+// worked out from just before it is replaced (ReprintError::InputChanged). A
+// crash part way through replacing files can still leave some edited and
+// others not.
+pub fn reprint_transaction(jobs: Vec<(Path, ChangeSet)>)
+-> Result<Vec<ReprintSummary>, ReprintError> {
+    let opts = ReprintOptions::default();
+
+    struct Edit {
+        // As given in the job, and as written.
+        file: Path,
+        canonical: Path,
+        tmp_path: Path,
+        bk_path: Path,
+        input_hash: String,
+        out: Output,
+        // Whether the edit changes the file, so has a temp file and backup.
+        written: bool,
+    }
+
+    let mut edits: Vec<Edit> = vec![];
+    for (file, changes) in jobs.into_iter() {
+        let canonical = try!(canonicalize(&file));
+        if edits.iter().any(|e| e.canonical == canonical) {
+            return Err(ReprintError::Verification(
+                format!("'{}' is in more than one job", file.display())));
+        }
+        let (input, out) = try!(preview_file(&file, changes, &opts));
+        let name = match canonical.as_str() {
+            Some(n) => n.to_string(),
+            None => return Err(ReprintError::Io(format!("Couldn't turn path '{}' into a string",
+                                                        canonical.display())))
+        };
+        let tmp_path = Path::new(name.clone() + ".tmp");
+        let (_, bk_path) = try!(backup_path(&canonical, &name[], &opts));
+        edits.push(Edit {
+            file: file,
+            canonical: canonical,
+            tmp_path: tmp_path,
+            bk_path: bk_path,
+            input_hash: content_hash(input.as_bytes()),
+            written: &out.buf[] != input.as_bytes(),
+            out: out,
+        });
+    }
+
+    let remove_temps = |&: edits: &[Edit]| {
+        for edit in edits.iter().filter(|e| e.written) {
+            let _ = remove_file(&edit.tmp_path);
+        }
+    };
+
+    for i in 0..edits.len() {
+        let edit = &edits[i];
+        if !edit.written {
+            continue;
+        }
+        if let Err(e) = create_new(&edit.tmp_path, &edit.out.buf[]) {
+            // The temp file which failed isn't ours to remove.
+            remove_temps(&edits[..i]);
+            return Err(e);
+        }
+        if let Err(e) = copy_xattrs(&edit.canonical, &edit.tmp_path) {
+            remove_temps(&edits[..i + 1]);
+            return Err(e);
+        }
+    }
+
+    for i in 0..edits.len() {
+        let edit = &edits[i];
+        if !edit.written {
+            continue;
+        }
+        // The backup is a link to (or copy of) the file, which stays put until
+        // the temp file replaces it.
+        let replaced = check_hash(&edit.canonical, &edit.input_hash[]).and_then(|()| {
+            create_backup(&edit.canonical, &edit.bk_path, &opts)
+        }).and_then(|()| {
+            rename(&edit.tmp_path, &edit.canonical, opts.io_retries).map_err(|e| {
+                let _ = remove_file(&edit.bk_path);
+                e
+            })
+        });
+        if let Err(e) = replaced {
+            let done = edits[..i].iter().filter(|edit| edit.written);
+            let rollback: Vec<ReprintError> = done.filter_map(|edit| {
+                restore_backup(&edit.bk_path, &edit.canonical, opts.io_retries).err()
+            }).collect();
+            remove_temps(&edits[i..]);
+            if rollback.is_empty() {
+                return Err(e);
+            }
+            return Err(ReprintError::RollbackFailed { cause: Box::new(e), rollback: rollback });
+        }
+    }
+
+    Ok(edits.into_iter().map(|Edit { file, out, written, .. }| {
+        ReprintSummary {
+            path: file,
+            hash: None,
+            stats: out.stats,
+            dropped: 0,
+            skipped: vec![],
+            trimmed_lines: 0,
+            reindented_lines: 0,
+            bytes_written: if written { out.buf.len() as u64 } else { 0 },
+            written: written,
+            wrote_in_place: false,
+            log_error: None,
+        }
+    }).collect())
+}
+
+// Passes the contents of `file` before and after applying `changes` to
+// `confirm`, e.g., to show the user a preview, then edits the file as
+// reprint_with with the default options if it returns true, or returns None
+// if not. Fails with InputChanged if the file changes after the preview: its
+// hash and length are checked when it is read again to be edited, and its
+// length and modification time just before it is replaced. Every change must
+// be for `file`.
+pub fn reprint_with_confirm<F>(file: &Path,
+                               changes: ChangeSet,
+                               confirm: F)
+-> Result<Option<ReprintSummary>, ReprintError>
+    where F: FnOnce(&str, &[u8]) -> bool
+{
+    // Before reading, so that a change made while we read is noticed.
+    let modified = match fs::stat(file) {
+        Ok(stat) => stat.modified,
+        Err(e) => return Err(ReprintError::Io(format!("Couldn't stat '{}': {}",
+                                                      file.display(),
+                                                      e.desc)))
+    };
+    let (input, out) = try!(preview_file(file,
+                                         ChangeSet::from_vec(changes.to_vec()),
+                                         &ReprintOptions::default()));
+    if !confirm(&input[], &out.buf[]) {
+        return Ok(None);
+    }
+
+    let changes = try!(normalize(changes));
+    let opts = ReprintOptions::builder()
+                   .expected_len(input.len() as u64)
+                   .expected_hash(content_hash(input.as_bytes()))
+                   .expected_modified(modified)
+                   .build();
+    reprint_file(file, &changes[], &opts).map(Some)
+}
+
+// As reprint_with for the single file `file`, but also returns any lints for
+// the changes (see lint). Every change must be for `file`. Lint indices refer
+// to the changes once normalized and resolved against the file.
+pub fn reprint_checked(file: &Path,
+                       changes: ChangeSet,
+                       opts: ReprintOptions)
+-> Result<(ReprintSummary, Vec<Lint>), ReprintError> {
+    try!(check_paths(file, &changes));
+    let changes = try!(normalize(changes));
+    let mut lints = vec![];
+    let summary = try!(reprint_file_with_lints(file, &changes[], &opts, Some(&mut lints)));
+    Ok((summary, lints))
+}
+
+// The contents `file` would have after applying `changes`, without writing
+// anything. Every change must be for `file`.
+pub fn reprint_preview(file: &Path, changes: ChangeSet) -> Result<Vec<u8>, ReprintError> {
+    preview_file(file, changes, &ReprintOptions::default()).map(|(_, out)| out.buf)
+}
+
+// What editing several files would do, see preview_all.
+#[derive(Clone, Show)]
+pub struct AggregateSummary {
+    // The number of files the changes would alter.
+    pub files_affected: usize,
+    // Totals over all the files.
+    pub inserted_bytes: u64,
+    pub removed_bytes: u64,
+    pub changes: usize,
+    // The stats for each file, in the order given.
+    pub files: Vec<(Path, EditStats)>,
+}
+
+// Works out the edit of each job's file with its changes, as reprint_preview,
+// and sums up what they would do, without writing anything. Every change must
+// be for its job's file. Fails on the first file which can't be previewed.
+pub fn preview_all(jobs: &[(Path, ChangeSet)]) -> Result<AggregateSummary, ReprintError> {
+    let mut summary = AggregateSummary {
+        files_affected: 0,
+        inserted_bytes: 0,
+        removed_bytes: 0,
+        changes: 0,
+        files: Vec::with_capacity(jobs.len()),
+    };
+    for &(ref file, ref changes) in jobs.iter() {
+        let (_, out) = try!(preview_file(file,
+                                         ChangeSet::from_vec(changes.to_vec()),
+                                         &ReprintOptions::default()));
+        let stats = out.stats;
+        if stats.inserted_bytes > 0 || stats.removed_bytes > 0 {
+            summary.files_affected += 1;
+        }
+        summary.inserted_bytes += stats.inserted_bytes;
+        summary.removed_bytes += stats.removed_bytes;
+        summary.changes += changes.len();
+        summary.files.push((file.clone(), stats));
+    }
+    Ok(summary)
+}
+
+// As reprint_preview, but also returns the contents of `file` as they were
+// read, so the before and after come from a single read.
+pub fn reprint_both(file: &Path, changes: ChangeSet) -> Result<(String, Vec<u8>), ReprintError> {
+    preview_file(file, changes, &ReprintOptions::default()).map(|(input, out)| (input, out.buf))
+}
+
+// Op codes for binary patches (see reprint_binary_patch).
+const BINARY_COPY: u8 = b'C';
+const BINARY_INSERT: u8 = b'I';
+
+// Edits `file` as reprint_with with the default options, and also returns the
+// edit as a compact binary patch, which apply_binary_patch applies to the
+// original contents. The patch is a sequence of ops made from the spans of the
+// edit: `C` then an offset and a length copies a range of the input, and `I`
+// then a length and that many bytes inserts them. Offsets and lengths are
+// 8 bytes, big endian. Every change must be for `file`.
+pub fn reprint_binary_patch(file: &Path,
+                            changes: ChangeSet)
+-> Result<(ReprintSummary, Vec<u8>), ReprintError> {
+    try!(check_paths(file, &changes));
+    let changes = try!(normalize(changes));
+    let input = try!(read_file(file));
+    let changes = try!(prepare(&input[], &changes[], &ReprintOptions::default())).changes;
+    let changes = &changes[];
+
+    let mut patch = vec![];
+    let mut pos = 0;
+    try!(splice(input.as_bytes(), changes, |kind, bytes| {
+        match kind {
+            SpanKind::Keep => {
+                patch.push(BINARY_COPY);
+                push_u64(&mut patch, pos as u64);
+                push_u64(&mut patch, bytes.len() as u64);
+                pos += bytes.len();
+            }
+            SpanKind::Remove => pos += bytes.len(),
+            SpanKind::Insert => {
+                patch.push(BINARY_INSERT);
+                push_u64(&mut patch, bytes.len() as u64);
+                patch.push_all(bytes);
+            }
+        }
+    }, None));
+
+    // Make sure the file we edit is the one the patch is for.
+    let opts = ReprintOptions::builder()
+                   .expected_len(input.len() as u64)
+                   .expected_hash(content_hash(input.as_bytes()))
+                   .build();
+    let summary = try!(reprint_file(file, changes, &opts));
+    Ok((summary, patch))
+}
+
+// Applies a patch from reprint_binary_patch to `input`, the contents of the
+// file before the edit.
+pub fn apply_binary_patch(input: &[u8], patch: &[u8]) -> Result<Vec<u8>, ReprintError> {
+    let malformed = |&: pos: usize| {
+        ReprintError::Process(format!("Malformed binary patch at byte {}", pos))
+    };
+
+    let mut result = vec![];
+    let mut pos = 0;
+    while pos < patch.len() {
+        let op = patch[pos];
+        match op {
+            BINARY_COPY => {
+                let (offset, len) = match (read_u64(patch, pos + 1), read_u64(patch, pos + 9)) {
+                    (Some(offset), Some(len)) => (offset, len),
+                    _ => return Err(malformed(pos))
+                };
+                match offset.checked_add(len) {
+                    Some(end) if end <= input.len() as u64 => {
+                        result.push_all(&input[offset as usize..end as usize]);
+                    }
+                    _ => return Err(malformed(pos))
+                }
+                pos += 17;
+            }
+            BINARY_INSERT => {
+                let len = match read_u64(patch, pos + 1) {
+                    Some(len) if len <= (patch.len() - pos - 9) as u64 => len as usize,
+                    _ => return Err(malformed(pos))
+                };
+                result.push_all(&patch[pos + 9..pos + 9 + len]);
+                pos += 9 + len;
+            }
+            _ => return Err(malformed(pos))
+        }
+    }
+    Ok(result)
+}
+
+fn push_u64(buf: &mut Vec<u8>, n: u64) {
+    for i in (0..8us).rev() {
+        buf.push((n >> (i * 8)) as u8);
+    }
+}
+
+// The big endian u64 at `pos` in `buf`, if there are 8 bytes there.
+fn read_u64(buf: &[u8], pos: usize) -> Option<u64> {
+    if pos + 8 > buf.len() {
+        return None;
+    }
+    Some(buf[pos..pos + 8].iter().fold(0u64, |n, &b| (n << 8) | b as u64))
+}
+
+// Writes the contents `file` would have after applying `changes` to the file
+// descriptor `fd` (e.g., a pipe to a pager), without touching `file`. `fd` is
+// left open. Every change must be for `file`. Needs the `fd` feature.
+#[cfg(all(feature = "fd", unix))]
+pub fn reprint_to_fd(file: &Path,
+                     changes: ChangeSet,
+                     fd: std::os::unix::Fd)
+-> Result<(), ReprintError> {
+    let out = try!(reprint_preview(file, changes));
+    write_fd(fd, &out[])
+}
+
+#[cfg(all(not(feature = "fd"), unix))]
+pub fn reprint_to_fd(_: &Path, _: ChangeSet, _: std::os::unix::Fd) -> Result<(), ReprintError> {
+    Err(ReprintError::Io("Writing to a file descriptor requires the `fd` feature".to_string()))
+}
+
+// Edits the file open as `fd` (for reading and writing), e.g., one opened with
+// `openat` in a sandbox, where there's no path to it. The whole file is read
+// from the start, then truncated and the output written back through `fd`, so
+// this is not atomic: there is no temp file or backup, and if writing fails the
+// file may be left empty or partly written, as for WriteMode::Truncate. The
+// changes' paths are ignored, so they should all be for the same file, and the
+// path in the summary is `/dev/fd/<fd>`. `fd` is left open. Needs the `fd`
+// feature.
+#[cfg(all(feature = "fd", unix))]
+pub fn reprint_fd(fd: std::os::unix::Fd,
+                  changes: ChangeSet)
+-> Result<ReprintSummary, ReprintError> {
+    let changes = try!(normalize(ignore_paths(changes)));
+    try!(seek_fd_start(fd));
+    let input = match String::from_utf8(try!(read_fd(fd))) {
+        Ok(input) => input,
+        Err(_) => return Err(ReprintError::Io(
+            format!("File descriptor {} doesn't hold UTF-8", fd)))
+    };
+    let changes = try!(prepare(&input[], &changes[], &ReprintOptions::default())).changes;
+    let changes = &changes[];
+
+    let mut out = Output::new(&input[], changes, false);
+    try!(process(&input[], changes, &mut out, None));
+    let Output { buf, stats, .. } = out;
+
+    try!(seek_fd_start(fd));
+    if unsafe { libc::ftruncate(fd, 0) } != 0 {
+        return Err(fd_error("truncate", fd));
+    }
+    try!(write_fd(fd, &buf[]));
+
+    Ok(ReprintSummary {
+        path: Path::new(format!("/dev/fd/{}", fd)),
+        hash: None,
+        stats: stats,
+        dropped: 0,
+        skipped: vec![],
+        trimmed_lines: 0,
+        reindented_lines: 0,
+        bytes_written: buf.len() as u64,
+        written: true,
+        wrote_in_place: false,
+        log_error: None,
+    })
+}
+
+#[cfg(all(not(feature = "fd"), unix))]
+pub fn reprint_fd(_: std::os::unix::Fd, _: ChangeSet) -> Result<ReprintSummary, ReprintError> {
+    Err(ReprintError::Io("Editing a file descriptor requires the `fd` feature".to_string()))
+}
+
+#[cfg(all(feature = "fd", unix))]
+const EINTR: usize = 4;
+
+#[cfg(all(feature = "fd", unix))]
+fn fd_error(what: &str, fd: std::os::unix::Fd) -> ReprintError {
+    ReprintError::Io(format!("Couldn't {} file descriptor {}: {}",
+                             what,
+                             fd,
+                             std::os::last_os_error()))
+}
+
+#[cfg(all(feature = "fd", unix))]
+fn seek_fd_start(fd: std::os::unix::Fd) -> Result<(), ReprintError> {
+    if unsafe { libc::lseek(fd, 0, libc::SEEK_SET) } < 0 {
+        return Err(fd_error("seek", fd));
+    }
+    Ok(())
+}
+
+// Reads from `fd` until the end of the file.
+#[cfg(all(feature = "fd", unix))]
+fn read_fd(fd: std::os::unix::Fd) -> Result<Vec<u8>, ReprintError> {
+    let mut result = vec![];
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = unsafe {
+            libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len() as libc::size_t)
+        };
+        if n < 0 {
+            if std::os::errno() == EINTR {
+                continue;
+            }
+            return Err(fd_error("read", fd));
+        }
+        if n == 0 {
+            return Ok(result);
+        }
+        result.push_all(&buf[..n as usize]);
+    }
+}
+
+// Writes all of `buf` to `fd`.
+#[cfg(all(feature = "fd", unix))]
+fn write_fd(fd: std::os::unix::Fd, buf: &[u8]) -> Result<(), ReprintError> {
+    let mut written = 0;
+    while written < buf.len() {
+        let rest = &buf[written..];
+        let n = unsafe {
+            libc::write(fd, rest.as_ptr() as *const libc::c_void, rest.len() as libc::size_t)
+        };
+        if n < 0 {
+            if std::os::errno() == EINTR {
+                continue;
+            }
+            return Err(fd_error("write to", fd));
+        }
+        written += n as usize;
+    }
+    Ok(())
+}
+
+// The edit `changes` would make to `file`, as a patch which `git apply` (or
+// `patch -p1`) accepts, from `a/<file>` to `b/<file>`, so `file` should be
+// relative to the root of the repository. Nothing is written. Every change
+// must be for `file`, and the output must be UTF-8. If the changes don't
+// change anything, the patch is empty.
+pub fn reprint_git_patch(file: &Path, changes: ChangeSet) -> Result<String, ReprintError> {
+    reprint_git_patch_with(file, changes, ReprintOptions::default())
+}
+
+// As reprint_git_patch, but with ReprintOptions::context_lines from `opts`,
+// and the changes prepared as an edit with `opts` would prepare them (e.g.,
+// with variables substituted and the formatter run). The output isn't
+// post-processed.
+pub fn reprint_git_patch_with(file: &Path,
+                              changes: ChangeSet,
+                              opts: ReprintOptions)
+-> Result<String, ReprintError> {
+    let (input, out) = try!(preview_file(file, changes, &opts));
+    let output = match String::from_utf8(out.buf) {
+        Ok(output) => output,
+        Err(_) => return Err(ReprintError::Process("Changes split a character".to_string()))
+    };
+    let name = match file.as_str() {
+        Some(name) => name,
+        None => return Err(ReprintError::Io(
+            format!("Couldn't turn path '{}' into a string", file.display())))
+    };
+    let context = opts.context_lines.unwrap_or(PATCH_CONTEXT);
+    Ok(unified_diff(name, &input[], &output[], context))
+}
+
+// Lines of context around each hunk in a patch, as for `diff -u`, or from
+// reprint_hunks, unless ReprintOptions::context_lines is set.
+const PATCH_CONTEXT: usize = 3;
+
+// A part of a file which would be changed, see reprint_hunks.
+#[derive(Clone, PartialEq, Show)]
+pub struct Hunk {
+    // The bytes of the file the hunk covers, which are whole lines.
+    pub original_range: Range<u32>,
+    pub original_text: String,
+    // What `original_text` would be replaced with.
+    pub new_text: String,
+    // The lines of the file the hunk covers, numbered from 1.
+    pub line_range: Range<u32>,
+}
+
+// The edit `changes` would make to `file` as a list of hunks, in order, without
+// writing anything. Each hunk holds one or more changes and a few lines of
+// context around them, and changes whose context would overlap or meet share a
+// hunk. Every change must be for `file`. Lines end with `\n`.
+pub fn reprint_hunks(file: &Path, changes: ChangeSet) -> Result<Vec<Hunk>, ReprintError> {
+    reprint_hunks_with(file, changes, ReprintOptions::default())
+}
+
+// As reprint_hunks, but with ReprintOptions::context_lines from `opts`, and
+// the changes prepared as an edit with `opts` would prepare them (see
+// reprint_git_patch_with).
+pub fn reprint_hunks_with(file: &Path,
+                          changes: ChangeSet,
+                          opts: ReprintOptions)
+-> Result<Vec<Hunk>, ReprintError> {
+    let context = opts.context_lines.unwrap_or(PATCH_CONTEXT);
+    try!(check_paths(file, &changes));
+    let changes = try!(normalize(changes));
+    let input = try!(read_file(file));
+    let changes = try!(prepare(&input[], &changes[], &opts)).changes;
+
+    let (lines, offsets) = split_lines(&input[]);
+    // The line holding byte `pos`. An append to input ending with a newline is
+    // on the empty line after the last one.
+    let line_of = |&: pos: usize| {
+        match offsets.binary_search(&pos) {
+            Ok(i) => i,
+            Err(i) => i - 1
+        }
+    };
+    let max_line = lines.len().saturating_sub(1);
+
+    // The lines (from 0, inclusive) and changes of each hunk.
+    let mut groups: Vec<(usize, usize, Vec<Change>)> = vec![];
+    for ch in changes.iter() {
+        let first = line_of(ch.start_byte as usize);
+        let last = if ch.end_byte > ch.start_byte {
+            line_of(ch.end_byte as usize - 1)
+        } else {
+            first
+        };
+        let first = first - cmp::min(first, context);
+        let last = cmp::min(last + context, cmp::max(last, max_line));
+        if let Some(&mut (_, ref mut group_last, ref mut group)) = groups.last_mut() {
+            if first <= *group_last + 1 {
+                *group_last = cmp::max(*group_last, last);
+                group.push(ch.clone());
+                continue;
+            }
+        }
+        groups.push((first, last, vec![ch.clone()]));
+    }
+
+    let mut hunks = vec![];
+    for &(first, last, ref group) in groups.iter() {
+        let start = offsets[first];
+        let end = offsets[cmp::min(last + 1, offsets.len() - 1)];
+        let shifted: Vec<Change> = group.iter().map(|c| {
+            let mut c = c.clone();
+            c.start_byte -= start as u32;
+            c.end_byte -= start as u32;
+            c
+        }).collect();
+        hunks.push(Hunk {
+            original_range: start as u32..end as u32,
+            original_text: input[start..end].to_string(),
+            new_text: try!(splice_str(&input[start..end], &shifted[])),
+            line_range: first as u32 + 1..last as u32 + 2,
+        });
+    }
+    Ok(hunks)
+}
+
+// A unified diff from `before` to `after`, in git's format, with `context`
+// lines of context.
+fn unified_diff(name: &str, before: &str, after: &str, context: usize) -> String {
+    let (a, _) = split_lines(before);
+    let (b, _) = split_lines(after);
+
+    // Runs of changed lines, as (start, end) in `a` and in `b`. Between runs
+    // (and before the first and after the last) the lines of `a` and `b` match.
+    let mut runs = vec![];
+    let (mut i, mut j) = (0, 0);
+    let mut matches = diff_lines(&a[], &b[]);
+    matches.push((a.len(), b.len()));
+    for &(mi, mj) in matches.iter() {
+        if i < mi || j < mj {
+            runs.push((i, mi, j, mj));
+        }
+        i = mi + 1;
+        j = mj + 1;
+    }
+    if runs.is_empty() {
+        return String::new();
+    }
+
+    let mut patch = format!("diff --git a/{0} b/{0}\n--- a/{0}\n+++ b/{0}\n", name);
+    let mut first = 0;
+    while first < runs.len() {
+        // Runs whose context would overlap or meet go in one hunk.
+        let mut last = first;
+        while last + 1 < runs.len() && runs[last + 1].0 - runs[last].1 <= 2 * context {
+            last += 1;
+        }
+        let before_context = cmp::min(runs[first].0, context);
+        let after_context = cmp::min(a.len() - runs[last].1, context);
+        let (a_start, a_end) = (runs[first].0 - before_context, runs[last].1 + after_context);
+        let (b_start, b_end) = (runs[first].2 - before_context, runs[last].3 + after_context);
+
+        // An empty range is given by the line before it.
+        let line_range = |&: start: usize, end: usize| {
+            if start == end {
+                format!("{},0", start)
+            } else {
+                format!("{},{}", start + 1, end - start)
+            }
+        };
+        patch.push_str(&format!("@@ -{} +{} @@\n",
+                                line_range(a_start, a_end),
+                                line_range(b_start, b_end))[]);
+
+        let mut pos = a_start;
+        for &(a_from, a_to, b_from, b_to) in runs[first..last + 1].iter() {
+            push_patch_lines(&mut patch, ' ', &a[pos..a_from]);
+            push_patch_lines(&mut patch, '-', &a[a_from..a_to]);
+            push_patch_lines(&mut patch, '+', &b[b_from..b_to]);
+            pos = a_to;
+        }
+        push_patch_lines(&mut patch, ' ', &a[pos..a_end]);
+
+        first = last + 1;
+    }
+    patch
+}
+
+fn push_patch_lines(patch: &mut String, prefix: char, lines: &[&str]) {
+    for line in lines.iter() {
+        patch.push(prefix);
+        patch.push_str(*line);
+        // Only the last line of a file can lack a newline.
+        if !line.ends_with("\n") {
+            patch.push_str("\n\\ No newline at end of file\n");
+        }
+    }
+}
+
+// The contents of `file` and the output of editing it with `changes` as set by
+// `opts`, before the output is post-processed (e.g., trimmed or re-encoded).
+fn preview_file(file: &Path,
+                changes: ChangeSet,
+                opts: &ReprintOptions)
+-> Result<(String, Output), ReprintError> {
+    try!(check_paths(file, &changes));
+    let changes = try!(normalize(changes));
+    let input = try!(read_file(file));
+    let out = {
+        let changes = try!(prepare(&input[], &changes[], opts)).changes;
+        let mut out = Output::new(&input[], &changes[], false);
+        try!(process(&input[], &changes[], &mut out, None));
+        out
+    };
+    Ok((input, out))
+}
+
+// Checks that every change is for `path`.
+fn check_paths(path: &Path, changes: &ChangeSet) -> Result<(), ReprintError> {
+    if let Some(ch) = changes.iter().find(|c| c.path != *path) {
+        return Err(ReprintError::Verification(
+            format!("Change to '{}' in changes for '{}'",
+                    ch.path.display(),
+                    path.display())));
+    }
+    Ok(())
+}
+
+// `changes` all with the first change's path, for entry points which ignore
+// paths, so that normalize sorts and verifies them as changes to one file.
+fn ignore_paths(mut changes: ChangeSet) -> ChangeSet {
+    if changes.is_empty() {
+        return changes;
+    }
+    let path = changes[0].path.clone();
+    for ch in changes.iter_mut() {
+        ch.path = path.clone();
+    }
+    changes
+}
+
+// A problem with a change which doesn't stop it being applied.
+#[derive(Clone, PartialEq, Show)]
+pub enum Lint {
+    // Change `index` neither removes nor inserts anything.
+    Empty { index: usize },
+    // Change `index` replaces text with the same text.
+    Identity { index: usize },
+    // Change `index` inserts `\r\n` line endings into input which only uses
+    // `\n`, or vice versa.
+    LineEnding { index: usize },
+    // Change `index` removes or inserts more than ReprintOptions allows for
+    // (large_change_bytes or large_change_lines); `bytes` is the larger of the
+    // two amounts.
+    LargeChange { index: usize, bytes: usize },
+    // Line `line` of the output (numbered from 1), which includes text from a
+    // change, is `len` characters long, more than
+    // ReprintOptions::max_line_length.
+    LineTooLong { line: u32, len: usize },
+}
+
+// Lints `changes`, which must be sorted and resolved, against `input`. Only
+// the lint thresholds of `opts` (and max_line_length) are used.
+pub fn lint(input: &str, changes: &ChangeSet, opts: &ReprintOptions) -> Vec<Lint> {
+    let bytes = input.as_bytes();
+    let (input_lf, input_crlf) = line_endings(bytes);
+
+    let mut result = vec![];
+    for (i, ch) in changes.iter().enumerate() {
+        let in_range = ch.start_byte <= ch.end_byte && ch.end_byte as usize <= bytes.len();
+        if ch.start_byte == ch.end_byte && ch.text.is_empty() {
+            result.push(Lint::Empty { index: i });
+        } else if in_range && !ch.template && is_identity(bytes, ch) {
+            result.push(Lint::Identity { index: i });
+        }
+
+        let (text_lf, text_crlf) = line_endings(ch.text.as_bytes());
+        if (text_crlf && input_lf && !input_crlf) || (text_lf && input_crlf && !input_lf) {
+            result.push(Lint::LineEnding { index: i });
+        }
+
+        if in_range {
+            let removed = &bytes[ch.start_byte as usize..ch.end_byte as usize];
+            let inserted = ch.text.as_bytes();
+            let max_bytes = cmp::max(removed.len(), inserted.len());
+            let max_lines = cmp::max(count_line_breaks(removed),
+                                     count_line_breaks(inserted)) as usize;
+            if opts.large_change_bytes.map_or(false, |limit| max_bytes > limit) ||
+               opts.large_change_lines.map_or(false, |limit| max_lines > limit) {
+                result.push(Lint::LargeChange { index: i, bytes: max_bytes });
+            }
+        }
+    }
+
+    if let Some(limit) = opts.max_line_length {
+        // Changes which can't be applied can't make long lines.
+        if let Ok(output) = splice_str(input, &changes[]) {
+            let ranges = output_ranges(&changes[]);
+            for &(line, len) in long_lines(output.as_bytes(), &ranges[], limit).iter() {
+                result.push(Lint::LineTooLong { line: line, len: len });
+            }
+        }
+    }
+    result
+}
+
+// Whether `bytes` has any `\n` line endings, and any `\r\n` line endings.
+fn line_endings(bytes: &[u8]) -> (bool, bool) {
+    let mut lf = false;
+    let mut crlf = false;
+    for (i, &b) in bytes.iter().enumerate() {
+        if b == b'\n' {
+            if i > 0 && bytes[i - 1] == b'\r' {
+                crlf = true;
+            } else {
+                lf = true;
+            }
+        }
+    }
+    (lf, crlf)
+}
+
+// Transcodes UTF-8 `buf` to the encoding called `label`.
+#[cfg(feature = "encoding")]
+fn encode(buf: Vec<u8>, label: &str) -> Result<Vec<u8>, ReprintError> {
+    use encoding::EncoderTrap;
+    use encoding::label::encoding_from_whatwg_label;
+
+    let encoding = match encoding_from_whatwg_label(label) {
+        Some(encoding) => encoding,
+        None => return Err(ReprintError::Process(format!("Unknown encoding '{}'", label)))
+    };
+    let text = match String::from_utf8(buf) {
+        Ok(text) => text,
+        Err(_) => return Err(ReprintError::Process("Changes split a character".to_string()))
+    };
+    match encoding.encode(&text[], EncoderTrap::Strict) {
+        Ok(encoded) => Ok(encoded),
+        Err(e) => Err(ReprintError::Process(format!("Couldn't encode output as '{}': {}",
+                                                    label,
+                                                    e)))
+    }
+}
+
+#[cfg(not(feature = "encoding"))]
+fn encode(_: Vec<u8>, _: &str) -> Result<Vec<u8>, ReprintError> {
+    Err(ReprintError::Process("Output encodings require the `encoding` feature".to_string()))
+}
+
+// If `input` is non-empty and doesn't end with a newline, and some change
+// appends to it, the changes with a newline added to the start of the first
+// such change (see ReprintOptions::ensure_newline_before_append).
+fn newline_before_append(input: &str, changes: &[Change]) -> Option<Vec<Change>> {
+    if input.is_empty() || input.ends_with("\n") {
+        return None;
+    }
+    let len = input.len() as u32;
+    let i = match changes.iter().position(|c| c.start_byte == len) {
+        Some(i) => i,
+        None => return None
+    };
+    if changes[i].text.starts_with("\n") || changes[i].text.starts_with("\r\n") {
+        return None;
+    }
+
+    let mut result = changes.to_vec();
+    result[i].text = "\n".to_string() + &result[i].text[];
+    Some(result)
+}
+
+// The changes with the line endings of their text rewritten to match the line
+// of `input` each starts in (see ReprintOptions::match_surrounding_eol).
+fn match_eol(input: &[u8], changes: &[Change]) -> Vec<Change> {
+    changes.iter().map(|c| {
+        let start = c.start_byte as usize;
+        // The newline which ends the change's line, or failing that, the one
+        // before it.
+        let newline = match input[start..].iter().position(|&b| b == b'\n') {
+            Some(i) => Some(start + i),
+            None => input[..start].iter().rposition(|&b| b == b'\n')
+        };
+        let crlf = match newline {
+            Some(i) => i > 0 && input[i - 1] == b'\r',
+            None => return c.clone()
+        };
+
+        let lf_text = c.text.replace("\r\n", "\n");
+        let mut c = c.clone();
+        c.text = if crlf { lf_text.replace("\n", "\r\n") } else { lf_text };
+        c
+    }).collect()
+}
+
+// The range of the output which holds each change's text. The changes must be
+// sorted and resolved.
+fn output_ranges(changes: &[Change]) -> Vec<(usize, usize)> {
+    let mut delta = 0i64;
+    changes.iter().map(|ch| {
+        let start = (ch.start_byte as i64 + delta) as usize;
+        delta += ch.delta();
+        (start, start + ch.text.len())
+    }).collect()
+}
+
+// Removes trailing spaces and tabs from each line of `buf` which overlaps one
+// of `ranges` (sorted, as from output_ranges), returning the number of lines
+// trimmed. An empty range overlaps the line it is in.
+fn trim_touched_lines(buf: &mut Vec<u8>, ranges: &[(usize, usize)]) -> usize {
+    let lines = touched_lines(&buf[], ranges);
+
+    let mut trimmed = 0;
+    let mut result = Vec::with_capacity(buf.len());
+    let mut pos = 0;
+    for &(start, end) in lines.iter() {
+        let mut keep = end;
+        while keep > start && (buf[keep - 1] == b' ' || buf[keep - 1] == b'\t') {
+            keep -= 1;
+        }
+        if keep < end {
+            trimmed += 1;
+            result.push_all(&buf[pos..keep]);
+            pos = end;
+        }
+    }
+    if trimmed > 0 {
+        result.push_all(&buf[pos..]);
+        *buf = result;
+    }
+    trimmed
+}
+
+// The start and end (before the line break, see line_break) of each line of
+// `buf` which overlaps one of `ranges` (sorted, as from output_ranges), in
+// order.
+fn touched_lines(buf: &[u8], ranges: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let mut lines: Vec<(usize, usize)> = vec![];
+    for &(start, end) in ranges.iter() {
+        let mut line_start = line_start_of(buf, start);
+        loop {
+            let (line_end, next) = match line_break(buf, line_start) {
+                Some(found) => found,
+                None => (buf.len(), buf.len() + 1)
+            };
+            if lines.last() != Some(&(line_start, line_end)) {
+                lines.push((line_start, line_end));
+            }
+            // The next line is touched if the range includes its first byte.
+            if next >= end {
+                break;
+            }
+            line_start = next;
+        }
+    }
+    lines
+}
+
+// How many lines of `buf` overlap `ranges`, as touched_lines, not counting the
+// empty "line" after a final newline (as for changed_line_mask).
+fn lines_changed(buf: &[u8], ranges: &[(usize, usize)]) -> usize {
+    touched_lines(buf, ranges).iter().filter(|&&(start, _)| start < buf.len()).count()
+}
+
+// The line number (from 1) and length in characters of each line of `buf`,
+// which must be UTF-8, which overlaps one of `ranges` (as for touched_lines)
+// and is longer than `limit`. Line terminators don't count towards the length.
+fn long_lines(buf: &[u8], ranges: &[(usize, usize)], limit: usize) -> Vec<(u32, usize)> {
+    let mut result = vec![];
+    let mut line = 1;
+    let mut pos = 0;
+    for &(start, end) in touched_lines(buf, ranges).iter() {
+        line += count_line_breaks(&buf[pos..start]) as u32;
+        pos = start;
+        let content = &buf[start..end];
+        // Count the bytes which start a character.
+        let len = content.iter().filter(|&&b| b & 0xC0 != 0x80).count();
+        if len > limit {
+            result.push((line, len));
+        }
+    }
+    result
+}
+
+// The changes with the variables in their text expanded (see
+// ReprintOptions::variables).
+fn expand_variables(changes: &[Change],
+                    variables: &HashMap<String, String>)
+-> Result<Vec<Change>, ReprintError> {
+    let mut result = Vec::with_capacity(changes.len());
+    for (i, c) in changes.iter().enumerate() {
+        let mut c = c.clone();
+        c.text = match expand_text(&c.text[], variables) {
+            Ok(text) => text,
+            Err(why) => return Err(ReprintError::Verification(
+                format!("Couldn't expand the text of change {}: {}", i, why)))
+        };
+        result.push(c);
+    }
+    Ok(result)
+}
+
+fn expand_text(text: &str, variables: &HashMap<String, String>) -> Result<String, String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('$') {
+        result.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if rest.starts_with("$") {
+            result.push('$');
+            rest = &rest[1..];
+        } else if rest.starts_with("{") {
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => return Err(format!("unterminated variable `${}`", rest))
+            };
+            let var = &rest[1..end];
+            let (name, default) = match var.find_str(":-") {
+                Some(j) => (&var[..j], Some(&var[j + 2..])),
+                None => (var, None)
+            };
+            match (variables.get(name), default) {
+                (Some(value), _) => result.push_str(&value[]),
+                (None, Some(default)) => result.push_str(default),
+                (None, None) => return Err(format!("variable `{}` is not set", name))
+            }
+            rest = &rest[end + 1..];
+        } else {
+            result.push('$');
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn format_changes(changes: &[Change], formatter: &Fn(&str) -> String) -> Vec<Change> {
+    changes.iter().map(|c| {
+        let mut c = c.clone();
+        c.text = formatter(&c.text[]);
+        c
+    }).collect()
+}
+
+// The changes with the indentation of their text rewritten in `style`, and the
+// number of lines which were rewritten (see ReprintOptions::indent_style).
+fn reindent_changes(input: &[u8],
+                    changes: &[Change],
+                    style: IndentStyle)
+-> (Vec<Change>, usize) {
+    let mut count = 0;
+    let result = changes.iter().map(|c| {
+        let starts_line = c.start_byte == 0 || input[c.start_byte as usize - 1] == b'\n';
+        let mut text = String::with_capacity(c.text.len());
+        for (i, line) in c.text.split('\n').enumerate() {
+            if i > 0 {
+                text.push('\n');
+            }
+            if i == 0 && !starts_line {
+                text.push_str(line);
+                continue;
+            }
+            let indent_len = line.bytes().take_while(|&b| b == b' ' || b == b'\t').count();
+            let indent = reindent(&line[..indent_len], style);
+            if &indent[] != &line[..indent_len] {
+                count += 1;
+            }
+            text.push_str(&indent[]);
+            text.push_str(&line[indent_len..]);
+        }
+        let mut c = c.clone();
+        c.text = text;
+        c
+    }).collect();
+    (result, count)
+}
+
+// `indent`, which is only spaces and tabs, in `style`.
+fn reindent(indent: &str, style: IndentStyle) -> String {
+    let width = match style {
+        IndentStyle::Tabs(width) | IndentStyle::Spaces(width) => cmp::max(width, 1)
+    };
+    let mut columns = 0;
+    for b in indent.bytes() {
+        columns = if b == b'\t' { (columns / width + 1) * width } else { columns + 1 };
+    }
+    let (tabs, spaces) = match style {
+        IndentStyle::Tabs(_) => (columns / width, columns % width),
+        IndentStyle::Spaces(_) => (0, columns)
+    };
+    let mut result: String = std::iter::repeat('\t').take(tabs).collect();
+    result.extend(std::iter::repeat(' ').take(spaces));
+    result
+}
+
+// Applies `changes` to everything read from `input`, writing the result to
+// `output`. The changes' paths are ignored, so they should all be for the same
+// file.
+pub fn reprint_stream<R: Reader, W: Writer>(input: &mut R,
+                                            output: &mut W,
+                                            changes: ChangeSet)
+-> Result<(), ReprintError> {
+    let changes = try!(normalize(ignore_paths(changes)));
+    let input = match input.read_to_string() {
+        Ok(s) => s,
+        Err(e) => return Err(ReprintError::Io(format!("Couldn't read input: {}", e.desc)))
+    };
+    let changes = try!(prepare(&input[], &changes[], &ReprintOptions::default())).changes;
+
+    let mut out = Output::new(&input[], &changes[], false);
+    try!(process(&input[], &changes[], &mut out, None));
+
+    match output.write(&out.buf[]).and_then(|_| output.flush()) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(ReprintError::Io(format!("Couldn't write output: {}", e.desc)))
+    }
+}
+
+// Applies `changes` to `input`, returning the edited text. The changes must be
+// sorted (e.g., by normalize) and their paths are ignored. Inserted text is
+// never itself changed (see process).
+pub fn apply(input: &str, changes: &ChangeSet) -> Result<String, ReprintError> {
+    let changes = try!(prepare(input, &changes[], &ReprintOptions::default())).changes;
+    splice_str(input, &changes[])
+}
+
+// For each line of the output of apply(input, changes), whether it includes
+// text from a change, or is where text was removed, e.g., to mark changed lines
+// in an editor's gutter. The changes must be sorted, as for apply.
+pub fn changed_line_mask(input: &str, changes: &ChangeSet) -> Result<Vec<bool>, ReprintError> {
+    let changes = try!(prepare(input, &changes[], &ReprintOptions::default())).changes;
+    let changes = &changes[];
+    let output = try!(splice_str(input, changes));
+
+    let bytes = output.as_bytes();
+    let ends_with_break = bytes.last().map_or(true, |&b| b == b'\n' || b == b'\r');
+    let line_count = count_line_breaks(bytes) as usize + if ends_with_break { 0 } else { 1 };
+    let mut mask: Vec<bool> = std::iter::repeat(false).take(line_count).collect();
+    let mut line = 0;
+    let mut pos = 0;
+    for &(start, _) in touched_lines(bytes, &output_ranges(changes)[]).iter() {
+        line += count_line_breaks(&bytes[pos..start]) as usize;
+        pos = start;
+        // A change at the very end of output which ends with a newline isn't
+        // on any line.
+        if line < line_count {
+            mask[line] = true;
+        }
+    }
+    Ok(mask)
+}
+
+// As apply, but writes the result to `out`, replacing its contents, so that
+// its allocation can be reused across calls. Works on bytes, so template
+// changes must already be expanded.
+pub fn apply_into(input: &[u8],
+                  changes: &ChangeSet,
+                  out: &mut Vec<u8>)
+-> Result<(), ReprintError> {
+    if changes.iter().any(|c| c.template) {
+        return Err(ReprintError::Verification(
+            "Template changes must be expanded before apply_into".to_string()));
+    }
+    // The rest of prepare needs the input as text, and with the default
+    // options does nothing else to changes which aren't templates.
+    let changes = try!(resolve_checked(&changes[], input.len()));
+    let changes = &changes[];
+
+    let mut output = Output::with_buf(mem::replace(out, vec![]), input.len(), changes, false);
+    let result = splice(input, changes, |kind, bytes| output.push_span(kind, bytes), None);
+    *out = output.buf;
+    result
+}
+
+// How much memory applying a change set would take, see estimate_memory.
+#[derive(Clone, Copy, Show)]
+pub struct MemoryEstimate {
+    // The size of the output buffer, which is allocated up front.
+    pub output_capacity: usize,
+    // The most memory apply (or reprint) needs at once: the input and the
+    // output, both held in full.
+    pub peak: usize,
+    // Whether the changes edit so little of the input (less than
+    // STREAMING_FRACTION of it) that a streaming writer, which copies the
+    // rest through, would do better.
+    pub prefer_streaming: bool,
+}
+
+pub const STREAMING_FRACTION: f64 = 0.1;
+
+// Estimates the memory needed to apply `changes` to an input of `input_len`
+// bytes, without applying them. Only the changes' offsets and text lengths are
+// used, so the changes aren't checked, and templates count as their unexpanded
+// text.
+pub fn estimate_memory(input_len: usize, changes: &ChangeSet) -> MemoryEstimate {
+    let mut removed = 0u64;
+    let mut inserted = 0u64;
+    for c in changes.iter() {
+        // Offsets relative to the end count backwards.
+        let len = if c.from_end {
+            c.start_byte.saturating_sub(c.end_byte)
+        } else {
+            c.end_byte.saturating_sub(c.start_byte)
+        };
+        removed += len as u64;
+        inserted += c.text.len() as u64;
+    }
+    let output_len = (input_len as u64 + inserted).saturating_sub(removed);
+    let output_capacity = output_len as usize;
+    let edited = removed + inserted;
+    MemoryEstimate {
+        output_capacity: output_capacity,
+        peak: input_len.saturating_add(output_capacity),
+        prefer_streaming: (edited as f64) < input_len as f64 * STREAMING_FRACTION,
+    }
+}
+
+// As apply, but edits `s` itself. Every change is checked before any is made,
+// so on error `s` is unchanged.
+pub fn apply_str_in_place(s: &mut String, changes: &ChangeSet) -> Result<(), ReprintError> {
+    let changes = try!(prepare(&s[], &changes[], &ReprintOptions::default())).changes;
+    for ch in changes.iter() {
+        if !s.is_char_boundary(ch.start_byte as usize) ||
+           !s.is_char_boundary(ch.end_byte as usize) {
+            return Err(ReprintError::Process(
+                format!("Change {}--{} splits a character", ch.start_byte, ch.end_byte)));
+        }
+    }
+
+    // Building the result in one pass is linear in the size of the output,
+    // where editing `s` one change at a time would copy the rest of it for
+    // each change.
+    let output = try!(splice_str(&s[], &changes[]));
+    *s = output;
+    Ok(())
+}
+
+// As apply, but borrows `input` rather than copying it if the changes would
+// leave it unchanged, e.g., because there are none.
+pub fn apply_str_cow<'a>(input: &'a str,
+                         changes: &ChangeSet)
+-> Result<CowString<'a>, ReprintError> {
+    let changes = try!(prepare(input, &changes[], &ReprintOptions::default())).changes;
+    let changes = &changes[];
+    if changes.iter().all(|c| is_identity(input.as_bytes(), c)) {
+        return Ok(Cow::Borrowed(input));
+    }
+    splice_str(input, changes).map(|s| Cow::Owned(s))
+}
+
+// A piece of the result of applying a change set, see preview_spans.
+#[derive(Clone, PartialEq, Show)]
+pub enum Span<'a> {
+    // Input which is left as it is.
+    Keep(&'a str),
+    // Input which is removed, or replaced by the following Insert.
+    Remove(&'a str),
+    // Text inserted by a change.
+    Insert(&'a str),
+}
+
+// Breaks down the result of applying `changes` to `input` into spans, without
+// consuming the changes. Concatenating the Keep and Insert spans gives the
+// output of apply. As for apply, the changes must be sorted, and any changes
+// relative to the end of the input must already be resolved.
+pub fn preview_spans<'a>(input: &'a str,
+                         changes: &'a ChangeSet)
+-> Result<Vec<Span<'a>>, ReprintError> {
+    if changes.iter().any(|c| c.from_end) {
+        return Err(ReprintError::Verification(
+            "Changes relative to the end must be resolved before previewing".to_string()));
+    }
+    if changes.iter().any(|c| c.template) {
+        return Err(ReprintError::Verification(
+            "Template changes must be expanded before previewing".to_string()));
+    }
+    try!(verify(&changes[]));
+    try!(verify_against(input.len() as u64, changes.iter()));
+
+    let mut spans = vec![];
+    try!(splice(input.as_bytes(), &changes[], |kind, bytes| spans.push((kind, bytes)), None));
+
+    let mut result = Vec::with_capacity(spans.len());
+    for &(kind, bytes) in spans.iter() {
+        let text = match str::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(_) => return Err(ReprintError::Process("Changes split a character".to_string()))
+        };
+        result.push(match kind {
+            SpanKind::Keep => Span::Keep(text),
+            SpanKind::Remove => Span::Remove(text),
+            SpanKind::Insert => Span::Insert(text),
+        });
+    }
+    Ok(result)
+}
+
+// precondition: verify(changes) && verify_against(input.len(), changes)
+fn splice_str(input: &str, changes: &[Change]) -> Result<String, ReprintError> {
+    let mut out = Output::new(input, changes, false);
+    try!(process(input, changes, &mut out, None));
+    match String::from_utf8(out.buf) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(ReprintError::Process("Changes split a character".to_string()))
+    }
+}
+
+// Renders `input` with each change shown inline as `«old→new»`, for
+// debugging. The changes must be sorted and resolved; ranges are clamped to the
+// input rather than checked.
+pub fn annotate(input: &str, changes: &ChangeSet) -> String {
+    let bytes = input.as_bytes();
+    let mut result = String::with_capacity(input.len());
+    let mut pos = 0us;
+    for ch in changes.iter() {
+        let start = cmp::min(cmp::max(ch.start_byte as usize, pos), bytes.len());
+        let end = cmp::min(cmp::max(ch.end_byte as usize, start), bytes.len());
+        result.push_str(&*String::from_utf8_lossy(&bytes[pos..start]));
+        result.push('«');
+        result.push_str(&*String::from_utf8_lossy(&bytes[start..end]));
+        result.push('→');
+        result.push_str(&ch.text[]);
+        result.push('»');
+        pos = end;
+    }
+    result.push_str(&*String::from_utf8_lossy(&bytes[pos..]));
+    result
+}
+
+// Whether applying `changes` to `input` would leave it as it is, because
+// every change is empty or replaces text with the same text. False if the
+// changes couldn't be applied at all.
+pub fn is_noop(input: &str, changes: &ChangeSet) -> bool {
+    match prepare(input, &changes[], &ReprintOptions::default()) {
+        Ok(prepared) => prepared.changes.iter().all(|c| is_identity(input.as_bytes(), c)),
+        Err(_) => false
+    }
+}
+
+// Whether `ch` would replace its range of `input` with the same bytes, which
+// includes an empty change. Assumes `ch` is in range.
+fn is_identity(input: &[u8], ch: &Change) -> bool {
+    &input[ch.start_byte as usize..ch.end_byte as usize] == ch.text.as_bytes()
+}
+
+// The changes which aren't identities (see is_identity) for `input`.
+fn drop_identity(input: &[u8], changes: &[Change]) -> Result<Vec<Change>, ReprintError> {
+    try!(verify_against(input.len() as u64, changes.iter()));
+    Ok(changes.iter().filter(|c| !is_identity(input, c)).map(|c| c.clone()).collect())
+}
+
+// Converts any changes relative to the end of the input (see Change::from_end)
+// to absolute offsets for an input of `input_len` bytes, and sorts the result.
+pub fn resolve_relative(mut changes: ChangeSet, input_len: u32) -> Result<ChangeSet, ReprintError> {
+    for ch in changes.iter_mut() {
+        if !ch.from_end {
+            continue;
+        }
+        if ch.start_byte > input_len || ch.end_byte > input_len {
+            return Err(ReprintError::Verification(
+                format!("Change {}--{} from the end out of range for input of length {}",
+                        ch.start_byte,
+                        ch.end_byte,
+                        input_len)));
+        }
+        ch.start_byte = input_len - ch.start_byte;
+        ch.end_byte = input_len - ch.end_byte;
+        ch.from_end = false;
+    }
+    changes.sort();
+    Ok(changes)
+}
+
+// If any of `changes` are relative to the end of the input, the whole set with
+// those resolved against `len`, sorted and verified.
+fn resolve_in(changes: &[Change], len: usize) -> Result<Option<Vec<Change>>, ReprintError> {
+    if !changes.iter().any(|c| c.from_end) {
+        return Ok(None);
+    }
+    let changes = try!(resolve_relative(ChangeSet::from_vec(changes.to_vec()), len as u32));
+    try!(verify(&changes[]));
+    Ok(Some(changes.into_vec()))
+}
+
+// If any of `changes` are templates, the whole set with those expanded against
+// `input`. The changes must already be resolved.
+fn expand_in(input: &str, changes: &[Change]) -> Result<Option<Vec<Change>>, ReprintError> {
+    if !changes.iter().any(|c| c.template) {
+        return Ok(None);
+    }
+    try!(verify_against(input.len() as u64, changes.iter()));
+
+    let mut result = Vec::with_capacity(changes.len());
+    for ch in changes.iter() {
+        let mut ch = ch.clone();
+        if ch.template {
+            let start = ch.start_byte as usize;
+            let end = ch.end_byte as usize;
+            if !input.is_char_boundary(start) || !input.is_char_boundary(end) {
+                return Err(ReprintError::Process(
+                    format!("Template change {}--{} splits a character", start, end)));
+            }
+            ch.text = ch.text.replace("{0}", &input[start..end]);
+            ch.template = false;
+        }
+        result.push(ch);
+    }
+    // Expanding changes the text, which can change the order of insertions at
+    // the same point.
+    result.sort();
+    try!(verify(&result[]));
+    Ok(Some(result))
+}
+
+// `changes` resolved against an input of `len` bytes (see resolve_in), and
+// checked to be compatible and in range.
+fn resolve_checked(changes: &[Change], len: usize) -> Result<Vec<Change>, ReprintError> {
+    let resolved = try!(resolve_in(changes, len));
+    let changes = match resolved {
+        Some(changes) => changes,
+        None => changes.to_vec()
+    };
+    try!(verify(&changes[]));
+    try!(verify_against(len as u64, changes.iter()));
+    Ok(changes)
+}
+
+// The changes to splice into a file, see prepare.
+struct Prepared {
+    changes: Vec<Change>,
+    // Changes skipped because of ReprintOptions::skip_line_prefix.
+    skipped: Vec<Change>,
+    // The number of changes dropped by ReprintOptions::drop_identity_changes.
+    dropped: usize,
+    // The number of lines reindented by ReprintOptions::indent_style.
+    reindented_lines: usize,
+}
+
+// Turns `changes` for the file `input`, which must be sorted, into the changes
+// to splice into it, as set by `opts`: resolves and checks them, skips, expands
+// and rewrites them. Everything which edits or previews a file goes through
+// here, so that a preview shows what an edit with the same options would
+// write.
+fn prepare(input: &str,
+           changes: &[Change],
+           opts: &ReprintOptions)
+-> Result<Prepared, ReprintError> {
+    let changes = try!(resolve_checked(changes, input.len()));
+    let changes = &changes[];
+
+    if let Some(ref region) = opts.region {
+        try!(verify_in_region(region, changes));
+    }
+
+    let mut skipped = vec![];
+    let unskipped = opts.skip_line_prefix.as_ref().map(|prefix| {
+        let (skip, keep): (Vec<Change>, Vec<Change>) =
+            changes.iter().map(|c| c.clone()).partition(|c| {
+                touches_line_with_prefix(input.as_bytes(), c, prefix.as_bytes())
+            });
+        skipped = skip;
+        keep
+    });
+    let changes = match unskipped {
+        Some(ref changes) => &changes[],
+        None => changes
+    };
+
+    let expanded = try!(expand_in(input, changes));
+    let changes = match expanded {
+        Some(ref changes) => &changes[],
+        None => changes
+    };
+
+    let substituted = match opts.variables {
+        Some(ref variables) => Some(try!(expand_variables(changes, variables))),
+        None => None
+    };
+    let changes = match substituted {
+        Some(ref changes) => &changes[],
+        None => changes
+    };
+
+    let formatted = opts.formatter.as_ref().map(|f| format_changes(changes, &**f));
+    let changes = match formatted {
+        Some(ref changes) => &changes[],
+        None => changes
+    };
+
+    let mut reindented_lines = 0;
+    let reindented = opts.indent_style.map(|style| {
+        let (changes, count) = reindent_changes(input.as_bytes(), changes, style);
+        reindented_lines = count;
+        changes
+    });
+    let changes = match reindented {
+        Some(ref changes) => &changes[],
+        None => changes
+    };
+
+    let appended = if opts.ensure_newline_before_append {
+        newline_before_append(input, changes)
+    } else {
+        None
+    };
+    let changes = match appended {
+        Some(ref changes) => &changes[],
+        None => changes
+    };
+
+    let matched = if opts.match_surrounding_eol {
+        Some(match_eol(input.as_bytes(), changes))
+    } else {
+        None
+    };
+    let changes = match matched {
+        Some(ref changes) => &changes[],
+        None => changes
+    };
+
+    if opts.reject_nul {
+        if let Some(index) = changes.iter().position(|c| c.text.as_bytes().contains(&0)) {
+            return Err(ReprintError::ContainsNul { index: index });
+        }
+    }
+
+    let kept = if opts.drop_identity_changes {
+        Some(try!(drop_identity(input.as_bytes(), changes)))
+    } else {
+        None
+    };
+    let dropped = kept.as_ref().map_or(0, |kept| changes.len() - kept.len());
+    let changes = match kept {
+        Some(ref changes) => &changes[],
+        None => changes
+    };
+
+    // Checked again now the text is final: expanding, substituting, formatting
+    // and reindenting can all make the output larger.
+    try!(verify_against(input.len() as u64, changes.iter()));
+
+    Ok(Prepared {
+        changes: changes.to_vec(),
+        skipped: skipped,
+        dropped: dropped,
+        reindented_lines: reindented_lines,
+    })
+}
+
+// The byte offset in `input` of column `col` of line `line`. Lines are
+// numbered from 1 and columns are byte offsets from the start of the line,
+// from 0. Lines end with `\n`, `\r\n`, or a `\r` on its own; the terminator
+// is not part of the line, so a column which lands on the `\r` of a `\r\n` is
+// the end of the line, and any greater column is out of range. None if the
+// position is past the end of its line or the input, or splits a character.
+pub fn resolve(input: &str, line: u32, col: u32) -> Option<u32> {
+    if line == 0 {
+        return None;
+    }
+
+    let bytes = input.as_bytes();
+    let mut start = 0us;
+    for _ in 1..line {
+        match line_break(bytes, start) {
+            Some((_, next)) => start = next,
+            None => return None
+        }
+    }
+    let end = match line_break(bytes, start) {
+        Some((end, _)) => end,
+        None => bytes.len()
+    };
+
+    let pos = start + col as usize;
+    if pos > end || !input.is_char_boundary(pos) {
+        return None;
+    }
+    Some(pos as u32)
+}
+
+// The byte offset of the `char_index`th (from 0) character of `input`, or of
+// the end of `input` if that is how many characters it has. None if it has
+// fewer.
+pub fn char_to_byte(input: &str, char_index: usize) -> Option<u32> {
+    input.char_indices()
+         .map(|(i, _)| i)
+         .chain(Some(input.len()).into_iter())
+         .nth(char_index)
+         .map(|i| i as u32)
+}
+
+// The inverse of char_to_byte: the number of characters before byte `byte` of
+// `input`. None if `byte` is past the end of `input` or inside a character.
+pub fn byte_to_char(input: &str, byte: u32) -> Option<usize> {
+    let byte = byte as usize;
+    if byte > input.len() || !input.is_char_boundary(byte) {
+        return None;
+    }
+    Some(input[..byte].chars().count())
+}
+
+// Changes to `path` from edits given as `(start, end, text)` where `start`
+// and `end` are `(row, column)` points into `input`, as used by tree-sitter:
+// rows and columns (in bytes) both count from 0. The result is normalized.
+pub fn from_points(path: &Path,
+                   input: &str,
+                   edits: Vec<((u32, u32), (u32, u32), String)>)
+-> Result<ChangeSet, ReprintError> {
+    let to_offset = |&: (row, col): (u32, u32)| {
+        match resolve(input, row + 1, col) {
+            Some(offset) => Ok(offset),
+            None => Err(ReprintError::Verification(
+                format!("Point ({}, {}) is not in the input", row, col)))
+        }
+    };
+
+    let mut changes = ChangeSet::new();
+    for (start, end, text) in edits.into_iter() {
+        let start = try!(to_offset(start));
+        let end = try!(to_offset(end));
+        changes.push(Change::new(path.clone(), start, end, text));
+    }
+    normalize(changes)
+}
+
+// The first line break in `bytes` at or after `from`, as the offset of the
+// terminator and the offset of the start of the next line. `\n`, `\r\n` and
+// a lone `\r` are each a single line break.
+fn line_break(bytes: &[u8], from: usize) -> Option<(usize, usize)> {
+    bytes[from..].iter().position(|&b| b == b'\n' || b == b'\r').map(|i| {
+        let end = from + i;
+        if bytes[end] == b'\r' && end + 1 < bytes.len() && bytes[end + 1] == b'\n' {
+            (end, end + 2)
+        } else {
+            (end, end + 1)
+        }
+    })
+}
+
+// The start of the line holding byte `pos` of `bytes`, with line breaks as for
+// line_break. A position between the `\r` and `\n` of a `\r\n` is on the line
+// the `\r\n` ends.
+fn line_start_of(bytes: &[u8], pos: usize) -> usize {
+    let mut end = pos;
+    if end > 0 && end < bytes.len() && bytes[end - 1] == b'\r' && bytes[end] == b'\n' {
+        end -= 1;
+    }
+    match bytes[..end].iter().rposition(|&b| b == b'\n' || b == b'\r') {
+        Some(i) => i + 1,
+        None => 0
+    }
+}
+
+// For each line of `input` (numbered from 1), the number of lines by which
+// applying `changes` moves it, i.e., its line number in the output minus its
+// line number in the input. A line which starts inside a removed range gets the
+// offset from the changes before it. Lines end as for resolve. The changes must
+// be sorted and resolved.
+pub fn line_offset_map(input: &str, changes: &ChangeSet) -> Vec<(u32, i64)> {
+    let bytes = input.as_bytes();
+    let mut result = vec![];
+    let mut offset = 0i64;
+    // The next change which hasn't been counted in offset.
+    let mut next = 0us;
+    let mut line = 1u32;
+    let mut line_start = 0us;
+    loop {
+        // Changes which end at or before the start of this line move it.
+        while next < changes.len() && changes[next].end_byte as usize <= line_start {
+            let ch = &changes[next];
+            let start = cmp::min(ch.start_byte as usize, bytes.len());
+            let end = cmp::min(ch.end_byte as usize, bytes.len());
+            offset += count_line_breaks(ch.text.as_bytes()) - count_line_breaks(&bytes[start..end]);
+            next += 1;
+        }
+        result.push((line, offset));
+
+        match line_break(bytes, line_start) {
+            Some((_, next_line)) => {
+                line += 1;
+                line_start = next_line;
+            }
+            None => break
+        }
+    }
+    result
+}
+
+// Where byte `original` of the input ends up once `changes` are applied.
+// Insertions at `original` come before it, so it moves past their text. An
+// offset strictly inside a replaced or removed range maps to the end of the
+// replacement text. The changes must be sorted and resolved.
+pub fn map_offset(changes: &ChangeSet, original: u32) -> u32 {
+    let mut delta = 0i64;
+    for ch in changes.iter() {
+        if ch.end_byte <= original {
+            delta += ch.delta();
+        } else if ch.start_byte < original {
+            return (ch.start_byte as i64 + delta + ch.text.len() as i64) as u32;
+        } else {
+            break;
+        }
+    }
+    (original as i64 + delta) as u32
+}
+
+// The number of line breaks in `bytes`, as for line_break.
+fn count_line_breaks(bytes: &[u8]) -> i64 {
+    let mut count = 0;
+    let mut pos = 0;
+    while let Some((_, next)) = line_break(bytes, pos) {
+        count += 1;
+        pos = next;
+    }
+    count
+}
+
+// The hash used for ReprintOptions::expected_hash and ReprintSummary::hash.
+pub fn content_hash(content: &[u8]) -> String {
+    let mut hasher = OutputHasher::new();
+    hasher.input(content);
+    hasher.result()
+}
+
+// A self-check that the result of `changes` (all for the file `input`) doesn't
+// depend on their order: applies them one at a time, in the order given and in
+// reverse, moving each change still to be made past the one just made (as
+// rebase_change), and checks both results are what apply gives for the sorted
+// set. Insertions at the same point with different text do depend on their
+// order, so a set with any fails unless they are given in sorted order.
+pub fn validate_commutativity(input: &str, changes: &ChangeSet) -> Result<(), ReprintError> {
+    let mut sorted = ChangeSet::from_vec(changes.to_vec());
+    sorted.sort();
+    let expected = try!(apply(input, &sorted));
+
+    // Since apply succeeded, every change is in range and on character
+    // boundaries, so it can be resolved and expanded on its own.
+    let len = input.len() as u32;
+    let mut given: Vec<Change> = changes.iter().map(|c| {
+        let mut c = c.clone();
+        if c.from_end {
+            c.start_byte = len - c.start_byte;
+            c.end_byte = len - c.end_byte;
+            c.from_end = false;
+        }
+        if c.template {
+            c.text = c.text.replace("{0}", &input[c.start_byte as usize..c.end_byte as usize]);
+            c.template = false;
+        }
+        c
+    }).collect();
+
+    let forward = try!(apply_one_at_a_time(input, given.clone()));
+    given.reverse();
+    let backward = try!(apply_one_at_a_time(input, given));
+    if forward != expected || backward != expected {
+        return Err(ReprintError::Verification(
+            "The result of applying changes depends on their order".to_string()));
+    }
+    Ok(())
+}
+
+// Applies `changes` to `input` in the order given, each to the output of the
+// one before, rebasing the rest over each change as it is made.
+fn apply_one_at_a_time(input: &str, mut changes: Vec<Change>) -> Result<String, ReprintError> {
+    let mut output = input.to_string();
+    while !changes.is_empty() {
+        let ch = changes.remove(0);
+        output = try!(apply(&output[], &ChangeSet::from_vec(vec![ch.clone()])));
+        for pending in changes.iter_mut() {
+            try!(rebase_change(&ch, pending));
+        }
+    }
+    Ok(output)
+}
+
+// Checks `changes` against `input` and `opts` as thoroughly as possible
+// without applying them, returning every problem found rather than just the
+// first: inverted, out of range, unsorted and overlapping changes, changes
+// which split a character, and anything `opts` rules out (max_changes, region,
+// reject_nul, and the output size). Identity changes are not errors, but see
+// lint.
+pub fn validate(input: &str,
+                changes: &ChangeSet,
+                opts: &ReprintOptions)
+-> Result<(), Vec<ReprintError>> {
+    let mut errors = vec![];
+    if let Some(limit) = opts.max_changes {
+        if changes.len() > limit {
+            errors.push(ReprintError::TooManyChanges { count: changes.len(), limit: limit });
+        }
+    }
+
+    let len = input.len() as u32;
+    let mut placed = vec![];
+    for (i, ch) in changes.iter().enumerate() {
+        if i > 0 && !ch.from_end && !changes[i - 1].from_end && changes[i - 1] > *ch {
+            errors.push(ReprintError::Verification(
+                format!("Changes out of order: {}--{} follows {}--{}",
+                        ch.start_byte,
+                        ch.end_byte,
+                        changes[i - 1].start_byte,
+                        changes[i - 1].end_byte)));
+        }
+        if let Err(e) = verify_change(ch) {
+            errors.push(e);
+            continue;
+        }
+
+        let mut ch = ch.clone();
+        if ch.from_end {
+            if ch.start_byte > len {
+                errors.push(ReprintError::Verification(
+                    format!("Change {}--{} from the end out of range for input of length {}",
+                            ch.start_byte,
+                            ch.end_byte,
+                            len)));
+                continue;
+            }
+            ch.start_byte = len - ch.start_byte;
+            ch.end_byte = len - ch.end_byte;
+            ch.from_end = false;
+        }
+        if let Err(e) = verify_against(len as u64, Some(&ch).into_iter()) {
+            errors.push(e);
+            continue;
+        }
+        if !input.is_char_boundary(ch.start_byte as usize) ||
+           !input.is_char_boundary(ch.end_byte as usize) {
+            errors.push(ReprintError::Process(
+                format!("Change {}--{} splits a character", ch.start_byte, ch.end_byte)));
+        }
+        if let Some(ref region) = opts.region {
+            if let Err(e) = verify_in_region(region, &[ch.clone()]) {
+                errors.push(e);
+            }
+        }
+        if opts.reject_nul && ch.text.as_bytes().contains(&0) {
+            errors.push(ReprintError::ContainsNul { index: i });
+        }
+        placed.push(ch);
+    }
+
+    placed.sort();
+    for (k, pair) in placed.windows(2).enumerate() {
+        if let Err(e) = verify_pair((k, &pair[0]), (k + 1, &pair[1])) {
+            errors.push(e);
+        }
+    }
+    if let Err(e) = verify_against(len as u64, placed.iter()) {
+        errors.push(e);
+    }
+
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+// Sorts and verifies `changes` and removes exact duplicates, without applying
+// them. The result is the set reprint would apply.
+pub fn normalize(changes: ChangeSet) -> Result<ChangeSet, ReprintError> {
+    normalize_with(changes, DedupKey::Full)
+}
+
+// As normalize, but removes the duplicates given by `key`.
+pub fn normalize_with(mut changes: ChangeSet, key: DedupKey) -> Result<ChangeSet, ReprintError> {
+    try!(dedup(&mut changes.0, key));
+    for &(_, changes) in files(&changes).iter() {
+        try!(verify(changes));
+    }
+    Ok(changes)
+}
+
+// Sorts `changes` and removes the duplicates given by `key`. With RangeOnly,
+// two changes to the same range with different text are a SameStartConflict,
+// and `changes` is left sorted with only exact duplicates removed.
+fn dedup(changes: &mut Vec<Change>, key: DedupKey) -> Result<(), ReprintError> {
+    let mut conflict = None;
+    if key == DedupKey::RangeOnly {
+        // The sort is stable, so the first change given for each range comes
+        // first.
+        changes.sort_by(|a, b| range_key(a).cmp(&range_key(b)));
+        let mut result: Vec<Change> = Vec::with_capacity(changes.len());
+        for ch in changes.iter() {
+            // Insertions at the same point are only duplicates if they are
+            // identical, as for Full.
+            let same_range = ch.start_byte != ch.end_byte &&
+                             result.last().map_or(false, |last| range_key(last) == range_key(ch));
+            if !same_range {
+                result.push(ch.clone());
+            } else if result.last().map_or(false, |last| last.text != ch.text) {
+                conflict = Some(ch.start_byte);
+                break;
+            }
+        }
+        if conflict.is_none() {
+            *changes = result;
+        }
+    }
+
+    changes.sort();
+    changes.dedup();
+    match conflict {
+        Some(start) => Err(ReprintError::SameStartConflict { start: start }),
+        None => Ok(())
+    }
+}
+
+// What DedupKey::RangeOnly compares.
+fn range_key(c: &Change) -> (&Path, u32, u32, bool) {
+    (&c.path, c.start_byte, c.end_byte, c.from_end)
+}
+
+// Checks that every change to `file` fits within it, without reading it. Only
+// the ranges are checked, not whether they fall on character boundaries.
+pub fn check_bounds(file: &Path, changes: &ChangeSet) -> Result<(), ReprintError> {
+    let len = match fs::stat(file) {
+        Ok(stat) => stat.size,
+        Err(e) => return Err(ReprintError::Io(format!("Couldn't stat '{}': {}",
+                                                      file.display(),
+                                                      e.desc)))
+    };
+    verify_against(len, changes.iter().filter(|c| c.path == *file))
+}
+
+// Checks that changes fit within an input of `len` bytes, and that the output
+// will be small enough for its offsets to fit in a u32. The output's size is
+// added up with overflow checks, since huge change sets could wrap even an i64.
+fn verify_against<'a, I>(len: u64, changes: I) -> Result<(), ReprintError>
+    where I: Iterator<Item=&'a Change>
+{
+    if len > std::i64::MAX as u64 {
+        return Err(ReprintError::SizeOverflow);
+    }
+    let mut out_len = len as i64;
+    for ch in changes {
+        if ch.start_byte as u64 > len || ch.end_byte as u64 > len {
+            return Err(ReprintError::Verification(
+                format!("Change {}--{} out of range for input of length {}",
+                        ch.start_byte,
+                        ch.end_byte,
+                        len)));
+        }
+        out_len = match out_len.checked_add(ch.delta()) {
+            Some(out_len) => out_len,
+            None => return Err(ReprintError::SizeOverflow)
+        };
+    }
+    if out_len > std::u32::MAX as i64 {
+        return Err(ReprintError::OutputTooLarge(out_len as u64));
+    }
+    Ok(())
+}
+
+// Whether any line which `ch` touches starts with `prefix`, once any spaces
+// and tabs at the start of the line are skipped, so indented lines count. An
+// insertion touches the line it is in.
+fn touches_line_with_prefix(input: &[u8], ch: &Change, prefix: &[u8]) -> bool {
+    let start = cmp::min(ch.start_byte as usize, input.len());
+    let end = cmp::min(ch.end_byte as usize, input.len());
+    let mut line_start = line_start_of(input, start);
+    loop {
+        let indent = input[line_start..].iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+        if input[line_start + indent..].starts_with(prefix) {
+            return true;
+        }
+        match line_break(input, line_start) {
+            // The next line is touched if the change includes its first byte.
+            Some((_, next)) if next < end => line_start = next,
+            _ => return false
+        }
+    }
+}
+
+fn verify_in_region(region: &Range<u32>, changes: &[Change]) -> Result<(), ReprintError> {
+    for ch in changes.iter() {
+        if ch.start_byte < region.start || ch.end_byte > region.end {
+            return Err(ReprintError::Verification(
+                format!("Change {}--{} outside region {}--{}",
+                        ch.start_byte,
+                        ch.end_byte,
+                        region.start,
+                        region.end)));
+        }
+    }
+    Ok(())
+}
+
+// Splits a sorted change set into the run of changes for each file.
+fn files<'a>(changes: &'a ChangeSet) -> Vec<(&'a Path, &'a [Change])> {
+    let mut result = vec![];
+    let mut start = 0;
+    for i in 1..changes.len() + 1 {
+        if i == changes.len() || changes[i].path != changes[start].path {
+            result.push((&changes[start].path, &changes[start..i]));
+            start = i;
+        }
+    }
+    result
+}
+
+// Assumes changes is sorted. A change overlaps the one before it if it starts
+// before that one ends. Changes which only meet at a boundary never overlap,
+// and the sort order decides how they interleave:
+//  * an insertion at the start of a replacement sorts first, so its text comes
+//    before the replacement's;
+//  * an insertion at the end of a replacement sorts after it, so its text
+//    comes after the replacement's;
+//  * several insertions at one point are all applied, ordered by their text;
+//  * two replacements may share a boundary, one ending where the other starts;
+//  * any number of insertions may share a start with one replacement, and they
+//    all sort before it, but two replacements with the same start conflict
+//    (ReprintError::SameStartConflict).
+// An insertion strictly inside another change's range is an overlap. A change
+// which starts after another starts and ends before (or where) it ends is
+// contained in it (ReprintError::ContainedChange), rather than overlapping.
+//
+// Changes relative to the end of the file can't be placed until the file's
+// length is known, so they are only checked once resolved.
+fn verify(changes: &[Change]) -> Result<(), ReprintError> {
+    let mut prev: Option<(usize, &Change)> = None;
+    for (i, ch) in changes.iter().enumerate() {
+        try!(verify_change(ch));
+        if ch.from_end {
+            continue;
+        }
+        if let Some(prev) = prev {
+            try!(verify_pair(prev, (i, ch)));
+        }
+        prev = Some((i, ch));
+    }
+
+    Ok(())
+}
+
+fn verify_change(ch: &Change) -> Result<(), ReprintError> {
+    let inverted = if ch.from_end {
+        ch.start_byte < ch.end_byte
+    } else {
+        ch.end_byte < ch.start_byte
+    };
+    if inverted {
+        return Err(ReprintError::Verification(format!("Bad change at {}", ch.start_byte)));
+    }
+    Ok(())
+}
+
+// Checks `ch` against the change before it in a sorted set, each with its index
+// in the set (for ReprintError::ContainedChange). Since the set is sorted,
+// checking neighbours is enough to rule out any overlap.
+fn verify_pair((prev_index, prev): (usize, &Change),
+               (index, ch): (usize, &Change))
+-> Result<(), ReprintError> {
+    // Insertions sort before replacements with the same start, so if `ch` is a
+    // replacement, the only one which can share its start is `prev`.
+    if prev.path == ch.path &&
+       ch.start_byte == prev.start_byte &&
+       prev.start_byte < prev.end_byte &&
+       ch.start_byte < ch.end_byte {
+        return Err(ReprintError::SameStartConflict { start: ch.start_byte });
+    }
+    // Since the changes are sorted, and `prev` doesn't overlap the changes
+    // before it, only `prev` can contain `ch`.
+    if prev.path == ch.path &&
+       prev.start_byte < ch.start_byte &&
+       ch.start_byte < prev.end_byte &&
+       ch.end_byte <= prev.end_byte {
+        return Err(ReprintError::ContainedChange { outer: prev_index, inner: index });
+    }
+    if prev.path == ch.path && ch.start_byte < prev.end_byte {
+        return Err(ReprintError::Verification(
+            format!("Overlapping changes: {}--{} overlaps {}--{} ",
+                    prev.start_byte,
+                    prev.end_byte,
+                    ch.start_byte,
+                    ch.end_byte)));
+    }
+    Ok(())
+}
+
+fn check_utf8(buf: &[u8]) -> Result<(), ReprintError> {
+    match str::from_utf8(buf) {
+        Ok(_) => Ok(()),
+        Err(str::Utf8Error::InvalidByte(pos)) => Err(ReprintError::InvalidUtf8 { pos: pos }),
+        // A character is cut short by the end of the buffer, so the error is
+        // at its first byte.
+        Err(str::Utf8Error::TooShort) => {
+            let pos = buf.iter().rposition(|&b| b & 0xC0 != 0x80).unwrap_or(0);
+            Err(ReprintError::InvalidUtf8 { pos: pos })
+        }
+    }
+}
+
+// An absolute path to the same file as `path`, with every symlink along it
+// followed (see ReprintOptions::canonicalize).
+fn canonicalize(path: &Path) -> Result<Path, ReprintError> {
+    // Give up on symlink loops.
+    const MAX_LINKS: usize = 256;
+
+    let err = |&: e: IoError| {
+        ReprintError::Io(format!("Couldn't resolve '{}': {}", path.display(), e.desc))
+    };
+    let absolute = match std::os::make_absolute(path) {
+        Ok(absolute) => absolute,
+        Err(e) => return Err(err(e))
+    };
+    let mut result = match absolute.root_path() {
+        Some(root) => root,
+        None => return Err(ReprintError::Io(format!("Couldn't resolve '{}'", path.display())))
+    };
+    let mut followed = 0us;
+    for component in absolute.components() {
+        result.push(component);
+        loop {
+            match fs::lstat(&result) {
+                Ok(ref stat) if stat.kind == std::io::FileType::Symlink => {}
+                // Doesn't exist (yet), or isn't a link; nothing to follow.
+                _ => break
+            }
+            followed += 1;
+            if followed > MAX_LINKS {
+                return Err(ReprintError::Io(format!("Couldn't resolve '{}': too many symlinks",
+                                                    path.display())));
+            }
+            let target = match fs::readlink(&result) {
+                Ok(target) => target,
+                Err(e) => return Err(err(e))
+            };
+            // A relative target is relative to the link's directory, an
+            // absolute one replaces the path so far.
+            result.pop();
+            result.push(target);
+        }
+    }
+    Ok(result)
+}
+
+// A file's contents, either read into memory or (with the `mmap` feature)
+// mapped, so that kept spans are copied straight from the file to the output.
+enum Input {
+    Owned(String),
+    #[cfg(all(feature = "mmap", unix))]
+    Mapped(std::os::MemoryMap),
+}
+
+impl Deref for Input {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match *self {
+            Input::Owned(ref s) => &s[],
+            #[cfg(all(feature = "mmap", unix))]
+            Input::Mapped(ref map) => unsafe {
+                // Checked to be UTF-8 by read_input.
+                str::from_utf8_unchecked(mapped_bytes(map))
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "mmap", unix))]
+unsafe fn mapped_bytes(map: &std::os::MemoryMap) -> &[u8] {
+    mem::transmute(std::raw::Slice { data: map.data() as *const u8, len: map.len() })
+}
+
+// Maps `path` into memory if we can, and reads it if not (e.g., because it is
+// empty, which can't be mapped). The file must not be truncated by anyone else
+// while it is mapped.
+#[cfg(all(feature = "mmap", unix))]
+fn read_input(path: &Path) -> Result<Input, ReprintError> {
+    use std::os::{MemoryMap, MapOption};
+    use std::os::unix::AsRawFd;
+
+    // The mapping outlives the file descriptor.
+    let map = match File::open(path) {
+        Ok(mut file) => match file.stat() {
+            Ok(stat) => {
+                let options = [MapOption::MapReadable, MapOption::MapFd(file.as_raw_fd())];
+                MemoryMap::new(stat.size as usize, &options).ok()
+            }
+            Err(_) => None
+        },
+        Err(_) => None
+    };
+    match map {
+        Some(map) => {
+            if str::from_utf8(unsafe { mapped_bytes(&map) }).is_err() {
+                return Err(ReprintError::Io(format!("Couldn't read '{}': not UTF-8",
+                                                    path.display())));
+            }
+            Ok(Input::Mapped(map))
+        }
+        None => read_file(path).map(Input::Owned)
+    }
+}
+
+#[cfg(not(all(feature = "mmap", unix)))]
+fn read_input(path: &Path) -> Result<Input, ReprintError> {
+    read_file(path).map(Input::Owned)
+}
+
+fn read_file(path: &Path) -> Result<String, ReprintError> {
+    let file = File::open(path);
+    let mut file = match file {
+        Ok(f) => f,
+        Err(e) => return Err(ReprintError::Io(format!("Couldn't open '{}': {}",
+                                                      path.display(),
+                                                      e.desc)))
+    };
+
+    match file.read_to_string() {
+        Ok(contents) => Ok(contents),
+        Err(e) => Err(ReprintError::Io(format!("Couldn't read '{}': {}", path.display(), e.desc)))
+    }
+}
+
+// Where process puts the output. The digest, if any, is computed as the spans
+// are pushed rather than in a separate pass over the buffer.
+struct Output {
+    buf: Vec<u8>,
+    hasher: Option<OutputHasher>,
+    stats: EditStats,
+}
+
+impl Output {
+    fn new(input: &str, changes: &[Change], hash: bool) -> Output {
+        Output::with_buf(Vec::new(), input.as_bytes().len(), changes, hash)
+    }
+
+    // Reuses `buf`, which is cleared and grown to fit the output.
+    fn with_buf(mut buf: Vec<u8>, input_len: usize, changes: &[Change], hash: bool) -> Output {
+        let changes_size = changes.iter().fold(0i64, |a, c| a + c.delta());
+        buf.clear();
+        buf.reserve((input_len as i64 + changes_size) as usize);
+        Output {
+            buf: buf,
+            hasher: if hash { Some(OutputHasher::new()) } else { None },
+            stats: EditStats::default(),
+        }
+    }
+
+    fn push_span(&mut self, kind: SpanKind, bytes: &[u8]) {
+        let len = bytes.len() as u64;
+        match kind {
+            SpanKind::Keep => self.stats.kept_bytes += len,
+            SpanKind::Insert => self.stats.inserted_bytes += len,
+            SpanKind::Remove => {
+                self.stats.removed_bytes += len;
+                return;
+            }
+        }
+        self.stats.span_count += 1;
+        self.stats.max_span = cmp::max(self.stats.max_span, len);
+
+        self.buf.push_all(bytes);
+        if let Some(ref mut hasher) = self.hasher {
+            hasher.input(bytes);
+        }
+    }
+}
+
+#[cfg(feature = "sha256")]
+struct OutputHasher(Sha256);
+
+#[cfg(feature = "sha256")]
+impl OutputHasher {
+    fn new() -> OutputHasher {
+        OutputHasher(Sha256::new())
+    }
+
+    fn input(&mut self, bytes: &[u8]) {
+        self.0.input(bytes);
+    }
+
+    fn result(mut self) -> String {
+        self.0.result_str()
+    }
+}
+
+#[cfg(not(feature = "sha256"))]
+struct OutputHasher(SipHasher);
+
+#[cfg(not(feature = "sha256"))]
+impl OutputHasher {
+    fn new() -> OutputHasher {
+        OutputHasher(SipHasher::new())
+    }
+
+    fn input(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+
+    fn result(self) -> String {
+        format!("{:016x}", self.0.finish())
+    }
+}
+
+// Changes are applied in a single pass over the input, and every change's
+// range refers to the original input, so inserted text is never subject to
+// further changes: replacing `a` with `aa` everywhere doubles each `a` once.
+//
+// precondition: changes == changes.sort() && verify(changes)
+fn process(input: &str,
+           changes: &[Change],
+           out: &mut Output,
+           progress: Option<&Fn(usize, usize)>)
+-> Result<(), ReprintError> {
+    splice(input.as_bytes(), changes, |kind, bytes| out.push_span(kind, bytes), progress)
+}
+
+#[derive(Clone, Copy, PartialEq, Show)]
+enum SpanKind {
+    Keep,
+    Remove,
+    Insert,
+}
+
+// Walks through the result of applying `changes` to `input`, passing each
+// non-empty span to `f` in order. The Keep and Insert spans make up the output.
+// If given, `progress` is called as described for ReprintOptions::progress.
+// precondition: changes == changes.sort() && verify(changes)
+fn splice<'a, F>(input: &'a [u8],
+                 changes: &'a [Change],
+                 mut f: F,
+                 progress: Option<&Fn(usize, usize)>)
+-> Result<(), ReprintError>
+    where F: FnMut(SpanKind, &'a [u8])
+{
+    let mut emit = |&mut: kind: SpanKind, bytes: &'a [u8]| {
+        if !bytes.is_empty() {
+            f(kind, bytes);
+        }
+    };
+
+    // Current position in the input.
+    let mut in_pos = 0us;
+    for (i, ch) in changes.iter().enumerate() {
+        // verify and verify_against should rule these out, but the changes
+        // might not have been through them, and we must not slice out of
+        // bounds.
+        if ch.from_end {
+            return Err(ReprintError::Process(
+                format!("Unresolved change relative to the end. {}--{}",
+                        ch.start_byte,
+                        ch.end_byte)));
+        }
+        if (ch.start_byte as usize) < in_pos {
+            return Err(ReprintError::Process(
+                format!("Change out of order or overlapping. {} < {}",
+                        ch.start_byte,
+                        in_pos)));
+        }
+        // A change may end (or start) at the very end of the input, e.g., to
+        // append to it, or to generate a file from scratch if it is empty.
+        if ch.end_byte < ch.start_byte || ch.end_byte as usize > input.len() {
+            return Err(ReprintError::Process(
+                format!("Change out of range for input. {}--{} (length {})",
+                        ch.start_byte,
+                        ch.end_byte,
+                        input.len())));
+        }
+        // A guarded change (see Change::guarded) must replace the text it
+        // expects. Checked once the range is known to be in bounds.
+        let removed = &input[ch.start_byte as usize..ch.end_byte as usize];
+        if let Some(ref expected) = ch.guard {
+            if removed != expected.as_bytes() {
+                return Err(ReprintError::GuardFailed {
+                    index: i,
+                    expected: expected.clone(),
+                    found: String::from_utf8_lossy(removed).into_owned(),
+                });
+            }
+        }
+        emit(SpanKind::Keep, &input[in_pos..ch.start_byte as usize]);
+        emit(SpanKind::Remove, removed);
+        emit(SpanKind::Insert, ch.text.as_bytes());
+        in_pos = ch.end_byte as usize;
+
+        if let Some(progress) = progress {
+            let done = i + 1;
+            if done % PROGRESS_INTERVAL == 0 || done == changes.len() {
+                progress(done, changes.len());
+            }
+        }
+    }
+
+    // Keep the rest of the input, byte for byte: nothing is added or dropped
+    // at the end, so whether the output ends with a newline is up to the
+    // input and the changes. If the last change ends at the end of the input,
+    // this is empty.
+    emit(SpanKind::Keep, &input[in_pos..]);
+    Ok(())
+}
+
+// Replaces the contents of `file` with `contents`, in the same way as reprint
+// writes an edited file: via a temp file and a backup, as set by `opts`. Only
+// the options to do with writing (and locking) are used.
+pub fn write_atomic(file: &Path,
+                    contents: &[u8],
+                    opts: ReprintOptions)
+-> Result<(), ReprintError> {
+    let _lock = match opts.lock_timeout {
+        Some(timeout) => Some(try!(lock_file(file, timeout))),
+        None => None
+    };
+    write_file(file, contents.to_vec(), &opts).map(|_| ())
+}
+
+// Undoes the last edit of `file` by moving its newest backup back over it
+// (atomically, unless the backup is compressed or on another file system),
+// which removes the backup. `opts` must name the backup as the edit did
+// (backup_dir, compress_backup, and backup_rotation); with rotation, the older
+// backups move down one, so undo can be repeated. Errors if there is no backup.
+pub fn undo(file: &Path, opts: ReprintOptions) -> Result<(), ReprintError> {
+    let input_name = match file.as_str() {
+        Some(n) => n.to_string(),
+        None => return Err(ReprintError::Io(format!("Couldn't turn path '{}' into a string",
+                                                    file.display())))
+    };
+    let (bk_name, bk_path) = try!(backup_path(file, &input_name[], &opts));
+    if !bk_path.exists() {
+        return Err(ReprintError::Io(format!("No backup '{}' to restore '{}' from",
+                                            bk_path.display(),
+                                            file.display())));
+    }
+
+    let _lock = match opts.lock_timeout {
+        Some(timeout) => Some(try!(lock_file(file, timeout))),
+        None => None
+    };
+    try!(restore_backup(&bk_path, file, opts.io_retries));
+
+    let bk_ext = if opts.compress_backup { ".gz" } else { "" };
+    // Counted in u32, since backup_rotation + 1 overflows a u8 at 255.
+    for n in 2..opts.backup_rotation as u32 + 1 {
+        let from = rotated_backup_path(&bk_name[], n, bk_ext);
+        if !from.exists() {
+            break;
+        }
+        try!(rename(&from, &rotated_backup_path(&bk_name[], n - 1, bk_ext), opts.io_retries));
+    }
+    Ok(())
+}
+
+// Writes `buf` to `input_path` as set by `opts`. Returns whether the file had
+// to be written in place because the temp file couldn't be renamed over it
+// (see ReprintSummary::wrote_in_place).
+fn write_file(input_path: &Path, buf: Vec<u8>, opts: &ReprintOptions)
+-> Result<bool, ReprintError> {
+    if opts.write_mode == WriteMode::Truncate {
+        try!(check_unmodified(input_path, opts));
+        return overwrite_file(input_path, &buf[], opts.io_retries).map(|()| false);
+    }
+
+    // Prepare file names.
+    let input_name = match input_path.as_str() {
+        Some(n) => n.to_string(),
+        None => return Err(ReprintError::Io(format!("Couldn't turn path '{}' into a string",
+                                                    input_path.display())))
+    };
+
+    let tmp_path = if opts.content_addressed_temp {
+        let hash = content_hash(&buf[]);
+        Path::new(format!("{}.{}.tmp", input_name, &hash[..16]))
+    } else {
+        Path::new(input_name.clone() + ".tmp")
+    };
+    let (bk_name, bk_path) = try!(backup_path(input_path, &input_name[], opts));
+    let bk_ext = if opts.compress_backup { ".gz" } else { "" };
+    if let Some(ref dir) = opts.backup_dir {
+        if let Err(e) = fs::mkdir_recursive(dir, std::io::USER_RWX) {
+            return Err(ReprintError::Io(format!("Couldn't create backup directory '{}': {}",
+                                                dir.display(),
+                                                e.desc)));
+        }
+    }
+
+    if opts.preserve_hardlinks {
+        try!(check_unmodified(input_path, opts));
+        if opts.backup_rotation > 0 {
+            try!(rotate_backups(&bk_name[], opts.backup_rotation, bk_ext, opts.io_retries));
+        }
+        try!(create_backup(input_path, &bk_path, opts));
+        return overwrite_file(input_path, &buf[], opts.io_retries).map(|()| false);
+    }
+
+    // Write to temporary file, unless a content addressed one is already there.
+    // One we didn't create may be about to be renamed by another process, so
+    // we never remove it.
+    let created = match create_new(&tmp_path, &buf[]) {
+        Ok(()) => true,
+        Err(ReprintError::AlreadyExists(_)) if opts.content_addressed_temp &&
+                                               has_contents(&tmp_path, &buf[]) => false,
+        Err(e) => return Err(e)
+    };
+    let result = replace_with_temp(input_path, &tmp_path, &buf[], &bk_name[], &bk_path, opts);
+    // Our temp file is also left if another process made the edit first. If it
+    // was renamed into place, this fails, or at worst removes another process's
+    // identical temp file, which it then finds the edit already made.
+    if created && (result.is_err() || tmp_path.exists()) {
+        let _ = fs::unlink(&tmp_path);
+    }
+    result
+}
+
+// The rest of write_file, once the temp file has been written: backs up the
+// input and renames the temp file over it.
+fn replace_with_temp(input_path: &Path,
+                     tmp_path: &Path,
+                     buf: &[u8],
+                     bk_name: &str,
+                     bk_path: &Path,
+                     opts: &ReprintOptions)
+-> Result<bool, ReprintError> {
+    try!(copy_xattrs(input_path, tmp_path));
+    try!(check_unmodified(input_path, opts));
+
+    if opts.backup_rotation > 0 {
+        let bk_ext = if opts.compress_backup { ".gz" } else { "" };
+        try!(rotate_backups(bk_name, opts.backup_rotation, bk_ext, opts.io_retries));
+    }
+
+    // Back up the input file. The input stays put until it is replaced by the
+    // temp file, so if anything fails it is where we found it.
+    match create_backup(input_path, bk_path, opts) {
+        Ok(()) => {}
+        // Another process making the same edit got there first.
+        Err(ReprintError::AlreadyExists(_)) if opts.content_addressed_temp &&
+                                               has_contents(input_path, buf) => return Ok(false),
+        Err(e) => return Err(e)
+    }
+
+    // Rename temp file to input file.
+    let renamed = with_retries(opts.io_retries, || fs::rename(tmp_path, input_path));
+    finish_rename(renamed, input_path, tmp_path, buf, bk_path, opts)
+}
+
+// Deals with the result of renaming `tmp_path` (holding `buf`) to `input_path`,
+// backed up to `bk_path`, returning whether the input was written in place.
+// Some file systems can't rename even within a directory, in which case we
+// fall back to writing the input in place, which is not atomic.
+fn finish_rename(renamed: std::io::IoResult<()>,
+                 input_path: &Path,
+                 tmp_path: &Path,
+                 buf: &[u8],
+                 bk_path: &Path,
+                 opts: &ReprintOptions)
+-> Result<bool, ReprintError> {
+    match renamed {
+        Ok(()) => Ok(false),
+        Err(ref e) if is_cross_device(e) => {
+            // If this fails the input may be partly written, so the backup
+            // stays.
+            try!(overwrite_file(input_path, buf, opts.io_retries));
+            try!(remove_file(tmp_path));
+            Ok(true)
+        }
+        Err(ref e) if opts.content_addressed_temp &&
+                      e.kind == IoErrorKind::FileNotFound &&
+                      has_contents(input_path, buf) => Ok(false),
+        Err(e) => {
+            // The input is untouched, so the backup of it goes too, or the
+            // next attempt would find it in the way.
+            let _ = fs::unlink(bk_path);
+            Err(ReprintError::Io(format!("Couldn't rename '{}' to '{}': {}",
+                                         tmp_path.display(),
+                                         input_path.display(),
+                                         e.desc)))
+        }
+    }
+}
+
+// Makes `bk_path` a backup of `input_path`, failing with AlreadyExists rather
+// than replacing an existing file (e.g., a backup made by a concurrent edit).
+// Unless the input is going to be written in place or the backup compressed,
+// the backup is a hard link to the input, which the temp file then replaces;
+// if the file system can't link the two (e.g., the backup directory is on
+// another file system), the input is copied.
+fn create_backup(input_path: &Path,
+                 bk_path: &Path,
+                 opts: &ReprintOptions)
+-> Result<(), ReprintError> {
+    if opts.compress_backup {
+        return compress_file(input_path, bk_path);
+    }
+    if !opts.preserve_hardlinks {
+        match fs::link(input_path, bk_path) {
+            Ok(()) => return Ok(()),
+            Err(ref e) if e.kind == IoErrorKind::PathAlreadyExists => {
+                return Err(ReprintError::AlreadyExists(bk_path.clone()));
+            }
+            Err(_) => {}
+        }
+    }
+
+    let result = with_retries(opts.io_retries, || {
+        File::open(input_path).and_then(|mut f| f.read_to_end())
+    });
+    let contents = match result {
+        Ok(contents) => contents,
+        Err(e) => return Err(ReprintError::Io(format!("Couldn't read '{}': {}",
+                                                      input_path.display(),
+                                                      e.desc)))
+    };
+    try!(create_new(bk_path, &contents[]));
+    match fs::stat(input_path).and_then(|stat| fs::chmod(bk_path, stat.perm)) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(ReprintError::Io(format!("Couldn't set the permissions of '{}': {}",
+                                               bk_path.display(),
+                                               e.desc)))
+    }
+}
+
+// Checks that the contents of `path` hash to `hash` (see content_hash), or
+// fails with InputChanged.
+fn check_hash(path: &Path, hash: &str) -> Result<(), ReprintError> {
+    let contents = match File::open(path).and_then(|mut f| f.read_to_end()) {
+        Ok(contents) => contents,
+        Err(e) => return Err(ReprintError::Io(format!("Couldn't read '{}': {}",
+                                                      path.display(),
+                                                      e.desc)))
+    };
+    if content_hash(&contents[]) != hash {
+        return Err(ReprintError::InputChanged);
+    }
+    Ok(())
+}
+
+// Whether `path` can be read and holds exactly `buf`.
+fn has_contents(path: &Path, buf: &[u8]) -> bool {
+    match File::open(path).and_then(|mut f| f.read_to_end()) {
+        Ok(contents) => &contents[] == buf,
+        Err(_) => false
+    }
+}
+
+// The contents of the newest backup of `file`, as named by `opts`, if there is
+// one which can be compared with the file: None with WriteMode::Truncate, a
+// compressed backup or a custom writer.
+fn read_plain_backup(file: &Path, opts: &ReprintOptions) -> Option<Vec<u8>> {
+    if opts.write_mode == WriteMode::Truncate || opts.compress_backup || opts.writer.is_some() {
+        return None;
+    }
+    let bk_path = match file.as_str().map(|name| backup_path(file, name, opts)) {
+        Some(Ok((_, bk_path))) => bk_path,
+        _ => return None
+    };
+    File::open(&bk_path).and_then(|mut f| f.read_to_end()).ok()
+}
+
+// Checks ReprintOptions::expected_modified.
+fn check_unmodified(path: &Path, opts: &ReprintOptions) -> Result<(), ReprintError> {
+    let modified = match opts.expected_modified {
+        Some(modified) => modified,
+        None => return Ok(())
+    };
+    let stat = match fs::stat(path) {
+        Ok(stat) => stat,
+        Err(e) => return Err(ReprintError::Io(format!("Couldn't stat '{}': {}",
+                                                      path.display(),
+                                                      e.desc)))
+    };
+    if stat.modified != modified || opts.expected_len.map_or(false, |len| stat.size != len) {
+        return Err(ReprintError::InputChanged);
+    }
+    Ok(())
+}
+
+// The name backups of `input_path` (named `input_name`) are based on, and the
+// path of the newest backup. Backups are named for the input, or if they go in
+// a separate directory, for its file name and a hash of its path (so that files
+// with the same name in different directories don't collide).
+fn backup_path(input_path: &Path,
+               input_name: &str,
+               opts: &ReprintOptions)
+-> Result<(String, Path), ReprintError> {
+    let bk_name = match opts.backup_dir {
+        Some(ref dir) => {
+            let file_name = input_path.filename_str().unwrap_or("");
+            let path_hash = content_hash(input_name.as_bytes());
+            let bk_name = dir.join(format!("{}.{}", file_name, &path_hash[..8]));
+            match bk_name.as_str() {
+                Some(n) => n.to_string(),
+                None => return Err(ReprintError::Io(
+                    format!("Couldn't turn path '{}' into a string", bk_name.display())))
+            }
+        }
+        None => input_name.to_string()
+    };
+    let bk_ext = if opts.compress_backup { ".gz" } else { "" };
+    let bk_path = if opts.backup_rotation > 0 {
+        rotated_backup_path(&bk_name[], 1, bk_ext)
+    } else {
+        Path::new(bk_name.clone() + ".bk" + bk_ext)
+    };
+    Ok((bk_name, bk_path))
+}
+
+fn rotated_backup_path(input_name: &str, n: u32, ext: &str) -> Path {
+    Path::new(format!("{}.bk.{}{}", input_name, n, ext))
+}
+
+// Makes room for a new `<name>.bk.1` by removing the oldest of `depth` backups
+// and moving the others up one.
+fn rotate_backups(input_name: &str,
+                  depth: u8,
+                  ext: &str,
+                  retries: u8)
+-> Result<(), ReprintError> {
+    let oldest = rotated_backup_path(input_name, depth as u32, ext);
+    if oldest.exists() {
+        try!(remove_file(&oldest));
+    }
+    for n in (1..depth as u32).rev() {
+        let from = rotated_backup_path(input_name, n, ext);
+        if from.exists() {
+            try!(rename(&from, &rotated_backup_path(input_name, n + 1, ext), retries));
+        }
+    }
+    Ok(())
+}
+
+// Create `path` with contents `buf`, failing with AlreadyExists if `path`
+// exists, even if another process creates it while we're writing.
+//
+// There is no exclusive open, so the contents are written to a file in a
+// fresh private directory (TempDir::new_in is race-free) and hard linked to
+// `path`; link is atomic and fails if the destination exists. The private
+// directory and its link are removed when `staging` is dropped. On file
+// systems without hard links, the staged file is renamed to `path` if it
+// doesn't exist, which can race with another process creating it.
+fn create_new(path: &Path, buf: &[u8]) -> Result<(), ReprintError> {
+    let staging = match TempDir::new_in(&path.dir_path(), "reprint") {
+        Ok(d) => d,
+        Err(e) => return Err(ReprintError::Io(format!("Couldn't create temp directory for '{}': {}",
+                                                      path.display(),
+                                                      e.desc)))
+    };
+    let staged_path = staging.path().join("out");
+
+    let mut staged_file = match File::open_mode(&staged_path,
+                                                FileMode::Open,
+                                                FileAccess::Write) {
+        Ok(f) => f,
+        Err(e) => return Err(ReprintError::Io(format!("Couldn't open '{}': {}",
+                                                      staged_path.display(),
+                                                      e.desc)))
+    };
+    // Writer::write writes all of `buf` or fails, there are no short writes.
+    match staged_file.write(buf) {
+        Ok(()) => {}
+        Err(e) => return Err(ReprintError::Io(format!("Couldn't write to '{}': {}",
+                                                      staged_path.display(),
+                                                      e.desc)))
+    }
+
+    match fs::link(&staged_path, path) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind == IoErrorKind::PathAlreadyExists => {
+            Err(ReprintError::AlreadyExists(path.clone()))
+        }
+        Err(_) if path.exists() => Err(ReprintError::AlreadyExists(path.clone())),
+        Err(_) => rename(&staged_path, path, 0)
+    }
+}
+
+// Whether `e` is from renaming across file systems (EXDEV). IoError has no
+// errno, and the global errno may have been changed since (e.g., by a retry),
+// but its detail is the OS's message for the error.
+fn is_cross_device(e: &IoError) -> bool {
+    e.kind == IoErrorKind::OtherIoError && error_detail_contains(e, "cross-device")
+}
+
+// Whether the OS's message for `e`, if it has one, contains `what`, ignoring
+// case.
+fn error_detail_contains(e: &IoError, what: &str) -> bool {
+    e.detail.as_ref().map_or(false, |detail| {
+        detail.to_ascii_lowercase().contains(what)
+    })
+}
+
+// Calls `f` until it succeeds, fails with an error which isn't transient, or
+// has been retried `retries` times, waiting longer before each retry (see
+// ReprintOptions::io_retries). `f` must be safe to repeat after a failure.
+fn with_retries<T, F>(retries: u8, mut f: F) -> std::io::IoResult<T>
+    where F: FnMut() -> std::io::IoResult<T>
+{
+    let mut delay = Duration::milliseconds(10);
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(ref e) if attempt < retries && is_transient(e) => {}
+            result => return result
+        }
+        std::io::timer::sleep(delay);
+        delay = delay * 2;
+        attempt += 1;
+    }
+}
+
+// Whether `e` might not happen again if we try again, e.g., EAGAIN or EBUSY
+// from a network file system. EBUSY has no IoErrorKind, so it is recognized by
+// its message (as for is_cross_device).
+fn is_transient(e: &IoError) -> bool {
+    match e.kind {
+        IoErrorKind::ResourceUnavailable | IoErrorKind::TimedOut => true,
+        IoErrorKind::OtherIoError => error_detail_contains(e, "busy"),
+        _ => false
+    }
+}
+
+fn remove_file(path: &Path) -> Result<(), ReprintError> {
+    match fs::unlink(path) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(ReprintError::Io(format!("Couldn't remove '{}': {}",
+                                               path.display(),
+                                               e.desc)))
+    }
+}
+
+// Replaces the contents of `path` with `buf`, keeping the file itself. Only
+// opening the file is retried: once it has been truncated and partly written,
+// trying again can't make things better.
+fn overwrite_file(path: &Path, buf: &[u8], retries: u8) -> Result<(), ReprintError> {
+    let file = with_retries(retries, || {
+        File::open_mode(path, FileMode::Truncate, FileAccess::Write)
+    });
+    match file.and_then(|mut f| f.write(buf)) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(ReprintError::Io(format!("Couldn't write to '{}': {}",
+                                               path.display(),
+                                               e.desc)))
+    }
+}
+
+fn rename(from: &Path, to: &Path, retries: u8) -> Result<(), ReprintError> {
+    match with_retries(retries, || fs::rename(from, to)) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(ReprintError::Io(format!("Couldn't rename '{}' to '{}': {}",
+                                               from.display(),
+                                               to.display(),
+                                               e.desc)))
+    }
+}
+
+// Moves a backup made by write_file back over `target`. Compressed backups are
+// decompressed on the fly, and ones on another file system copied back.
+fn restore_backup(backup: &Path, target: &Path, retries: u8) -> Result<(), ReprintError> {
+    if backup.extension_str() != Some("gz") {
+        let renamed = with_retries(retries, || fs::rename(backup, target));
+        return finish_restore(renamed, backup, target, retries);
+    }
+
+    try!(decompress_file(backup, target));
+    remove_file(backup)
+}
+
+// Finishes restore_backup once renaming `backup` over `target` has been tried.
+// A backup copied to another file system (see create_backup) can't be renamed
+// back, so it is written over `target` instead, which is not atomic, then
+// removed.
+fn finish_restore(renamed: std::io::IoResult<()>,
+                  backup: &Path,
+                  target: &Path,
+                  retries: u8)
+-> Result<(), ReprintError> {
+    match renamed {
+        Ok(()) => Ok(()),
+        Err(ref e) if is_cross_device(e) => {
+            let contents = match File::open(backup).and_then(|mut f| f.read_to_end()) {
+                Ok(contents) => contents,
+                Err(e) => return Err(ReprintError::Io(format!("Couldn't read '{}': {}",
+                                                              backup.display(),
+                                                              e.desc)))
+            };
+            // If this fails the target may be partly written, so the backup
+            // stays.
+            try!(overwrite_file(target, &contents[], retries));
+            remove_file(backup)
+        }
+        Err(e) => Err(ReprintError::Io(format!("Couldn't rename '{}' to '{}': {}",
+                                               backup.display(),
+                                               target.display(),
+                                               e.desc)))
+    }
+}
+
+#[cfg(feature = "flate2")]
+fn compress_file(from: &Path, to: &Path) -> Result<(), ReprintError> {
+    use flate2::CompressionLevel;
+    use flate2::writer::GzEncoder;
+
+    // Compressed in memory, so that the backup can be created with create_new.
+    let result = File::open(from).and_then(|mut input| {
+        let mut encoder = GzEncoder::new(Vec::new(), CompressionLevel::Default);
+        try!(std::io::util::copy(&mut input, &mut encoder));
+        encoder.finish()
+    });
+    match result {
+        Ok(compressed) => create_new(to, &compressed[]),
+        Err(e) => Err(ReprintError::Io(format!("Couldn't compress '{}' to '{}': {}",
+                                               from.display(),
+                                               to.display(),
+                                               e.desc)))
+    }
+}
+
+#[cfg(feature = "flate2")]
+fn decompress_file(from: &Path, to: &Path) -> Result<(), ReprintError> {
+    use flate2::reader::GzDecoder;
+
+    let result = File::open(from).and_then(|input| {
+        let mut decoder = try!(GzDecoder::new(input));
+        let mut output = try!(File::create(to));
+        std::io::util::copy(&mut decoder, &mut output)
+    });
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => Err(ReprintError::Io(format!("Couldn't decompress '{}' to '{}': {}",
+                                               from.display(),
+                                               to.display(),
+                                               e.desc)))
+    }
+}
+
+#[cfg(not(feature = "flate2"))]
+fn compress_file(_: &Path, _: &Path) -> Result<(), ReprintError> {
+    Err(ReprintError::Io("Compressing backups requires the `flate2` feature".to_string()))
+}
+
+#[cfg(not(feature = "flate2"))]
+fn decompress_file(_: &Path, _: &Path) -> Result<(), ReprintError> {
+    Err(ReprintError::Io("Decompressing backups requires the `flate2` feature".to_string()))
+}
+
+// An advisory lock on a file, released when dropped.
+#[cfg(all(feature = "lock", unix))]
+struct FileLock {
+    _file: File,
+}
+
+#[cfg(all(feature = "lock", unix))]
+extern {
+    fn flock(fd: libc::c_int, operation: libc::c_int) -> libc::c_int;
+}
+
+// Takes an exclusive flock on `path`, polling until `timeout` has passed.
+// Since write_file replaces the file rather than writing to it, the file we
+// lock may have been replaced by the time we get the lock; if so, we try again
+// with the new one.
+#[cfg(all(feature = "lock", unix))]
+fn lock_file(path: &Path, timeout: Duration) -> Result<FileLock, ReprintError> {
+    use std::io::timer;
+    use std::os::unix::AsRawFd;
+
+    const LOCK_EX: libc::c_int = 2;
+    const LOCK_NB: libc::c_int = 4;
+
+    let step = Duration::milliseconds(10);
+    let mut waited = Duration::zero();
+    loop {
+        let mut file = match File::open(path) {
+            Ok(f) => f,
+            Err(e) => return Err(ReprintError::Io(format!("Couldn't open '{}': {}",
+                                                          path.display(),
+                                                          e.desc)))
+        };
+        if unsafe { flock(file.as_raw_fd(), LOCK_EX | LOCK_NB) } == 0 {
+            let replaced = match (file.stat(), fs::stat(path)) {
+                (Ok(locked), Ok(current)) => {
+                    locked.unstable.device != current.unstable.device ||
+                        locked.unstable.inode != current.unstable.inode
+                }
+                _ => true
+            };
+            if !replaced {
+                return Ok(FileLock { _file: file });
+            }
+        }
+
+        // Either the file is locked, or the one we locked has been replaced.
+        // Both count against the timeout, and the stale lock is let go.
+        drop(file);
+        if waited >= timeout {
+            return Err(ReprintError::LockTimeout(path.clone()));
+        }
+        timer::sleep(step);
+        waited = waited + step;
+    }
+}
+
+#[cfg(not(all(feature = "lock", unix)))]
+struct FileLock;
+
+#[cfg(not(all(feature = "lock", unix)))]
+fn lock_file(_: &Path, _: Duration) -> Result<FileLock, ReprintError> {
+    Err(ReprintError::Io("Locking files requires the `lock` feature on Unix".to_string()))
+}
+
+// The temporary file replaces the input, so it must take on the input's
+// extended attributes (e.g., an SELinux context) or they are lost.
+#[cfg(all(feature = "xattr", target_os = "linux"))]
+fn copy_xattrs(from: &Path, to: &Path) -> Result<(), ReprintError> {
+    let (from, to) = match (from.as_str(), to.as_str()) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return Err(ReprintError::Io(format!("Couldn't turn path '{}' into a string",
+                                                 from.display())))
+    };
+
+    let names = match xattr::list(from) {
+        Ok(names) => names,
+        Err(e) => return Err(ReprintError::Io(format!("Couldn't list attributes of '{}': {}",
+                                                      from,
+                                                      e)))
+    };
+    for name in names {
+        let value = match xattr::get(from, &name) {
+            Ok(Some(value)) => value,
+            // Removed since we listed it.
+            Ok(None) => continue,
+            Err(e) => return Err(ReprintError::Io(format!("Couldn't read attribute of '{}': {}",
+                                                          from,
+                                                          e)))
+        };
+        if let Err(e) = xattr::set(to, &name, &value[]) {
+            return Err(ReprintError::Io(format!("Couldn't set attribute of '{}': {}", to, e)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(all(feature = "xattr", target_os = "linux")))]
+fn copy_xattrs(_: &Path, _: &Path) -> Result<(), ReprintError> {
+    Ok(())
+}
+
+impl PartialEq for Change {
+    fn eq(&self, other: &Change) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Change {}
+
+// Changes are ordered by (path, start_byte, end_byte, text), then by whether
+// they are relative to the end of the file and whether they are templates,
+// then by their guard (but not by their source).
+// Equal starts are common (e.g., an insertion just before a replacement), so
+// ordering on the rest of the change makes sorting deterministic and keeps Eq
+// structural. verify decides which changes with the same start are compatible.
+impl Ord for Change {
+    fn cmp(&self, other: &Change) -> std::cmp::Ordering {
+        (&self.path,
+         self.start_byte,
+         self.end_byte,
+         &self.text,
+         self.from_end,
+         self.template,
+         &self.guard).cmp(&(&other.path,
+                            other.start_byte,
+                            other.end_byte,
+                            &other.text,
+                            other.from_end,
+                            other.template,
+                            &other.guard))
+    }
+}
+
+impl PartialOrd for Change {
+    fn partial_cmp(&self, other: &Change) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Change {
+    pub fn new(path: Path, start_byte: u32, end_byte: u32, text: String) -> Change {
+        Change {
+            path: path,
+            start_byte: start_byte,
+            end_byte: end_byte,
+            text: text,
+            from_end: false,
+            template: false,
+            source: None,
+            guard: None,
+        }
+    }
+
+    // A change to the range starting `bytes_from_end_start` bytes before the
+    // end of the file and ending `bytes_from_end_end` bytes before it, e.g.,
+    // `from_end(path, 3, 0, ..)` replaces the last three bytes. The offsets are
+    // resolved once the file's length is known (see resolve_relative).
+    pub fn from_end(path: Path,
+                    bytes_from_end_start: u32,
+                    bytes_from_end_end: u32,
+                    text: String)
+    -> Change {
+        Change {
+            path: path,
+            start_byte: bytes_from_end_start,
+            end_byte: bytes_from_end_end,
+            text: text,
+            from_end: true,
+            template: false,
+            source: None,
+            guard: None,
+        }
+    }
+
+    // A change which replaces `start_byte..end_byte` with `template`, where
+    // every `{0}` in `template` stands for the text being replaced, e.g.,
+    // `template(path, 3, 8, "({0})")` wraps bytes 3..8 in parentheses. The
+    // template is expanded against the input when the change is applied.
+    pub fn template(path: Path, start_byte: u32, end_byte: u32, template: &str) -> Change {
+        Change {
+            path: path,
+            start_byte: start_byte,
+            end_byte: end_byte,
+            text: template.to_string(),
+            from_end: false,
+            template: true,
+            source: None,
+            guard: None,
+        }
+    }
+
+    // As new, but tagged with `source`, the name of whatever produced the
+    // change, so that changes from several tools can be pulled apart again (see
+    // filter_by_source). The source makes no difference to applying the change,
+    // and isn't part of its order, so the same change from two sources is a
+    // duplicate.
+    pub fn with_source(path: Path,
+                       start_byte: u32,
+                       end_byte: u32,
+                       text: String,
+                       source: String)
+    -> Change {
+        Change { source: Some(source), ..Change::new(path, start_byte, end_byte, text) }
+    }
+
+    // As new, but only applies if `start_byte..end_byte` of the input is still
+    // `expected` when the change is applied, or the edit fails with
+    // ReprintError::GuardFailed, e.g., so that a stale edit isn't made to
+    // content which has since changed.
+    pub fn guarded(path: Path,
+                   start_byte: u32,
+                   end_byte: u32,
+                   expected: String,
+                   text: String)
+    -> Change {
+        Change { guard: Some(expected), ..Change::new(path, start_byte, end_byte, text) }
+    }
+
+    // Parses a change to `path` written as `start..end="text"`, e.g.,
+    // `3..8="Goodbye"`. In the text, `\"`, `\\`, `\n`, `\r` and `\t` are
+    // escapes, and any other `"` or `\` is an error.
+    pub fn parse(path: Path, s: &str) -> Result<Change, ReprintError> {
+        let malformed = |&: why: &str| {
+            ReprintError::Verification(format!("Malformed change '{}': {}", s, why))
+        };
+
+        let (range, quoted) = match s.find('=') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => return Err(malformed("expected `=`"))
+        };
+        let (start, end) = match range.find_str("..") {
+            Some(i) => (range[..i].trim().parse::<u32>(), range[i + 2..].trim().parse::<u32>()),
+            None => return Err(malformed("expected a range, `start..end`"))
+        };
+        let (start, end) = match (start, end) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return Err(malformed("range bounds must be byte offsets"))
+        };
+
+        if quoted.len() < 2 || !quoted.starts_with("\"") || !quoted.ends_with("\"") {
+            return Err(malformed("text must be quoted"));
+        }
+        let mut text = String::new();
+        let mut chars = quoted[1..quoted.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => text.push(match chars.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    _ => return Err(malformed("bad escape in text"))
+                }),
+                '"' => return Err(malformed("unescaped `\"` in text")),
+                c => text.push(c)
+            }
+        }
+
+        Ok(Change::new(path, start, end, text))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn start_byte(&self) -> u32 {
+        self.start_byte
+    }
+
+    pub fn end_byte(&self) -> u32 {
+        self.end_byte
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text[]
+    }
+
+    // Whether start_byte and end_byte count back from the end of the file.
+    pub fn is_from_end(&self) -> bool {
+        self.from_end
+    }
+
+    // Whether text is a template to be expanded against the input.
+    pub fn is_template(&self) -> bool {
+        self.template
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_ref().map(|s| &s[])
+    }
+
+    pub fn guard(&self) -> Option<&str> {
+        self.guard.as_ref().map(|s| &s[])
+    }
+
+    // Every change is exactly one of an insertion (an empty range and some
+    // text), a deletion (a range and no text), a replacement (both), or a
+    // no-op (neither). A replacement may still replace text with the same text.
+    pub fn is_insertion(&self) -> bool {
+        self.start_byte == self.end_byte && !self.text.is_empty()
+    }
+
+    pub fn is_deletion(&self) -> bool {
+        self.start_byte != self.end_byte && self.text.is_empty()
+    }
+
+    pub fn is_replacement(&self) -> bool {
+        self.start_byte != self.end_byte && !self.text.is_empty()
+    }
+
+    pub fn is_noop(&self) -> bool {
+        self.start_byte == self.end_byte && self.text.is_empty()
+    }
+
+    // How many bytes longer the change makes the file (negative if shorter).
+    pub fn delta(&self) -> i64 {
+        self.text.as_bytes().len() as i64 -
+            (self.end_byte as i64 - self.start_byte as i64)
+    }
+}
+
+// A stable text form of `changes` (for a single file), e.g., for snapshot
+// tests: the changes in order, one per line, each as read by Change::parse,
+// e.g., `3..8="Goodbye cruel"`. Whether a change is relative to the end or a
+// template is not recorded.
+pub fn to_fixture(changes: &ChangeSet) -> String {
+    let mut sorted = changes.to_vec();
+    sorted.sort();
+    let mut result = String::new();
+    for c in sorted.iter() {
+        result.push_str(&format!("{}..{}=\"", c.start_byte, c.end_byte)[]);
+        for ch in c.text.chars() {
+            match ch {
+                '"' => result.push_str("\\\""),
+                '\\' => result.push_str("\\\\"),
+                '\n' => result.push_str("\\n"),
+                '\r' => result.push_str("\\r"),
+                '\t' => result.push_str("\\t"),
+                ch => result.push(ch)
+            }
+        }
+        result.push_str("\"\n");
+    }
+    result
+}
+
+// Reads changes to `path` written by to_fixture. Blank lines are skipped.
+pub fn from_fixture(path: &Path, fixture: &str) -> Result<ChangeSet, ReprintError> {
+    let mut changes = ChangeSet::new();
+    for line in fixture.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        changes.push(try!(Change::parse(path.clone(), line)));
+    }
+    Ok(changes)
+}
+
+// The changes in `changes` tagged with `source` (see Change::with_source), in
+// the same order.
+pub fn filter_by_source(changes: ChangeSet, source: &str) -> ChangeSet {
+    changes.into_iter().filter(|c| c.source() == Some(source)).collect()
+}
+
+// The changes in `a` which are not in `b`. Both must be sorted.
+pub fn difference(a: &ChangeSet, b: &ChangeSet) -> ChangeSet {
+    ChangeSet::from_vec(a.iter()
+                         .filter(|c| b.binary_search_by(|y| y.cmp(*c)).is_err())
+                         .map(|c| c.clone())
+                         .collect())
+}
+
+// The index pairs `(i, j)` such that `a[i]` and `b[j]` could not both be
+// applied, by the same rules as verify. A change which is in both sets is not
+// a conflict, since one copy would be removed as a duplicate. Both sets must be
+// sorted.
+pub fn conflicts(a: &ChangeSet, b: &ChangeSet) -> Vec<(usize, usize)> {
+    let mut result = vec![];
+    for (i, x) in a.iter().enumerate() {
+        for (j, y) in b.iter().enumerate() {
+            if y.path > x.path || (y.path == x.path && y.start_byte >= x.end_byte) {
+                // Every later change in b starts too late to overlap x.
+                break;
+            }
+            if x != y && changes_overlap(x, y) {
+                result.push((i, j));
+            }
+        }
+    }
+    result
+}
+
+// Combines `first` with `second`, whose offsets are into the output of
+// `first`, into one change set for the original input, so that applying the
+// result is the same as applying `first` then `second`. A change in `second`
+// which overlaps text inserted (or a point removed) by `first` can't be
+// expressed against the original input, and is an error. Insertions by both
+// at the same point are joined into one insertion, with their text in the
+// order it has after applying `first` then `second`, since sorting them (by
+// text) could reorder them, and would drop one of two with the same text. The
+// result is sorted and verified.
+pub fn compose(first: ChangeSet, second: ChangeSet) -> Result<ChangeSet, ReprintError> {
+    let first = try!(normalize(first));
+    let second = try!(normalize(second));
+    if first.iter().chain(second.iter()).any(|c| c.from_end) {
+        return Err(ReprintError::Verification(
+            "Changes relative to the end must be resolved before compose".to_string()));
+    }
+
+    let mut result = vec![];
+    let first_files = files(&first);
+    for &(path, firsts) in first_files.iter() {
+        if !second.iter().any(|c| c.path == *path) {
+            result.push_all(firsts);
+        }
+    }
+    for &(path, seconds) in files(&second).iter() {
+        let firsts = match first_files.iter().find(|&&(p, _)| p == path) {
+            Some(&(_, firsts)) => firsts,
+            None => {
+                result.push_all(seconds);
+                continue;
+            }
+        };
+        let ranges = output_ranges(firsts);
+
+        // Each change to the file against the original input, with where its
+        // text goes in the output of `first`, and whether it is from `second`.
+        let mut placed: Vec<(Change, u32, bool)> = firsts.iter().zip(ranges.iter()).map(|(f, r)| {
+            (f.clone(), r.0 as u32, false)
+        }).collect();
+        for s in seconds.iter() {
+            let mut start_delta = 0i64;
+            // An end at the output of a deletion is before the deletion.
+            let mut end_delta = 0i64;
+            for (f, &(out_start, out_end)) in firsts.iter().zip(ranges.iter()) {
+                let (out_start, out_end) = (out_start as u32, out_end as u32);
+                if out_start < s.end_byte && s.start_byte < out_end {
+                    return Err(ReprintError::Verification(
+                        format!("Change {}--{} overlaps the output of change {}--{}",
+                                s.start_byte,
+                                s.end_byte,
+                                f.start_byte,
+                                f.end_byte)));
+                }
+                if out_end <= s.start_byte {
+                    start_delta += f.delta();
+                }
+                if out_end < s.end_byte {
+                    end_delta += f.delta();
+                }
+            }
+            if s.start_byte == s.end_byte {
+                end_delta = start_delta;
+            }
+
+            let mut rebased = s.clone();
+            rebased.start_byte = (s.start_byte as i64 - start_delta) as u32;
+            rebased.end_byte = (s.end_byte as i64 - end_delta) as u32;
+            placed.push((rebased, s.start_byte, true));
+        }
+        result.push_all(&join_insertions(placed)[]);
+    }
+
+    // Only sorted and verified: once insertions are joined, nothing left is a
+    // duplicate to drop.
+    let mut result = ChangeSet::from_vec(result);
+    result.sort();
+    try!(result.verify());
+    Ok(result)
+}
+
+// The changes from compose, with the insertions at each point which come from
+// both `first` and `second` joined into one. Their text is in output order: by
+// where it goes in the output of `first`, and where `second` inserts just
+// before text inserted by `first`, the text from `second` first.
+fn join_insertions(mut placed: Vec<(Change, u32, bool)>) -> Vec<Change> {
+    // The sort is stable, so the changes from each side stay in order.
+    placed.sort_by(|a, b| {
+        (a.0.start_byte, a.0.end_byte, a.1, !a.2).cmp(&(b.0.start_byte, b.0.end_byte, b.1, !b.2))
+    });
+
+    let mut result = vec![];
+    let mut i = 0;
+    while i < placed.len() {
+        let start = placed[i].0.start_byte;
+        let mut end = i + 1;
+        if placed[i].0.end_byte == start {
+            while end < placed.len() &&
+                  placed[end].0.start_byte == start &&
+                  placed[end].0.end_byte == start {
+                end += 1;
+            }
+        }
+        let group = &placed[i..end];
+        if group.iter().any(|p| p.2) && group.iter().any(|p| !p.2) {
+            // A template insertion replaces nothing, so `{0}` is empty.
+            let text: String = group.iter().map(|p| {
+                if p.0.template { p.0.text.replace("{0}", "") } else { p.0.text.clone() }
+            }).collect();
+            result.push(Change::new(group[0].0.path.clone(), start, start, text));
+        } else {
+            result.extend(group.iter().map(|p| p.0.clone()));
+        }
+        i = end;
+    }
+    result
+}
+
+// Splits an input of `input_len` bytes into at most `chunks` contiguous
+// regions of roughly equal size, and `changes` (sorted, resolved and for a
+// single file) into the changes for each region. Region boundaries are moved
+// so that no change crosses one. The changes for a region are relative to its
+// start, so applying them to that slice of the input and concatenating the
+// results is the same as applying `changes` to the whole input.
+pub fn partition(changes: ChangeSet,
+                 input_len: u32,
+                 chunks: usize)
+-> Vec<(Range<u32>, ChangeSet)> {
+    let chunks = cmp::max(chunks, 1);
+
+    // The end of each region.
+    let mut ends = vec![];
+    let mut last = 0;
+    for k in 1..chunks {
+        let mut end = (input_len as u64 * k as u64 / chunks as u64) as u32;
+        if let Some(ch) = changes.iter().find(|c| c.start_byte < end && end < c.end_byte) {
+            end = ch.end_byte;
+        }
+        if end > last && end < input_len {
+            ends.push(end);
+            last = end;
+        }
+    }
+    ends.push(input_len);
+
+    let mut result = vec![];
+    let mut changes = changes.into_vec().into_iter().peekable();
+    let mut start = 0;
+    for (i, &end) in ends.iter().enumerate() {
+        let is_last = i + 1 == ends.len();
+        let mut region = ChangeSet::new();
+        while changes.peek().map_or(false, |c| is_last || c.end_byte <= end) {
+            let mut ch = changes.next().unwrap();
+            ch.start_byte -= start;
+            ch.end_byte -= start;
+            region.push(ch);
+        }
+        result.push((start..end, region));
+        start = end;
+    }
+    result
+}
+
+// Widens each change in `changes` (all for the file `input`) to replace whole
+// lines, adding the rest of those lines to its text, so that applying the
+// result gives the same output. Changes which end up sharing a line are merged
+// into one. The result is normalized. Lines end with `\n`; an insertion at the
+// start of a line replaces that line.
+pub fn snap_to_lines(input: &str, changes: ChangeSet) -> Result<ChangeSet, ReprintError> {
+    let changes = try!(normalize(changes));
+    let changes = try!(prepare(input, &changes[], &ReprintOptions::default())).changes;
+    let bytes = input.as_bytes();
+
+    // The whole-line range of each group of changes, and the changes.
+    let mut groups: Vec<(usize, usize, Vec<Change>)> = vec![];
+    for ch in changes.iter() {
+        let (start, end) = (ch.start_byte as usize, ch.end_byte as usize);
+        let line_start = match bytes[..start].iter().rposition(|&b| b == b'\n') {
+            Some(i) => i + 1,
+            None => 0
+        };
+        let line_end = if end > line_start && bytes[end - 1] == b'\n' {
+            end
+        } else {
+            match bytes[end..].iter().position(|&b| b == b'\n') {
+                Some(i) => end + i + 1,
+                None => bytes.len()
+            }
+        };
+        if let Some(&mut (_, ref mut group_end, ref mut group)) = groups.last_mut() {
+            if line_start < *group_end {
+                *group_end = cmp::max(*group_end, line_end);
+                group.push(ch.clone());
+                continue;
+            }
+        }
+        groups.push((line_start, line_end, vec![ch.clone()]));
+    }
+
+    let mut result = vec![];
+    for &(start, end, ref group) in groups.iter() {
+        let shifted: Vec<Change> = group.iter().map(|c| {
+            let mut c = c.clone();
+            c.start_byte -= start as u32;
+            c.end_byte -= start as u32;
+            c
+        }).collect();
+        let text = try!(splice_str(&input[start..end], &shifted[]));
+        result.push(Change::new(group[0].path.clone(), start as u32, end as u32, text));
+    }
+    normalize(ChangeSet::from_vec(result))
+}
+
+// Adds `by` to the offsets of every change, e.g., to move changes made against
+// a slice of a file (as from partition) to that slice's place in the whole
+// file. Changes relative to the end can't be moved. If any change can't be
+// moved, or would end up before the start of the file or past the largest u32
+// offset, none of them are.
+pub fn shift(changes: &mut ChangeSet, by: i64) -> Result<(), ReprintError> {
+    for ch in changes.iter() {
+        if ch.from_end {
+            return Err(ReprintError::Verification(
+                "Changes relative to the end must be resolved before shifting".to_string()));
+        }
+        for &offset in [ch.start_byte, ch.end_byte].iter() {
+            let in_range = match (offset as i64).checked_add(by) {
+                Some(shifted) => shifted >= 0 && shifted <= std::u32::MAX as i64,
+                None => false
+            };
+            if !in_range {
+                return Err(ReprintError::Verification(
+                    format!("Shifting change {}--{} by {} puts it out of range",
+                            ch.start_byte,
+                            ch.end_byte,
+                            by)));
+            }
+        }
+    }
+    for ch in changes.iter_mut() {
+        ch.start_byte = (ch.start_byte as i64 + by) as u32;
+        ch.end_byte = (ch.end_byte as i64 + by) as u32;
+    }
+    Ok(())
+}
+
+// Moves `pending`, a change to the text `applied` was applied to, so that it
+// applies to the text after `applied`, e.g., for an editor which applies
+// changes one at a time. A change after `applied` is shifted by
+// applied.delta(); one before it (including an insertion where `applied`
+// starts) is left alone. An error if they overlap, are for different files, or
+// either is relative to the end.
+pub fn rebase_change(applied: &Change, pending: &mut Change) -> Result<(), ReprintError> {
+    if applied.from_end || pending.from_end {
+        return Err(ReprintError::Verification(
+            "Changes relative to the end must be resolved before rebasing".to_string()));
+    }
+    if applied.path != pending.path {
+        return Err(ReprintError::Verification(
+            format!("Can't rebase a change to '{}' over a change to '{}'",
+                    pending.path.display(),
+                    applied.path.display())));
+    }
+
+    if pending.end_byte <= applied.start_byte {
+        return Ok(());
+    }
+    if pending.start_byte < applied.end_byte {
+        return Err(ReprintError::Verification(
+            format!("Overlapping changes: {}--{} overlaps {}--{} ",
+                    applied.start_byte,
+                    applied.end_byte,
+                    pending.start_byte,
+                    pending.end_byte)));
+    }
+    let mut moved = ChangeSet::from_vec(vec![pending.clone()]);
+    try!(shift(&mut moved, applied.delta()));
+    *pending = moved.into_vec().pop().unwrap();
+    Ok(())
+}
+
+// The changes to `path` which turn `before` into `after`. Lines are matched
+// with a Myers diff, then each run of changed lines becomes a single change,
+// trimmed of any text common to the start or end of the old and new lines.
+// The result is sorted and non-overlapping, and apply(before, result) ==
+// after.
+pub fn diff_to_changes(path: &Path, before: &str, after: &str) -> ChangeSet {
+    let (a, a_offsets) = split_lines(before);
+    let (b, _) = split_lines(after);
+
+    let mut result = ChangeSet::new();
+    let (mut i, mut j) = (0, 0);
+    let mut matches = diff_lines(&a[], &b[]);
+    // A final match at the end of both inputs picks up any trailing change.
+    matches.push((a.len(), b.len()));
+    for &(mi, mj) in matches.iter() {
+        if i < mi || j < mj {
+            let removed = &before[a_offsets[i]..a_offsets[mi]];
+            let inserted: String = b[j..mj].concat();
+
+            // Trim common text, keeping to character boundaries.
+            let mut prefix = removed.bytes()
+                                    .zip(inserted.bytes())
+                                    .take_while(|&(x, y)| x == y)
+                                    .count();
+            while !removed.is_char_boundary(prefix) || !inserted.is_char_boundary(prefix) {
+                prefix -= 1;
+            }
+            let (removed_rest, inserted_rest) = (&removed[prefix..], &inserted[prefix..]);
+            let mut suffix = removed_rest.bytes().rev()
+                                         .zip(inserted_rest.bytes().rev())
+                                         .take_while(|&(x, y)| x == y)
+                                         .count();
+            while !removed_rest.is_char_boundary(removed_rest.len() - suffix) ||
+                  !inserted_rest.is_char_boundary(inserted_rest.len() - suffix) {
+                suffix -= 1;
+            }
+
+            let start = a_offsets[i] + prefix;
+            let end = a_offsets[mi] - suffix;
+            let text = &inserted_rest[..inserted_rest.len() - suffix];
+            result.push(Change::new(path.clone(), start as u32, end as u32, text.to_string()));
+        }
+        i = mi + 1;
+        j = mj + 1;
+    }
+    result
+}
+
+// A self-check on a change set for `input`: normalizes and applies `changes`,
+// derives the minimal changes for the same edit with diff_to_changes, and
+// checks that applying those to `input` gives the same output. The derived
+// changes needn't be the same as `changes` (e.g., if `changes` replaces text
+// with the same text), only have the same effect. A failure which isn't from
+// the changes themselves is a bug in the splice or the diff.
+pub fn verify_roundtrip(input: &str, changes: &ChangeSet) -> Result<(), ReprintError> {
+    let changes = try!(normalize(ChangeSet::from_vec(changes.to_vec())));
+    let output = try!(apply(input, &changes));
+    let path = changes.first().map_or(Path::new("-"), |c| c.path.clone());
+    let derived = diff_to_changes(&path, input, &output[]);
+    let rederived = try!(apply(input, &derived));
+    if rederived != output {
+        return Err(ReprintError::Verification(
+            format!("Round trip failed: the {} changes derived from the output give a \
+                     different output", derived.len())));
+    }
+    Ok(())
+}
+
+// Splits `s` into lines, each including its `\n`, and the offset of the start
+// of each line (plus the end of `s`).
+fn split_lines(s: &str) -> (Vec<&str>, Vec<usize>) {
+    let mut lines = vec![];
+    let mut offsets = vec![0];
+    let mut start = 0;
+    while start < s.len() {
+        let end = match s[start..].find('\n') {
+            Some(i) => start + i + 1,
+            None => s.len()
+        };
+        lines.push(&s[start..end]);
+        offsets.push(end);
+        start = end;
+    }
+    (lines, offsets)
+}
+
+// The pairs `(i, j)` of lines with `a[i] == b[j]` in a longest common
+// subsequence of `a` and `b`, in order, by Myers' algorithm.
+fn diff_lines(a: &[&str], b: &[&str]) -> Vec<(usize, usize)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    // v[k + offset] is the furthest x reached on diagonal k = x - y.
+    let offset = max + 1;
+    let mut v: Vec<isize> = std::iter::repeat(0).take(2 * offset as usize + 1).collect();
+    // The part of v which step d reads, diagonals -d - 1 to d + 1, as it was at
+    // the start of each step, for finding the path back. Only keeping that
+    // much makes the trace O(D^2) rather than O((N + M)D) for D edits.
+    let mut trace = vec![];
+
+    'search: for d in 0..max + 1 {
+        trace.push(v[(offset - d - 1) as usize..(offset + d + 2) as usize].to_vec());
+        let mut k = -d;
+        while k <= d {
+            let ki = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[ki - 1] < v[ki + 1]) {
+                v[ki + 1]
+            } else {
+                v[ki - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[ki] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+            k += 2;
+        }
+    }
+
+    let mut matches = vec![];
+    let (mut x, mut y) = (n, m);
+    for d in (0..trace.len() as isize).rev() {
+        let v = &trace[d as usize];
+        let at = |&: k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) { k + 1 } else { k - 1 };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            matches.push((x as usize, y as usize));
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    matches.reverse();
+    matches
+}
+
+// The first pair of changes which stopped try_merge: `a[a_index]` and
+// `b[b_index]`, once the sets are sorted and deduplicated, with their ranges.
+#[derive(Clone, PartialEq, Show)]
+pub struct MergeConflict {
+    pub a_index: usize,
+    pub b_index: usize,
+    pub a_range: Range<u32>,
+    pub b_range: Range<u32>,
+}
+
+// Why try_merge couldn't combine two change sets.
+#[derive(Clone, PartialEq, Show)]
+pub enum TryMergeError {
+    // The first pair of changes, one from each set, which conflict.
+    Conflict(MergeConflict),
+    // One of the sets (`a` if `in_a`, otherwise `b`) isn't valid on its own,
+    // e.g., two of its changes overlap, with the error from verifying it.
+    Invalid { in_a: bool, error: ReprintError },
+}
+
+// Combines two change sets into one sorted, verified set, if each is valid on
+// its own and no change in one conflicts with a change in the other (as for
+// conflicts, so a change in both sets is kept once). Duplicates within a set
+// are removed as by normalize.
+pub fn try_merge(mut a: ChangeSet, mut b: ChangeSet) -> Result<ChangeSet, TryMergeError> {
+    a.sort();
+    a.dedup();
+    b.sort();
+    b.dedup();
+    for &(set, in_a) in [(&a, true), (&b, false)].iter() {
+        for &(_, changes) in files(set).iter() {
+            if let Err(e) = verify(changes) {
+                return Err(TryMergeError::Invalid { in_a: in_a, error: e });
+            }
+        }
+    }
+    if let Some(&(i, j)) = conflicts(&a, &b).first() {
+        return Err(TryMergeError::Conflict(MergeConflict {
+            a_index: i,
+            b_index: j,
+            a_range: a[i].start_byte..a[i].end_byte,
+            b_range: b[j].start_byte..b[j].end_byte,
+        }));
+    }
+
+    let mut merged = a.into_vec();
+    merged.push_all(&b[]);
+    merged.sort();
+    merged.dedup();
+    Ok(ChangeSet::from_vec(merged))
+}
+
+// A pair of changes from merge3 which can't both be applied.
+#[derive(Clone, PartialEq, Show)]
+pub struct Conflict {
+    pub ours_range: Range<u32>,
+    pub theirs_range: Range<u32>,
+}
+
+// Why merge3 couldn't merge two change sets.
+#[derive(Clone, PartialEq, Show)]
+pub enum MergeError {
+    // Every pair of changes, one from each side, which can't both be applied.
+    Conflicts(Vec<Conflict>),
+    // A change on one side can't be resolved against the base, e.g., it is
+    // relative to the end and out of range.
+    Invalid(ReprintError),
+}
+
+// Combines two change sets made against the same `base` text into one, if
+// none of their changes conflict (by the same rules as verify, so insertions
+// by both sides at the same point are both kept). A change made by both sides
+// is only kept once. Changes relative to the end are resolved against `base`.
+pub fn merge3(base: &str,
+              ours: &ChangeSet,
+              theirs: &ChangeSet)
+-> Result<ChangeSet, MergeError> {
+    let ours = match merge_side(base, ours) {
+        Ok(ours) => ours,
+        Err(e) => return Err(MergeError::Invalid(e))
+    };
+    let theirs = match merge_side(base, theirs) {
+        Ok(theirs) => theirs,
+        Err(e) => return Err(MergeError::Invalid(e))
+    };
+
+    let found: Vec<Conflict> = conflicts(&ours, &theirs).into_iter().map(|(i, j)| {
+        Conflict {
+            ours_range: ours[i].start_byte..ours[i].end_byte,
+            theirs_range: theirs[j].start_byte..theirs[j].end_byte,
+        }
+    }).collect();
+    if !found.is_empty() {
+        return Err(MergeError::Conflicts(found));
+    }
+
+    let mut merged = ours.into_vec();
+    merged.push_all(&theirs[]);
+    merged.sort();
+    merged.dedup();
+    Ok(ChangeSet::from_vec(merged))
+}
+
+// A sorted copy of one side of a merge, resolved against `base`.
+fn merge_side(base: &str, changes: &ChangeSet) -> Result<ChangeSet, ReprintError> {
+    resolve_relative(ChangeSet::from_vec(changes.to_vec()), base.len() as u32)
+}
+
+// The number of `changes` (sorted, verified and for a single file) which
+// overlap `range`, including insertions at points in `range`.
+pub fn count_in_range(changes: &ChangeSet, range: Range<u32>) -> usize {
+    use std::cmp::Ordering::{Greater, Less};
+
+    // Since the changes don't overlap, their ends are in order as well as their
+    // starts, so both bounds can be found by binary search.
+    let first = match changes.binary_search_by(|c| {
+        if c.end_byte > range.start || c.start_byte >= range.start { Greater } else { Less }
+    }) {
+        Ok(i) | Err(i) => i
+    };
+    let last = match changes.binary_search_by(|c| {
+        if c.start_byte >= range.end { Greater } else { Less }
+    }) {
+        Ok(i) | Err(i) => i
+    };
+    if last > first { last - first } else { 0 }
+}
+
+// Checks that each of the `required` ranges is entirely replaced by one of
+// `changes` (an empty range needs a change which includes that point, e.g.,
+// an insertion there), and if `exclusive` is set, that no change strays outside
+// the required ranges. The changes are taken to be for a single file, and must
+// be resolved.
+pub fn assert_covers(changes: &ChangeSet,
+                     required: &[Range<u32>],
+                     exclusive: bool)
+-> Result<(), ReprintError> {
+    if changes.iter().any(|c| c.from_end) {
+        return Err(ReprintError::Verification(
+            "Changes relative to the end must be resolved before checking them".to_string()));
+    }
+
+    for r in required.iter() {
+        if !changes.iter().any(|c| c.start_byte <= r.start && r.end <= c.end_byte) {
+            return Err(ReprintError::Verification(
+                format!("No change covers {}--{}", r.start, r.end)));
+        }
+    }
+
+    if exclusive {
+        // Merge the required ranges which overlap or meet, so that a change may
+        // cover several of them.
+        let mut merged: Vec<Range<u32>> = required.to_vec();
+        merged.sort_by(|a, b| (a.start, a.end).cmp(&(b.start, b.end)));
+        let mut union: Vec<Range<u32>> = vec![];
+        for r in merged.into_iter() {
+            if let Some(last) = union.last_mut() {
+                if r.start <= last.end {
+                    last.end = cmp::max(last.end, r.end);
+                    continue;
+                }
+            }
+            union.push(r);
+        }
+
+        for c in changes.iter() {
+            if !union.iter().any(|r| r.start <= c.start_byte && c.end_byte <= r.end) {
+                return Err(ReprintError::Verification(
+                    format!("Change {}--{} is outside the required ranges",
+                            c.start_byte,
+                            c.end_byte)));
+            }
+        }
+    }
+    Ok(())
+}
+
+// A pair of overlapping changes, `changes[first]` and `changes[second]`, and
+// the bytes both of them replace (empty for an insertion inside a change).
+#[derive(Clone, PartialEq, Show)]
+pub struct Overlap {
+    pub first: usize,
+    pub second: usize,
+    pub range: Range<u32>,
+}
+
+// Every pair of overlapping changes in `changes`, which must be sorted, by the
+// same rules as verify, with `first < second`. Changes relative to the end are
+// ignored.
+pub fn overlaps(changes: &ChangeSet) -> Vec<Overlap> {
+    let mut result = vec![];
+    for (i, x) in changes.iter().enumerate() {
+        if x.from_end {
+            continue;
+        }
+        for (j, y) in changes.iter().enumerate().skip(i + 1) {
+            if y.path != x.path || y.start_byte >= x.end_byte {
+                // Every later change starts too late to overlap x.
+                break;
+            }
+            if !y.from_end && changes_overlap(x, y) {
+                result.push(Overlap {
+                    first: i,
+                    second: j,
+                    range: cmp::max(x.start_byte, y.start_byte)..cmp::min(x.end_byte, y.end_byte),
+                });
+            }
+        }
+    }
+    result
+}
+
+// Whether two changes to the same file overlap. An insertion only overlaps a
+// change it is strictly inside.
+fn changes_overlap(x: &Change, y: &Change) -> bool {
+    x.path == y.path && x.start_byte < y.end_byte && y.start_byte < x.end_byte
+}
+
+// Makes a change for every non-overlapping occurrence of `find` in `input`,
+// the contents of `path`. The result is already sorted and non-overlapping.
+// An empty `find` matches nothing. Only `input` is searched, so occurrences of
+// `find` in `with`, or made by the replacements, are left alone.
+pub fn replace_all(path: &Path, input: &str, find: &str, with: &str) -> ChangeSet {
+    let mut result = ChangeSet::new();
+    if find.is_empty() {
+        return result;
+    }
+
+    let mut pos = 0;
+    while let Some(i) = input[pos..].find_str(find) {
+        let start = pos + i;
+        pos = start + find.len();
+        result.push(Change::new(path.clone(), start as u32, pos as u32, with.to_string()));
+    }
+    result
+}
+
+// Makes a change for every non-overlapping occurrence of `find` in `input`,
+// the contents of `path`, wrapping it in `prefix` and `suffix`. Occurrences are
+// found as by replace_all. Each occurrence is replaced along with its wrapping,
+// rather than by two insertions, so that where one occurrence ends and the next
+// starts, the first's suffix still comes before the second's prefix. The
+// result is sorted and non-overlapping.
+pub fn wrap_all(path: &Path, input: &str, find: &str, prefix: &str, suffix: &str) -> ChangeSet {
+    let wrapped = prefix.to_string() + find + suffix;
+    replace_all(path, input, find, &wrapped[])
+}
+
+// A change to `path` replacing the `n`th (from 1) occurrence of `old` in
+// `input` with `new`. Occurrences are counted as by replace_all, so they don't
+// overlap. An error if `old` is empty, `n` is 0, or there are fewer than `n`
+// occurrences.
+pub fn replace_nth(path: &Path,
+                   input: &str,
+                   old: &str,
+                   new: &str,
+                   n: usize)
+-> Result<Change, ReprintError> {
+    if old.is_empty() || n == 0 {
+        return Err(ReprintError::Verification(
+            format!("No occurrence {} of '{}' to replace", n, old)));
+    }
+
+    let mut pos = 0;
+    let mut count = 0;
+    while let Some(i) = input[pos..].find_str(old) {
+        let start = pos + i;
+        pos = start + old.len();
+        count += 1;
+        if count == n {
+            return Ok(Change::new(path.clone(), start as u32, pos as u32, new.to_string()));
+        }
+    }
+    Err(ReprintError::Verification(
+        format!("Only {} occurrences of '{}', not {}", count, old, n)))
+}
+
+// Edits every file under `root` whose path (relative to `root`) matches
+// `glob`, replacing each occurrence of `find` with `with` as replace_all. Each
+// file is edited on its own, as reprint_with with the default options, so a
+// failure leaves the other files alone. Returns the result for each matching
+// file which had an occurrence, or couldn't be read, in path order. Files which
+// aren't UTF-8 are skipped, as are reprint's own temp files and backups
+// (`.tmp`, `.bk`, `.bk.N`, and those gzipped).
+//
+// In `glob`, `*` matches any run of characters except `/`, `**` any run
+// including `/`, and `?` any one character except `/`. A glob without a `/`
+// is matched against file names alone, e.g., `*.rs`.
+pub fn replace_in_tree(root: &Path,
+                       glob: &str,
+                       find: &str,
+                       with: &str)
+-> Vec<(Path, Result<ReprintSummary, ReprintError>)> {
+    edit_tree(root, glob, |&: path: &Path, input: &str| replace_all(path, input, find, with))
+}
+
+// As replace_in_tree, but replacing each match of `re` as replace_regex, so
+// `replacement` can refer to capture groups. Needs the `regex` feature.
+#[cfg(feature = "regex")]
+pub fn replace_regex_in_tree(root: &Path,
+                             glob: &str,
+                             re: &regex::Regex,
+                             replacement: &str)
+-> Vec<(Path, Result<ReprintSummary, ReprintError>)> {
+    edit_tree(root, glob, |&: path: &Path, input: &str| {
+        replace_regex(path, input, re, replacement)
+    })
+}
+
+// Edits every file under `root` whose path matches `glob` (see
+// replace_in_tree) with the changes `make_changes` makes for its path and
+// contents, skipping files it makes no changes for.
+fn edit_tree<F>(root: &Path,
+                glob: &str,
+                make_changes: F)
+-> Vec<(Path, Result<ReprintSummary, ReprintError>)>
+    where F: Fn(&Path, &str) -> ChangeSet
+{
+    let mut paths: Vec<Path> = match fs::walk_dir(root) {
+        Ok(paths) => paths.filter(|p| p.is_file()).collect(),
+        Err(e) => {
+            let err = ReprintError::Io(format!("Couldn't read '{}': {}", root.display(), e.desc));
+            return vec![(root.clone(), Err(err))];
+        }
+    };
+    paths.sort();
+
+    let mut results = vec![];
+    for path in paths.into_iter() {
+        let name = if glob.contains_char('/') {
+            path.path_relative_from(root).and_then(|p| p.as_str().map(|s| s.to_string()))
+        } else {
+            path.filename_str().map(|s| s.to_string())
+        };
+        if !name.map_or(false, |name| glob_matches(glob.as_bytes(), name.as_bytes())) {
+            continue;
+        }
+        if is_temp_or_backup(&path) {
+            continue;
+        }
+
+        let input = match File::open(&path).and_then(|mut f| f.read_to_end()) {
+            Ok(bytes) => match String::from_utf8(bytes) {
+                Ok(input) => input,
+                Err(_) => continue
+            },
+            Err(e) => {
+                let err = ReprintError::Io(format!("Couldn't read '{}': {}",
+                                                   path.display(),
+                                                   e.desc));
+                results.push((path, Err(err)));
+                continue;
+            }
+        };
+        let changes = make_changes(&path, &input[]);
+        if changes.is_empty() {
+            continue;
+        }
+        let result = reprint_file(&path, &changes[], &ReprintOptions::default());
+        results.push((path, result));
+    }
+    results
+}
+
+// Whether `path` is named as one of write_file's temp files or backups.
+fn is_temp_or_backup(path: &Path) -> bool {
+    let name = match path.filename_str() {
+        Some(name) => name,
+        None => return false
+    };
+    if name.ends_with(".tmp") {
+        return true;
+    }
+    let name = if name.ends_with(".gz") { &name[..name.len() - 3] } else { name };
+    if name.ends_with(".bk") {
+        return true;
+    }
+    // `<name>.bk.N`.
+    match name.rfind('.') {
+        Some(i) => {
+            let n = &name[i + 1..];
+            !n.is_empty() && n.bytes().all(|b| b >= b'0' && b <= b'9') && name[..i].ends_with(".bk")
+        }
+        None => false
+    }
+}
+
+// Whether `name` matches `glob`, see replace_in_tree.
+fn glob_matches(glob: &[u8], name: &[u8]) -> bool {
+    if glob.is_empty() {
+        return name.is_empty();
+    }
+    if glob.starts_with(b"**") {
+        let rest = &glob[2..];
+        return (0..name.len() + 1).any(|i| glob_matches(rest, &name[i..]));
+    }
+    match glob[0] {
+        b'*' => {
+            let rest = &glob[1..];
+            let run = name.iter().take_while(|&&b| b != b'/').count();
+            (0..run + 1).any(|i| glob_matches(rest, &name[i..]))
+        }
+        b'?' => {
+            if name.is_empty() || name[0] == b'/' {
+                return false;
+            }
+            // Skip a whole (UTF-8) character.
+            let len = 1 + name[1..].iter().take_while(|&&b| b & 0xC0 == 0x80).count();
+            glob_matches(&glob[1..], &name[len..])
+        }
+        b => !name.is_empty() && name[0] == b && glob_matches(&glob[1..], &name[1..])
+    }
+}
+
+// A change to `path` replacing `range` with the contents of `src`.
+pub fn replace_with_file(path: &Path,
+                         range: Range<u32>,
+                         src: &Path)
+-> Result<Change, ReprintError> {
+    let text = try!(read_file(src));
+    Ok(Change::new(path.clone(), range.start, range.end, text))
+}
+
+// A change to `path` replacing the lines of `input` between the line with the
+// first occurrence of `begin` and the next line with `end` with `text`, e.g.,
+// for a block between `// BEGIN GENERATED` and `// END GENERATED` markers.
+// The marker lines themselves are kept, so `text` should end with a newline.
+// An error if either marker is missing, or `end` only comes before `begin`.
+pub fn replace_between(path: &Path,
+                       input: &str,
+                       begin: &str,
+                       end: &str,
+                       text: &str)
+-> Result<Change, ReprintError> {
+    if begin.is_empty() || end.is_empty() {
+        return Err(ReprintError::Verification("Empty marker".to_string()));
+    }
+    let missing = |&: marker: &str| {
+        ReprintError::Verification(format!("Marker '{}' not found", marker))
+    };
+
+    let begin_pos = match input.find_str(begin) {
+        Some(i) => i,
+        None => return Err(missing(begin))
+    };
+    // The replaced lines start after the line with `begin`.
+    let start = match input[begin_pos..].find('\n') {
+        Some(i) => begin_pos + i + 1,
+        None => return Err(missing(end))
+    };
+    let end_pos = match input[start..].find_str(end) {
+        Some(i) => start + i,
+        None if input[..begin_pos].find_str(end).is_some() => {
+            return Err(ReprintError::Verification(
+                format!("Marker '{}' comes before marker '{}'", end, begin)));
+        }
+        None => return Err(missing(end))
+    };
+    let end_line = match input[..end_pos].rfind('\n') {
+        Some(i) => i + 1,
+        None => 0
+    };
+    Ok(Change::new(path.clone(), start as u32, end_line as u32, text.to_string()))
+}
+
+// The bytes of the `index`th (from 0) document in `input`, where documents are
+// separated by lines which are just `delimiter` (e.g., `---` in YAML), for use
+// as ReprintOptions::region. The delimiter lines aren't part of any document,
+// so if `input` starts with one, document 0 is empty. None if there are fewer
+// documents.
+pub fn document_range(input: &str, delimiter: &str, index: usize) -> Option<Range<u32>> {
+    let mut doc = 0;
+    let mut doc_start = 0;
+    let mut line_start = 0;
+    while line_start < input.len() {
+        let line_end = match input[line_start..].find('\n') {
+            Some(i) => line_start + i + 1,
+            None => input.len()
+        };
+        let line = input[line_start..line_end].trim_right_matches('\n').trim_right_matches('\r');
+        if line == delimiter {
+            if doc == index {
+                return Some(doc_start as u32..line_start as u32);
+            }
+            doc += 1;
+            doc_start = line_end;
+        }
+        line_start = line_end;
+    }
+    if doc == index {
+        Some(doc_start as u32..input.len() as u32)
+    } else {
+        None
+    }
+}
+
+// An insertion of `text` just before the first occurrence of `anchor` in
+// `input`, the contents of `path`. None if `anchor` is empty or not found.
+pub fn insert_before(path: &Path, input: &str, anchor: &str, text: &str) -> Option<Change> {
+    if anchor.is_empty() {
+        return None;
+    }
+    input.find_str(anchor).map(|i| {
+        Change::new(path.clone(), i as u32, i as u32, text.to_string())
+    })
+}
+
+// As insert_before, but inserts just after the anchor.
+pub fn insert_after(path: &Path, input: &str, anchor: &str, text: &str) -> Option<Change> {
+    if anchor.is_empty() {
+        return None;
+    }
+    input.find_str(anchor).map(|i| {
+        let pos = (i + anchor.len()) as u32;
+        Change::new(path.clone(), pos, pos, text.to_string())
+    })
+}
+
+// An insertion of `text` at `at` in `input` (the contents of `path`), padded
+// with spaces so that `text` starts at column `target_col` (counting
+// characters from 0), e.g., to line up the `=`s of a block of assignments. If
+// `at` is already at or past that column, `text` is inserted as it is.
+pub fn insert_aligned(path: &Path, input: &str, at: u32, text: &str, target_col: usize) -> Change {
+    let at = cmp::min(at as usize, input.len());
+    let line_start = match input.as_bytes()[..at].iter().rposition(|&b| b == b'\n') {
+        Some(i) => i + 1,
+        None => 0
+    };
+    let col = String::from_utf8_lossy(&input.as_bytes()[line_start..at]).chars().count();
+    let mut padded: String = std::iter::repeat(' ').take(target_col.saturating_sub(col)).collect();
+    padded.push_str(text);
+    Change::new(path.clone(), at as u32, at as u32, padded)
+}
+
+// An insertion of `text` at `at` in `input` (the contents of `path`), with
+// every line of `text` after the first indented to the column of `at`, so a
+// multi-line block lines up with where it starts. If the line is only
+// whitespace up to `at`, that whitespace is used as the indent (keeping any
+// tabs), otherwise it is spaces. Empty lines are not indented.
+pub fn reindent_insert(path: &Path, input: &str, at: u32, text: &str) -> Change {
+    let bytes = input.as_bytes();
+    let at = cmp::min(at as usize, bytes.len());
+    let line_start = match bytes[..at].iter().rposition(|&b| b == b'\n') {
+        Some(i) => i + 1,
+        None => 0
+    };
+    let prefix = &bytes[line_start..at];
+    let indent: String = if prefix.iter().all(|&b| b == b' ' || b == b'\t') {
+        prefix.iter().map(|&b| b as char).collect()
+    } else {
+        std::iter::repeat(' ').take(prefix.len()).collect()
+    };
+
+    let mut result = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            result.push('\n');
+            if !line.is_empty() {
+                result.push_str(&indent[]);
+            }
+        }
+        result.push_str(line);
+    }
+    Change::new(path.clone(), at as u32, at as u32, result)
+}
+
+// Comments out every line of `input` (the contents of `path`) which overlaps
+// `range`, by inserting `line_prefix` at the start of the line. Lines end as
+// for resolve.
+pub fn comment_out(path: &Path, input: &str, range: Range<u32>, line_prefix: &str) -> ChangeSet {
+    let bytes = input.as_bytes();
+    let start = cmp::min(range.start as usize, bytes.len());
+    let end = cmp::min(range.end as usize, bytes.len());
+
+    let mut result = ChangeSet::new();
+    let mut line = line_start_of(bytes, start);
+    loop {
+        result.push(Change::new(path.clone(), line as u32, line as u32, line_prefix.to_string()));
+        match line_break(bytes, line) {
+            Some((_, next)) if next < end => line = next,
+            _ => return result
+        }
+    }
+}
+
+// Like replace_all, but for every match of `re`. `$1`, `$name`, etc. in
+// `replacement` are expanded from the match's capture groups. An empty match
+// which expands to nothing would be a no-op, so no change is made for it.
+#[cfg(feature = "regex")]
+pub fn replace_regex(path: &Path,
+                     input: &str,
+                     re: &regex::Regex,
+                     replacement: &str)
+-> ChangeSet {
+    let mut result = ChangeSet::new();
+    for caps in re.captures_iter(input) {
+        let (start, end) = match caps.pos(0) {
+            Some(p) => p,
+            None => continue
+        };
+        let text = caps.expand(replacement);
+        if start == end && text.is_empty() {
+            continue;
+        }
+        result.push(Change::new(path.clone(), start as u32, end as u32, text));
+    }
+    result
+}
+
+// Changes as Language Server Protocol text edits, whose positions are lines
+// and UTF-16 code unit offsets into a line, both from 0.
+pub mod lsp {
+    use super::{ChangeSet, line_break};
+
+    #[derive(Clone, Copy, PartialEq, Show)]
+    pub struct Position {
+        pub line: u32,
+        pub character: u32,
+    }
+
+    #[derive(Clone, Copy, PartialEq, Show)]
+    pub struct Range {
+        pub start: Position,
+        pub end: Position,
+    }
+
+    #[derive(Clone, PartialEq, Show)]
+    pub struct TextEdit {
+        pub range: Range,
+        pub new_text: String,
+    }
+
+    // Converts `changes`, which must be resolved, to edits of `input`. As in
+    // the protocol, `\n`, `\r\n` and a lone `\r` each end a line. An offset
+    // which splits a character is moved back to the start of the character.
+    pub fn to_lsp_edits(input: &str, changes: &ChangeSet) -> Vec<TextEdit> {
+        let bytes = input.as_bytes();
+        let mut line_starts = vec![0us];
+        while let Some((_, next)) = line_break(bytes, *line_starts.last().unwrap()) {
+            line_starts.push(next);
+        }
+
+        let position = |&: offset: u32| {
+            let mut offset = ::std::cmp::min(offset as usize, bytes.len());
+            while !input.is_char_boundary(offset) {
+                offset -= 1;
+            }
+            let line = match line_starts.binary_search_by(|s| s.cmp(&offset)) {
+                Ok(i) => i,
+                Err(i) => i - 1
+            };
+            let character = input[line_starts[line]..offset].chars().fold(0, |n, c| {
+                n + c.len_utf16()
+            });
+            Position { line: line as u32, character: character as u32 }
+        };
+
+        changes.iter().map(|ch| {
+            TextEdit {
+                range: Range { start: position(ch.start_byte), end: position(ch.end_byte) },
+                new_text: ch.text.clone(),
+            }
+        }).collect()
+    }
+}
+
+// Reads changes to `target` from `path`, a JSON file holding an array of
+// `{"start": _, "end": _, "text": _}` objects or, with the `toml` feature, a
+// `.toml` file with a `[[change]]` table for each. The changes are normalized
+// and checked against the length of `target`.
+pub fn load_changes(path: &Path, target: &Path) -> Result<ChangeSet, ReprintError> {
+    let contents = try!(read_file(path));
+    let changes = if path.extension_str() == Some("toml") {
+        try!(decode_toml_changes(target, &contents[]))
+    } else {
+        try!(decode_changes(target, &contents[]))
+    };
+    let changes = try!(normalize(changes));
+    try!(check_bounds(target, &changes));
+    Ok(changes)
+}
+
+// The form changes take on the command line and in files of changes.
+#[cfg(feature = "serialize")]
+#[derive(RustcEncodable, RustcDecodable)]
+struct ChangeRecord {
+    start: u32,
+    end: u32,
+    text: String,
+}
+
+// Changes to `path` from `json`, an array of `{"start": _, "end": _, "text": _}`
+// objects, in the given order. Needs the `serialize` feature.
+#[cfg(feature = "serialize")]
+pub fn decode_changes(path: &Path, json: &str) -> Result<ChangeSet, ReprintError> {
+    match rustc_serialize::json::decode::<Vec<ChangeRecord>>(json) {
+        Ok(records) => Ok(from_records(path, records)),
+        Err(e) => Err(ReprintError::Verification(format!("Malformed changes: {:?}", e)))
+    }
+}
+
+// Edits `file` with changes read from `reader` as JSON Lines, one
+// `{"start": _, "end": _, "text": _}` object per line (blank lines are
+// skipped), e.g., from a pipe. Nothing is written until `reader` is exhausted,
+// then the changes are applied as reprint_with with the default options.
+// Needs the `serialize` feature.
+#[cfg(feature = "serialize")]
+pub fn reprint_from_jsonl<R: Reader>(file: &Path,
+                                     reader: R)
+-> Result<ReprintSummary, ReprintError> {
+    let mut records = vec![];
+    for (i, line) in std::io::BufferedReader::new(reader).lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Err(ReprintError::Io(format!("Couldn't read changes: {}", e.desc)))
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match rustc_serialize::json::decode::<ChangeRecord>(line.trim()) {
+            Ok(record) => records.push(record),
+            Err(e) => return Err(ReprintError::Verification(
+                format!("Malformed change on line {}: {:?}", i + 1, e)))
+        }
+    }
+    let changes = try!(normalize(from_records(file, records)));
+    reprint_file(file, &changes[], &ReprintOptions::default())
+}
+
+#[cfg(not(feature = "serialize"))]
+pub fn reprint_from_jsonl<R: Reader>(_: &Path, _: R) -> Result<ReprintSummary, ReprintError> {
+    Err(ReprintError::Verification("Reading changes requires the `serialize` feature".to_string()))
+}
+
+#[cfg(feature = "serialize")]
+fn from_records(path: &Path, records: Vec<ChangeRecord>) -> ChangeSet {
+    ChangeSet::from_vec(records.into_iter().map(|r| {
+        Change::new(path.clone(), r.start, r.end, r.text)
+    }).collect())
+}
+
+// A line of a replay log (see ReprintOptions::replay_log): the changes made to
+// `path` at `time` (seconds since the epoch).
+#[cfg(feature = "serialize")]
+#[derive(RustcEncodable, RustcDecodable)]
+struct LogEntry {
+    path: String,
+    time: i64,
+    changes: Vec<ChangeRecord>,
+}
+
+#[cfg(feature = "serialize")]
+fn append_to_log(log: &Path, file: &Path, changes: &[Change]) -> Result<(), ReprintError> {
+    let path = match file.as_str() {
+        Some(path) => path.to_string(),
+        None => return Err(ReprintError::Io(format!("Couldn't turn path '{}' into a string",
+                                                    file.display())))
+    };
+    let entry = LogEntry {
+        path: path,
+        time: time::get_time().sec,
+        changes: changes.iter().map(|c| {
+            ChangeRecord { start: c.start_byte, end: c.end_byte, text: c.text.clone() }
+        }).collect(),
+    };
+    let line = rustc_serialize::json::encode(&entry) + "\n";
+
+    let result = File::open_mode(log, FileMode::Append, FileAccess::Write).and_then(|mut f| {
+        f.write_str(&line[])
+    });
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => Err(ReprintError::Io(format!("Couldn't write to '{}': {}",
+                                               log.display(),
+                                               e.desc)))
+    }
+}
+
+#[cfg(not(feature = "serialize"))]
+fn append_to_log(_: &Path, _: &Path, _: &[Change]) -> Result<(), ReprintError> {
+    Err(ReprintError::Io("Replay logs require the `serialize` feature".to_string()))
+}
+
+// Appends `changes` to `file`, which couldn't be made for `reason`, to the
+// rejects file `rejects` (see ReprintOptions::write_rejects).
+fn append_rejects(rejects: &Path,
+                  file: &Path,
+                  changes: &[Change],
+                  reason: &str)
+-> Result<(), ReprintError> {
+    let mut entry = format!("--- {}\n", file.display());
+    for c in changes.iter() {
+        entry.push_str(&format!("# {}\n", reason)[]);
+        entry.push_str(&to_fixture(&ChangeSet::from_vec(vec![c.clone()]))[]);
+    }
+
+    let result = File::open_mode(rejects, FileMode::Append, FileAccess::Write).and_then(|mut f| {
+        f.write_str(&entry[])
+    });
+    match result {
+        Ok(()) => Ok(()),
+        Err(e) => Err(ReprintError::Io(format!("Couldn't write to '{}': {}",
+                                               rejects.display(),
+                                               e.desc)))
+    }
+}
+
+// Re-applies every edit recorded in the replay log `log`, in order. Each file
+// keeps a single rotated backup (`<name>.bk.1`, see
+// ReprintOptions::backup_rotation), so that a file edited more than once can
+// be replayed.
+#[cfg(feature = "serialize")]
+pub fn replay(log: &Path) -> Result<(), ReprintError> {
+    let contents = try!(read_file(log));
+    for line in contents.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = match rustc_serialize::json::decode::<LogEntry>(line) {
+            Ok(entry) => entry,
+            Err(e) => return Err(ReprintError::Verification(
+                format!("Malformed replay log entry: {:?}", e)))
+        };
+        let path = Path::new(entry.path);
+        let opts = ReprintOptions::builder().backup_rotation(1).build();
+        try!(reprint_with(from_records(&path, entry.changes), opts));
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "serialize"))]
+pub fn replay(_: &Path) -> Result<(), ReprintError> {
+    Err(ReprintError::Verification("Replay logs require the `serialize` feature".to_string()))
+}
+
+// A change set together with the length and hash (as content_hash) of the
+// input it was made against, so that it can be stored and later applied only
+// to that same input (see apply_snapshot).
+pub struct EditSnapshot {
+    pub input_hash: String,
+    pub input_len: u64,
+    pub changes: ChangeSet,
+}
+
+pub fn snapshot(input: &str, changes: &ChangeSet) -> EditSnapshot {
+    EditSnapshot {
+        input_hash: content_hash(input.as_bytes()),
+        input_len: input.len() as u64,
+        changes: ChangeSet::from_vec(changes.to_vec()),
+    }
+}
+
+// Edits `file` with the snapshot's changes, as reprint_with, if its contents
+// still have the snapshot's length and hash, and fails with InputChanged
+// otherwise. Every change must be for `file`.
+pub fn apply_snapshot(file: &Path, snapshot: EditSnapshot) -> Result<ReprintSummary, ReprintError> {
+    try!(check_paths(file, &snapshot.changes));
+    let opts = ReprintOptions::builder()
+                   .expected_len(snapshot.input_len)
+                   .expected_hash(snapshot.input_hash)
+                   .build();
+    let mut summaries = try!(reprint_with(snapshot.changes, opts));
+    match summaries.pop() {
+        Some(summary) => Ok(summary),
+        None => Err(ReprintError::Verification("Snapshot has no changes".to_string()))
+    }
+}
+
+#[cfg(feature = "serialize")]
+#[derive(RustcEncodable, RustcDecodable)]
+struct SnapshotRecord {
+    input_hash: String,
+    input_len: u64,
+    changes: Vec<ChangeRecord>,
+}
+
+impl EditSnapshot {
+    // The snapshot as JSON, with the changes in the form read by
+    // decode_changes. Changes relative to the end and templates can't be
+    // written this way. Needs the `serialize` feature.
+    #[cfg(feature = "serialize")]
+    pub fn to_json(&self) -> Result<String, ReprintError> {
+        if self.changes.iter().any(|c| c.from_end || c.template) {
+            return Err(ReprintError::Verification(
+                "Changes relative to the end and templates can't be serialized".to_string()));
+        }
+        let record = SnapshotRecord {
+            input_hash: self.input_hash.clone(),
+            input_len: self.input_len,
+            changes: self.changes.iter().map(|c| {
+                ChangeRecord { start: c.start_byte, end: c.end_byte, text: c.text.clone() }
+            }).collect(),
+        };
+        Ok(rustc_serialize::json::encode(&record))
+    }
+
+    #[cfg(not(feature = "serialize"))]
+    pub fn to_json(&self) -> Result<String, ReprintError> {
+        Err(ReprintError::Verification(
+            "Writing snapshots requires the `serialize` feature".to_string()))
+    }
+
+    // Reads a snapshot written by to_json, with changes to `path`.
+    #[cfg(feature = "serialize")]
+    pub fn from_json(path: &Path, json: &str) -> Result<EditSnapshot, ReprintError> {
+        match rustc_serialize::json::decode::<SnapshotRecord>(json) {
+            Ok(record) => Ok(EditSnapshot {
+                input_hash: record.input_hash,
+                input_len: record.input_len,
+                changes: from_records(path, record.changes),
+            }),
+            Err(e) => Err(ReprintError::Verification(format!("Malformed snapshot: {:?}", e)))
+        }
+    }
+
+    #[cfg(not(feature = "serialize"))]
+    pub fn from_json(_: &Path, _: &str) -> Result<EditSnapshot, ReprintError> {
+        Err(ReprintError::Verification(
+            "Reading snapshots requires the `serialize` feature".to_string()))
+    }
+}
+
+#[cfg(all(feature = "toml", feature = "serialize"))]
+#[derive(RustcDecodable)]
+struct ChangeTables {
+    change: Vec<ChangeRecord>,
+}
+
+#[cfg(all(feature = "toml", feature = "serialize"))]
+fn decode_toml_changes(path: &Path, text: &str) -> Result<ChangeSet, ReprintError> {
+    match toml::decode_str::<ChangeTables>(text) {
+        Some(tables) => Ok(from_records(path, tables.change)),
+        None => Err(ReprintError::Verification("Malformed changes".to_string()))
+    }
+}
+
+#[cfg(not(all(feature = "toml", feature = "serialize")))]
+fn decode_toml_changes(_: &Path, _: &str) -> Result<ChangeSet, ReprintError> {
+    Err(ReprintError::Verification(
+        "Reading TOML changes requires the `toml` and `serialize` features".to_string()))
+}
+
+#[cfg(not(feature = "serialize"))]
+pub fn decode_changes(_: &Path, _: &str) -> Result<ChangeSet, ReprintError> {
+    Err(ReprintError::Verification("Reading changes requires the `serialize` feature".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ascii::AsciiExt;
+    use std::borrow::Cow;
+    use std::cell::RefCell;
+    use std::cmp;
+    use std::collections::HashMap;
+    use std::io::USER_RWX;
+    use std::io::fs::{self, PathExtensions};
+    use std::io::{File, IoError, IoErrorKind, IoResult, TempDir};
+    use std::iter::{IntoIterator, repeat};
+    use std::ops::Range;
+    use std::rc::Rc;
+    use std::thread::Thread;
+    use super::{Change, ChangeSet, DedupKey, FileWriter, Hunk, IndentStyle, Lint, MergeConflict,
+                Overlap, PROGRESS_INTERVAL, ReprintError, ReprintOptions, SpanKind, TryMergeError,
+                annotate, apply, apply_binary_patch, apply_snapshot, apply_str_cow,
+                apply_str_in_place, assert_covers, byte_to_char, changed_line_mask, char_to_byte,
+                check_utf8, compose, content_hash, count_in_range, create_new, diff_to_changes,
+                document_range, estimate_memory, expand_text, filter_by_source, finish_rename,
+                finish_restore, from_fixture, insert_aligned, is_noop, line_offset_map, lint,
+                normalize, normalize_with, overlaps, partition, preview_all, rebase_change,
+                reindent_insert, replace_all, replace_between, replace_in_tree, replace_nth,
+                reprint_binary_patch, reprint_git_patch, reprint_git_patch_with, reprint_hunks_with,
+                reprint_transaction, reprint_with, reprint_with_confirm, resolve, shift,
+                snap_to_lines, snapshot, splice, splice_str, to_fixture, try_merge, undo,
+                verify_against, verify_roundtrip, with_retries, wrap_all, write_atomic};
+
+    fn path() -> Path {
+        Path::new("test.rs")
+    }
+
+    fn change(start: u32, end: u32, text: &str) -> Change {
+        Change::new(path(), start, end, text.to_string())
+    }
+
+    fn set(changes: Vec<Change>) -> ChangeSet {
+        ChangeSet::from_vec(changes)
+    }
+
+    // A file holding `contents` in a fresh directory, which is removed when the
+    // TempDir is dropped.
+    fn temp_file(contents: &str) -> (TempDir, Path) {
+        let dir = TempDir::new("reprint-test").unwrap();
+        let file = dir.path().join("file.txt");
+        write(&file, contents);
+        (dir, file)
+    }
+
+    fn write(file: &Path, contents: &str) {
+        File::create(file).unwrap().write_str(contents).unwrap();
+    }
+
+    fn read(file: &Path) -> String {
+        File::open(file).unwrap().read_to_string().unwrap()
+    }
+
+    fn change_to(file: &Path, start: u32, end: u32, text: &str) -> Change {
+        Change::new(file.clone(), start, end, text.to_string())
+    }
+
+    #[test]
+    fn normalize_sorts_and_dedups() {
+        let changes = set(vec![change(5, 6, "b"), change(0, 1, "a"), change(5, 6, "b")]);
+        let changes = normalize(changes).unwrap();
+        assert_eq!(changes.into_vec(), vec![change(0, 1, "a"), change(5, 6, "b")]);
+    }
+
+    #[test]
+    fn normalize_rejects_overlap() {
+        let changes = set(vec![change(0, 4, "a"), change(2, 6, "b")]);
+        match normalize(changes) {
+            Err(ReprintError::Verification(_)) => {}
+            result => panic!("expected an overlap, got {:?}", result.map(|c| c.into_vec()))
+        }
+    }
+
+    #[test]
+    fn refuses_file_changed_since_offsets_were_computed() {
+        let (_dir, file) = temp_file("hello world\n");
+        let opts = ReprintOptions::builder()
+                       .expected_hash(content_hash(b"hello world\n"))
+                       .build();
+        write(&file, "goodbye world\n");
+        let result = reprint_with(set(vec![change_to(&file, 0, 5, "HELLO")]), opts);
+        assert_eq!(result.err(), Some(ReprintError::InputChanged));
+        assert_eq!(read(&file), "goodbye world\n");
+    }
+
+    #[test]
+    fn same_start_changes_sort_deterministically() {
+        let expected = vec![change(3, 3, "a"),
+                            change(3, 3, "b"),
+                            change(3, 4, "y"),
+                            change(3, 5, "x")];
+        let mut forwards = vec![change(3, 5, "x"),
+                                change(3, 3, "b"),
+                                change(3, 4, "y"),
+                                change(3, 3, "a")];
+        let mut backwards = forwards.clone();
+        backwards.reverse();
+        forwards.sort();
+        backwards.sort();
+        assert_eq!(forwards, expected);
+        assert_eq!(backwards, expected);
+    }
+
+    fn normalize_and_apply(input: &str, changes: Vec<Change>) -> Result<String, ReprintError> {
+        normalize(set(changes)).and_then(|changes| apply(input, &changes))
+    }
+
+    #[test]
+    fn replacements_meeting_at_a_boundary() {
+        let changes = vec![change(4, 8, "Y"), change(0, 4, "X")];
+        assert_eq!(normalize_and_apply("aaaabbbb", changes), Ok("XY".to_string()));
+    }
+
+    #[test]
+    fn insertion_after_a_replacement_at_its_end() {
+        let changes = vec![change(4, 4, "I"), change(0, 4, "X")];
+        assert_eq!(normalize_and_apply("aaaabbbb", changes), Ok("XIbbbb".to_string()));
+    }
+
+    #[test]
+    fn insertion_before_a_replacement_at_its_start() {
+        let changes = vec![change(4, 8, "Y"), change(4, 4, "I")];
+        assert_eq!(normalize_and_apply("aaaabbbb", changes), Ok("aaaaIY".to_string()));
+    }
+
+    #[test]
+    fn insertions_at_the_same_point() {
+        let changes = vec![change(4, 4, "J"), change(4, 4, "I")];
+        assert_eq!(normalize_and_apply("aaaabbbb", changes), Ok("aaaaIJbbbb".to_string()));
+    }
+
+    #[test]
+    fn insertion_inside_a_replacement_overlaps() {
+        let changes = vec![change(2, 6, "X"), change(4, 4, "I")];
+        assert_eq!(normalize_and_apply("aaaabbbb", changes),
+                   Err(ReprintError::ContainedChange { outer: 0, inner: 1 }));
+    }
+
+    #[test]
+    fn formatter_rewrites_inserted_text() {
+        let (_dir, file) = temp_file("let x = 1;\n");
+        let opts = ReprintOptions::builder()
+                       .formatter(Box::new(|&: s: &str| s.to_ascii_uppercase()))
+                       .build();
+        let changes = set(vec![change_to(&file, 4, 5, "renamed")]);
+        let summaries = reprint_with(changes, opts).unwrap();
+        assert_eq!(read(&file), "let RENAMED = 1;\n");
+        assert_eq!(summaries[0].stats.inserted_bytes, 7);
+    }
+
+    #[cfg(all(feature = "xattr", target_os = "linux"))]
+    #[test]
+    fn xattrs_survive_an_edit() {
+        use xattr;
+
+        let (_dir, file) = temp_file("hello\n");
+        let name = file.as_str().unwrap().to_string();
+        // Not every file system has user attributes (e.g., tmpfs on older
+        // kernels), in which case there is nothing to test.
+        if xattr::set(&name[], "user.reprint-test", b"kept").is_err() {
+            return;
+        }
+        reprint_with(set(vec![change_to(&file, 0, 5, "HELLO")]), ReprintOptions::default())
+            .unwrap();
+        assert_eq!(read(&file), "HELLO\n");
+        assert_eq!(xattr::get(&name[], "user.reprint-test").unwrap(), Some(b"kept".to_vec()));
+    }
+
+    #[test]
+    fn empty_set_borrows_the_input() {
+        let input = "unchanged";
+        match apply_str_cow(input, &ChangeSet::new()) {
+            Ok(Cow::Borrowed(s)) => assert_eq!(s, input),
+            result => panic!("expected the input, borrowed, got {:?}", result)
+        }
+    }
+
+    #[test]
+    fn real_change_owns_the_output() {
+        match apply_str_cow("unchanged", &set(vec![change(0, 2, "")])) {
+            Ok(Cow::Owned(s)) => assert_eq!(s, "changed"),
+            result => panic!("expected an owned output, got {:?}", result)
+        }
+    }
+
+    #[test]
+    fn insert_into_empty_file() {
+        let (_dir, file) = temp_file("");
+        reprint_with(set(vec![change_to(&file, 0, 0, "hello\n")]), ReprintOptions::default())
+            .unwrap();
+        assert_eq!(read(&file), "hello\n");
+    }
+
+    #[test]
+    fn replacement_in_empty_file_is_out_of_range() {
+        let (_dir, file) = temp_file("");
+        let result = reprint_with(set(vec![change_to(&file, 0, 5, "hello")]),
+                                  ReprintOptions::default());
+        match result {
+            Err(ReprintError::Verification(_)) => {}
+            result => panic!("expected an out of range change, got {:?}", result)
+        }
+        assert_eq!(read(&file), "");
+    }
+
+    #[test]
+    fn racing_creates_of_one_file_succeed_once() {
+        let dir = TempDir::new("reprint-test").unwrap();
+        for i in 0..20 {
+            let file = dir.path().join(format!("race{}.tmp", i));
+            let guards: Vec<_> = (0..2).map(|n| {
+                let file = file.clone();
+                Thread::scoped(move || create_new(&file, format!("writer {}", n).as_bytes()))
+            }).collect();
+            let results: Vec<_> = guards.into_iter().map(|g| g.join().unwrap()).collect();
+            assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+            assert!(results.iter().any(|r| *r == Err(ReprintError::AlreadyExists(file.clone()))));
+        }
+    }
+
+    #[test]
+    fn template_wraps_an_identifier_in_parentheses() {
+        let changes = set(vec![Change::template(path(), 8, 11, "({0})")]);
+        assert_eq!(apply("let x = foo;", &changes), Ok("let x = (foo);".to_string()));
+    }
+
+    #[test]
+    fn changes_inside_the_region_apply() {
+        let (_dir, file) = temp_file("header\nbody\nfooter\n");
+        let opts = ReprintOptions::builder().region(7..12).build();
+        let changes = set(vec![change_to(&file, 7, 11, "BODY")]);
+        reprint_with(changes, opts).unwrap();
+        assert_eq!(read(&file), "header\nBODY\nfooter\n");
+    }
+
+    #[test]
+    fn change_straddling_the_region_is_refused() {
+        let (_dir, file) = temp_file("header\nbody\nfooter\n");
+        let opts = ReprintOptions::builder().region(7..12).build();
+        let changes = set(vec![change_to(&file, 3, 11, "BODY")]);
+        match reprint_with(changes, opts) {
+            Err(ReprintError::Verification(_)) => {}
+            result => panic!("expected a change outside the region, got {:?}", result)
+        }
+        assert_eq!(read(&file), "header\nbody\nfooter\n");
+    }
+
+    // A small xorshift generator, so the adversarial tests are repeatable.
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self, bound: u32) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0 % bound
+        }
+    }
+
+    // Up to `count` changes to an input of `len` bytes, or None if they
+    // overlap.
+    fn random_changes(rng: &mut Rng, len: u32, count: u32) -> Option<ChangeSet> {
+        let count = rng.next(count + 1);
+        let changes = (0..count).map(|_| {
+            let start = rng.next(len + 1);
+            let end = start + rng.next(len + 1 - start);
+            let text = ["", "x", "yz"][rng.next(3) as usize];
+            change(start, end, text)
+        }).collect();
+        normalize(set(changes)).ok()
+    }
+
+    #[test]
+    fn splicing_adversarial_changes_never_panics() {
+        let input = "héllo\nwörld\n";
+        let mut rng = Rng(0x2545F491);
+        for _ in 0..10000 {
+            let count = rng.next(5);
+            let mut changes: Vec<Change> = (0..count).map(|_| {
+                // Offsets go past the end of the input, and may be inverted or
+                // split a character.
+                let start = rng.next(input.len() as u32 + 4);
+                let end = rng.next(input.len() as u32 + 4);
+                let text = if rng.next(2) == 0 { "" } else { "x" };
+                change(start, end, text)
+            }).collect();
+            if rng.next(2) == 0 {
+                changes.sort();
+            }
+            // Any error will do, as long as it isn't a panic.
+            let _ = splice_str(input, &changes[]);
+        }
+    }
+
+    #[test]
+    fn resolve_in_a_crlf_file() {
+        let input = "ab\r\ncd\r\nef";
+        assert_eq!(resolve(input, 2, 0), Some(4));
+        assert_eq!(resolve(input, 2, 1), Some(5));
+        assert_eq!(resolve(input, 3, 0), Some(8));
+        // The `\r` of a `\r\n` is the end of the line.
+        assert_eq!(resolve(input, 1, 2), Some(2));
+        assert_eq!(resolve(input, 1, 3), None);
+        assert_eq!(resolve(input, 3, 2), Some(10));
+        assert_eq!(resolve(input, 4, 0), None);
+    }
+
+    // Replaces the first byte of `file` with `text`, with `opts`.
+    fn edit_first_byte(file: &Path, text: &str, opts: ReprintOptions) {
+        reprint_with(set(vec![change_to(file, 0, 1, text)]), opts).unwrap();
+    }
+
+    fn with_suffix(file: &Path, suffix: &str) -> Path {
+        Path::new(format!("{}{}", file.as_str().unwrap(), suffix))
+    }
+
+    #[test]
+    fn rotated_backups() {
+        let (_dir, file) = temp_file("0\n");
+        for text in ["1", "2", "3"].iter() {
+            edit_first_byte(&file, *text, ReprintOptions::builder().backup_rotation(2).build());
+        }
+        assert_eq!(read(&file), "3\n");
+        assert_eq!(read(&with_suffix(&file, ".bk.1")), "2\n");
+        assert_eq!(read(&with_suffix(&file, ".bk.2")), "1\n");
+        assert!(!with_suffix(&file, ".bk.3").exists());
+        assert!(!with_suffix(&file, ".bk").exists());
+    }
+
+    // flock locks belong to an open file, so a lock held here contends with the
+    // edit just as one held by another process would.
+    #[cfg(all(feature = "lock", unix))]
+    #[test]
+    fn edit_waits_for_the_lock() {
+        use std::time::Duration;
+        use super::lock_file;
+
+        let (_dir, file) = temp_file("0\n");
+        let opts = |&:| ReprintOptions::builder().lock_timeout(Duration::milliseconds(50)).build();
+        {
+            let _lock = lock_file(&file, Duration::zero()).unwrap();
+            let result = reprint_with(set(vec![change_to(&file, 0, 1, "1")]), opts());
+            assert_eq!(result.err(), Some(ReprintError::LockTimeout(file.clone())));
+            assert_eq!(read(&file), "0\n");
+        }
+        edit_first_byte(&file, "1", opts());
+        assert_eq!(read(&file), "1\n");
+    }
+
+    #[test]
+    fn append_to_the_end_of_a_file() {
+        let (_dir, file) = temp_file("first\n");
+        reprint_with(set(vec![change_to(&file, 6, 6, "second\n")]), ReprintOptions::default())
+            .unwrap();
+        assert_eq!(read(&file), "first\nsecond\n");
+    }
+
+    #[test]
+    fn change_past_the_end_is_out_of_range() {
+        match apply("first\n", &set(vec![change(7, 7, "second\n")])) {
+            Err(ReprintError::Verification(_)) => {}
+            result => panic!("expected an out of range change, got {:?}", result)
+        }
+    }
+
+    #[test]
+    fn change_set_apply_methods() {
+        assert_eq!(set(vec![change(0, 5, "Goodbye")]).apply("Hello, world"),
+                   Ok("Goodbye, world".to_string()));
+
+        let (_dir, file) = temp_file("Hello, world");
+        let summary = set(vec![change_to(&file, 0, 5, "Goodbye")]).apply_to_file(&file).unwrap();
+        assert!(summary.written);
+        assert_eq!(read(&file), "Goodbye, world");
+    }
+
+    #[test]
+    fn no_op_edit_leaves_the_file_alone() {
+        let (_dir, file) = temp_file("Hello, world");
+        for changes in vec![vec![], vec![change_to(&file, 0, 5, "Hello")]].into_iter() {
+            let summary = set(changes).apply_to_file(&file).unwrap();
+            assert!(!summary.written);
+            assert_eq!(summary.bytes_written, 0);
+            assert_eq!(read(&file), "Hello, world");
+            assert!(!with_suffix(&file, ".bk").exists());
+        }
+    }
+
+    #[test]
+    fn annotate_marks_changes_inline() {
+        let changes = set(vec![change(0, 5, "Bye"), change(12, 12, "!")]);
+        assert_eq!(annotate("Hello, world", &changes), "«Hello→Bye», world«→!»");
+    }
+
+    #[test]
+    fn changes_on_prefixed_lines_are_skipped() {
+        let (_dir, file) = temp_file("let a = 1;\n    // let b = 2;\nlet c = 3;\n");
+        let changes = set(vec![change_to(&file, 4, 5, "x"),
+                               change_to(&file, 22, 23, "y"),
+                               change_to(&file, 33, 34, "z")]);
+        let opts = ReprintOptions::builder().skip_line_prefix("//".to_string()).build();
+        let summaries = reprint_with(changes, opts).unwrap();
+        assert_eq!(read(&file), "let x = 1;\n    // let b = 2;\nlet z = 3;\n");
+        assert_eq!(summaries[0].skipped, vec![change_to(&file, 22, 23, "y")]);
+    }
+
+    #[test]
+    fn parse_valid_changes() {
+        assert_eq!(Change::parse(path(), "3..8=\"Goodbye\""), Ok(change(3, 8, "Goodbye")));
+        assert_eq!(Change::parse(path(), r#" 0 .. 0 ="say \"hi\"\n\\""#),
+                   Ok(change(0, 0, "say \"hi\"\n\\")));
+    }
+
+    #[test]
+    fn parse_malformed_changes() {
+        let malformed = ["3..8=Goodbye", "3-8=\"x\"", "a..8=\"x\"", "3..8", r#"0..1="\q""#,
+                         r#"0..1="a"b""#];
+        for s in malformed.iter() {
+            match Change::parse(path(), *s) {
+                Err(ReprintError::Verification(_)) => {}
+                result => panic!("expected '{}' to be malformed, got {:?}", s, result)
+            }
+        }
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn load_changes_from_json() {
+        use super::load_changes;
+
+        let (dir, file) = temp_file("Hello, world!");
+        let config = dir.path().join("changes.json");
+        write(&config, r#"[{"start": 7, "end": 12, "text": "there"},
+                           {"start": 0, "end": 5, "text": "Hi"}]"#);
+        let changes = load_changes(&config, &file).unwrap();
+        assert_eq!(changes.into_vec(),
+                   vec![change_to(&file, 0, 5, "Hi"), change_to(&file, 7, 12, "there")]);
+
+        write(&config, r#"[{"start": 7, "text": "there"}]"#);
+        match load_changes(&config, &file) {
+            Err(ReprintError::Verification(_)) => {}
+            result => panic!("expected malformed changes, got {:?}", result.map(|c| c.into_vec()))
+        }
+
+        write(&config, r#"[{"start": 7, "end": 20, "text": "there"}]"#);
+        assert!(load_changes(&config, &file).is_err());
+    }
+
+    #[cfg(all(feature = "toml", feature = "serialize"))]
+    #[test]
+    fn load_changes_from_toml() {
+        use super::load_changes;
+
+        let (dir, file) = temp_file("Hello, world!");
+        let config = dir.path().join("changes.toml");
+        write(&config, "[[change]]\nstart = 0\nend = 5\ntext = \"Hi\"\n");
+        let changes = load_changes(&config, &file).unwrap();
+        assert_eq!(changes.into_vec(), vec![change_to(&file, 0, 5, "Hi")]);
+
+        write(&config, "[[change]]\nstart = 0\n");
+        assert!(load_changes(&config, &file).is_err());
+    }
+
+    #[test]
+    fn inserted_text_is_not_changed_again() {
+        let input = "a-b-a";
+        let changes = replace_all(&path(), input, "a", "aa");
+        assert_eq!(apply(input, &changes).unwrap(), "aa-b-aa");
+
+        // The second change's range is in the original input, not in the text
+        // inserted by the first.
+        let changes = set(vec![change(0, 1, "bab"), change(2, 3, "c")]);
+        assert_eq!(apply(input, &changes).unwrap(), "bab-c-a");
+
+        // Replacements which make new occurrences of `find` leave them alone.
+        let input = "aab";
+        let changes = replace_all(&path(), input, "ab", "b");
+        assert_eq!(apply(input, &changes).unwrap(), "ab");
+    }
+
+    #[test]
+    fn edits_reach_every_hard_link_when_preserving_them() {
+        let (dir, file) = temp_file("Hello\n");
+        let other = dir.path().join("other.txt");
+        fs::link(&file, &other).unwrap();
+        edit_first_byte(&file, "J", ReprintOptions::builder().preserve_hardlinks(true).build());
+        assert_eq!(read(&file), "Jello\n");
+        assert_eq!(read(&other), "Jello\n");
+
+        // Without the option, the edited file is replaced and the link is left.
+        edit_first_byte(&file, "C", ReprintOptions::default());
+        assert_eq!(read(&file), "Cello\n");
+        assert_eq!(read(&other), "Jello\n");
+    }
+
+    #[test]
+    fn apply_str_in_place_near_multibyte_characters() {
+        // 'é' is bytes 1..3, '€' is bytes 6..9.
+        let mut s = "héllo €".to_string();
+        apply_str_in_place(&mut s, &set(vec![change(1, 3, "e"), change(9, 9, "!")])).unwrap();
+        assert_eq!(s, "hello €!");
+
+        let mut s = "héllo €".to_string();
+        apply_str_in_place(&mut s, &set(vec![change(3, 3, "-"), change(6, 9, "EUR")])).unwrap();
+        assert_eq!(s, "hé-llo EUR");
+
+        for &(start, end) in [(0, 2), (2, 3), (2, 2), (7, 9), (6, 8)].iter() {
+            let mut s = "héllo €".to_string();
+            let changes = set(vec![change(4, 5, "L"), change(start, end, "x")]);
+            assert!(apply_str_in_place(&mut s, &changes).is_err());
+            assert_eq!(s, "héllo €");
+        }
+    }
+
+    #[test]
+    fn trims_only_touched_lines() {
+        let (_dir, file) = temp_file("one  \ntwo\nthree \nfour\t\n");
+        let changes = set(vec![change_to(&file, 6, 9, "TWO  "),
+                               change_to(&file, 16, 16, "x \ny\t")]);
+        let opts = ReprintOptions::builder().trim_trailing_whitespace(true).build();
+        let summaries = reprint_with(changes, opts).unwrap();
+        assert_eq!(read(&file), "one  \nTWO\nthree x\ny\nfour\t\n");
+        assert_eq!(summaries[0].trimmed_lines, 3);
+    }
+
+    #[test]
+    fn compose_then_apply_is_apply_then_apply() {
+        let input = "Hello, world!";
+        let first = set(vec![change(0, 5, "Hi"), change(7, 12, "there")]);
+        let second = set(vec![change(2, 4, " - "), change(10, 10, "?")]);
+        let composed = compose(set(first.to_vec()), set(second.to_vec())).unwrap();
+        let expected = apply(&apply(input, &first).unwrap()[], &second).unwrap();
+        assert_eq!(expected, "Hi - there!?");
+        assert_eq!(apply(input, &composed).unwrap(), expected);
+
+        // Changing text inserted by `first` is an error.
+        assert!(compose(first, set(vec![change(0, 1, "h")])).is_err());
+    }
+
+    #[test]
+    fn compose_round_trips_random_changes() {
+        let input = "abcdefghij";
+        let mut rng = Rng(0x9E3779B9);
+        let mut composed = 0us;
+        for _ in 0..2000 {
+            let first = match random_changes(&mut rng, input.len() as u32, 3) {
+                Some(changes) => changes,
+                None => continue
+            };
+            let middle = apply(input, &first).unwrap();
+            let second = match random_changes(&mut rng, middle.len() as u32, 3) {
+                Some(changes) => changes,
+                None => continue
+            };
+            let expected = apply(&middle[], &second).unwrap();
+            // Errors are expected when `second` changes text inserted by
+            // `first`.
+            if let Ok(changes) = compose(first, second) {
+                assert_eq!(apply(input, &changes).unwrap(), expected);
+                composed += 1;
+            }
+        }
+        assert!(composed > 0);
+    }
+
+    #[test]
+    fn resolve_in_a_cr_only_file() {
+        let input = "ab\rcd\ref";
+        assert_eq!(resolve(input, 1, 0), Some(0));
+        assert_eq!(resolve(input, 1, 2), Some(2));
+        assert_eq!(resolve(input, 1, 3), None);
+        assert_eq!(resolve(input, 2, 0), Some(3));
+        assert_eq!(resolve(input, 2, 1), Some(4));
+        assert_eq!(resolve(input, 3, 0), Some(6));
+        assert_eq!(resolve(input, 3, 2), Some(8));
+        assert_eq!(resolve(input, 4, 0), None);
+    }
+
+    #[test]
+    fn resolve_with_mixed_line_endings() {
+        // Each of `\r`, `\n` and `\r\n` ends one line.
+        let input = "a\rb\nc\r\nd";
+        assert_eq!(resolve(input, 2, 0), Some(2));
+        assert_eq!(resolve(input, 3, 0), Some(4));
+        assert_eq!(resolve(input, 4, 0), Some(7));
+        assert_eq!(resolve(input, 5, 0), None);
+    }
+
+    #[test]
+    fn line_offsets_in_a_cr_only_file() {
+        let input = "ab\rcd\ref";
+        // Adds a line before line 2 and joins lines 2 and 3.
+        let changes = set(vec![change(3, 3, "x\r"), change(5, 6, "")]);
+        assert_eq!(line_offset_map(input, &changes), vec![(1, 0), (2, 1), (3, 0)]);
+        assert_eq!(apply(input, &changes).unwrap(), "ab\rx\rcdef");
+    }
+
+    #[test]
+    fn applying_partitions_is_applying_the_whole() {
+        let input = "The quick brown fox jumps over the lazy dog.\n";
+        let len = input.len() as u32;
+        let mut rng = Rng(0x6C078965);
+        for _ in 0..1000 {
+            let changes = match random_changes(&mut rng, len, 8) {
+                Some(changes) => changes,
+                None => continue
+            };
+            let expected = apply(input, &changes).unwrap();
+            let chunks = rng.next(6) as usize;
+            let regions = partition(changes, len, chunks);
+            assert!(regions.len() <= cmp::max(chunks, 1));
+
+            let mut output = String::new();
+            let mut next = 0;
+            for &(ref range, ref region) in regions.iter() {
+                assert_eq!(range.start, next);
+                next = range.end;
+                output.push_str(&apply(&input[range.start as usize..range.end as usize],
+                                       region).unwrap()[]);
+            }
+            assert_eq!(next, len);
+            assert_eq!(output, expected);
+        }
+    }
+
+    #[test]
+    fn partition_moves_boundaries_out_of_changes() {
+        let changes = set(vec![change(2, 8, "x")]);
+        let regions = partition(changes, 10, 2);
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].0, 0..8);
+        assert_eq!(regions[0].1.to_vec(), vec![change(2, 8, "x")]);
+        assert_eq!(regions[1].0, 8..10);
+        assert!(regions[1].1.is_empty());
+    }
+
+    #[test]
+    fn backups_go_in_the_backup_dir() {
+        let (dir, file) = temp_file("Hello\n");
+        let backups = dir.path().join("backups").join("deep");
+        edit_first_byte(&file, "J", ReprintOptions::builder().backup_dir(backups.clone()).build());
+        assert_eq!(read(&file), "Jello\n");
+        assert!(!with_suffix(&file, ".bk").exists());
+
+        let entries = fs::readdir(&backups).unwrap();
+        assert_eq!(entries.len(), 1);
+        let name = entries[0].filename_str().unwrap();
+        assert!(name.starts_with("file.txt.") && name.ends_with(".bk"));
+        assert_eq!(read(&entries[0]), "Hello\n");
+    }
+
+    #[test]
+    fn rejects_nul_in_inserted_text() {
+        let (_dir, file) = temp_file("Hello\n");
+        let changes = set(vec![change_to(&file, 0, 1, "J"), change_to(&file, 5, 5, "\0")]);
+        match reprint_with(changes, ReprintOptions::builder().reject_nul(true).build()) {
+            Err(ReprintError::ContainsNul { index: 1 }) => {}
+            result => panic!("expected a NUL to be rejected, got {:?}", result)
+        }
+        assert_eq!(read(&file), "Hello\n");
+
+        edit_first_byte(&file, "\0", ReprintOptions::default());
+        assert_eq!(read(&file), "\0ello\n");
+    }
+
+    #[test]
+    fn reindent_a_block_inserted_at_column_4() {
+        let input = "fn f() {\n    \n}\n";
+        let ch = reindent_insert(&path(), input, 13, "a();\nb();\nc();");
+        assert_eq!(ch, change(13, 13, "a();\n    b();\n    c();"));
+        assert_eq!(apply(input, &set(vec![ch])).unwrap(),
+                   "fn f() {\n    a();\n    b();\n    c();\n}\n");
+
+        // After other text on the line, the indent is spaces; empty lines get none.
+        let input = "\tx = {}";
+        let ch = reindent_insert(&path(), input, 5, "a\n\nb");
+        assert_eq!(ch, change(5, 5, "a\n\n     b"));
+        let ch = reindent_insert(&path(), input, 1, "a\nb");
+        assert_eq!(ch, change(1, 1, "a\n\tb"));
+    }
+
+    #[test]
+    fn count_changes_in_ranges() {
+        let changes = set(vec![change(2, 4, "a"), change(6, 6, "b"), change(8, 12, "")]);
+        // Containing every change.
+        assert_eq!(count_in_range(&changes, 0..20), 3);
+        assert_eq!(count_in_range(&changes, 2..12), 3);
+        // Overlapping some.
+        assert_eq!(count_in_range(&changes, 3..7), 2);
+        assert_eq!(count_in_range(&changes, 6..7), 1);
+        assert_eq!(count_in_range(&changes, 10..11), 1);
+        // Missing them all.
+        assert_eq!(count_in_range(&changes, 0..2), 0);
+        assert_eq!(count_in_range(&changes, 4..6), 0);
+        assert_eq!(count_in_range(&changes, 12..20), 0);
+        assert_eq!(count_in_range(&ChangeSet::new(), 0..20), 0);
+    }
+
+    #[test]
+    fn cross_device_renames_fall_back_to_writing_in_place() {
+        let (dir, file) = temp_file("Hello\n");
+        let tmp = dir.path().join("file.txt.tmp");
+        let bk = with_suffix(&file, ".bk");
+        write(&tmp, "Jello\n");
+        write(&bk, "Hello\n");
+        let exdev = IoError {
+            kind: IoErrorKind::OtherIoError,
+            desc: "unknown error",
+            detail: Some("Invalid cross-device link (os error 18)".to_string()),
+        };
+        let opts = ReprintOptions::default();
+        assert_eq!(finish_rename(Err(exdev), &file, &tmp, b"Jello\n", &bk, &opts), Ok(true));
+        assert_eq!(read(&file), "Jello\n");
+        assert!(!tmp.exists());
+        assert_eq!(read(&bk), "Hello\n");
+
+        // Other failures leave the input alone, and remove its backup.
+        write(&tmp, "Cello\n");
+        let other = IoError {
+            kind: IoErrorKind::PermissionDenied,
+            desc: "permission denied",
+            detail: None,
+        };
+        assert!(finish_rename(Err(other), &file, &tmp, b"Cello\n", &bk, &opts).is_err());
+        assert_eq!(read(&file), "Jello\n");
+        assert!(!bk.exists());
+    }
+
+    #[test]
+    fn cross_device_restores_fall_back_to_writing_in_place() {
+        let (_dir, file) = temp_file("Jello\n");
+        let bk = with_suffix(&file, ".bk");
+        write(&bk, "Hello\n");
+
+        // Other failures leave the edit and the backup alone.
+        let other = IoError {
+            kind: IoErrorKind::PermissionDenied,
+            desc: "permission denied",
+            detail: None,
+        };
+        assert!(finish_restore(Err(other), &bk, &file, 0).is_err());
+        assert_eq!(read(&file), "Jello\n");
+        assert_eq!(read(&bk), "Hello\n");
+
+        let exdev = IoError {
+            kind: IoErrorKind::OtherIoError,
+            desc: "unknown error",
+            detail: Some("Invalid cross-device link (os error 18)".to_string()),
+        };
+        assert_eq!(finish_restore(Err(exdev), &bk, &file, 0), Ok(()));
+        assert_eq!(read(&file), "Hello\n");
+        assert!(!bk.exists());
+    }
+
+    #[test]
+    fn write_atomic_backs_up_and_rolls_back() {
+        let (dir, file) = temp_file("Hello\n");
+        let bk = with_suffix(&file, ".bk");
+        write_atomic(&file, b"Goodbye\n", ReprintOptions::default()).unwrap();
+        assert_eq!(read(&file), "Goodbye\n");
+        assert_eq!(read(&bk), "Hello\n");
+
+        // The old backup is in the way, so nothing is written, and the temp
+        // file goes.
+        match write_atomic(&file, b"Again\n", ReprintOptions::default()) {
+            Err(ReprintError::AlreadyExists(_)) => {}
+            result => panic!("expected the backup to be in the way, got {:?}", result)
+        }
+        assert_eq!(read(&file), "Goodbye\n");
+        assert_eq!(read(&bk), "Hello\n");
+        assert_eq!(fs::readdir(dir.path()).unwrap().len(), 2);
+
+        undo(&file, ReprintOptions::default()).unwrap();
+        assert_eq!(read(&file), "Hello\n");
+        assert!(!bk.exists());
+    }
+
+    #[test]
+    fn diff_to_changes_round_trips() {
+        let pairs = [("", ""), ("", "a\n"), ("a\n", ""), ("a\nb\nc\n", "a\nb\nc\n"),
+                     ("a\nb\nc\n", "a\nB\nc\n"), ("a\nb\nc", "a\nb\nc\nd"), ("x\ny\n", "y\nx\n"),
+                     ("héllo\nwörld\n", "hello\nwörld!\n"), ("a\r\nb\r\n", "a\r\nc\r\nb\r\n")];
+        for &(before, after) in pairs.iter() {
+            let changes = diff_to_changes(&path(), before, after);
+            assert_eq!(apply(before, &changes).unwrap(), after);
+        }
+
+        assert!(diff_to_changes(&path(), "a\nb\n", "a\nb\n").is_empty());
+        // Only the differing text is replaced.
+        assert_eq!(diff_to_changes(&path(), "one\ntwo\nthree\n", "one\ntoo\nthree\n").into_vec(),
+                   vec![change(5, 6, "o")]);
+    }
+
+    #[test]
+    fn diff_to_changes_round_trips_random_edits() {
+        let before = "fn main() {\n    println!(\"hi\");\n}\n";
+        let mut rng = Rng(0x1B873593);
+        for _ in 0..1000 {
+            let changes = match random_changes(&mut rng, before.len() as u32, 4) {
+                Some(changes) => changes,
+                None => continue
+            };
+            let after = apply(before, &changes).unwrap();
+            assert_eq!(apply(before, &diff_to_changes(&path(), before, &after[])).unwrap(), after);
+        }
+    }
+
+    #[test]
+    fn newline_before_appending() {
+        let cases = [("last", "more\n", true, "last\nmore\n"),
+                     ("last", "more\n", false, "lastmore\n"),
+                     ("last\n", "more\n", true, "last\nmore\n"),
+                     ("last", "\nmore\n", true, "last\nmore\n"),
+                     ("", "more\n", true, "more\n")];
+        for &(input, text, ensure, expected) in cases.iter() {
+            let (_dir, file) = temp_file(input);
+            let len = input.len() as u32;
+            let opts = ReprintOptions::builder().ensure_newline_before_append(ensure).build();
+            reprint_with(set(vec![change_to(&file, len, len, text)]), opts).unwrap();
+            assert_eq!(read(&file), expected);
+        }
+    }
+
+    #[test]
+    fn noop_change_sets() {
+        let input = "Hello, world!";
+        assert!(is_noop(input, &ChangeSet::new()));
+        let same = set(vec![change(0, 5, "Hello"), change(7, 7, ""), change(13, 13, "")]);
+        assert!(is_noop(input, &same));
+        assert!(!is_noop(input, &set(vec![change(0, 5, "Hello"), change(7, 12, "there")])));
+        assert!(!is_noop(input, &set(vec![change(13, 13, "!")])));
+        // Changes which can't be applied aren't a no-op.
+        assert!(!is_noop(input, &set(vec![change(10, 20, "")])));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn canonicalized_paths_share_backups() {
+        use std::io::FileType;
+        use super::canonicalize;
+
+        let (dir, file) = temp_file("Hello\n");
+        let first = dir.path().join("first.txt");
+        let second = dir.path().join("second.txt");
+        fs::symlink(&file, &first).unwrap();
+        fs::symlink(&file, &second).unwrap();
+
+        let opts = |&:| ReprintOptions::builder().canonicalize(true).build();
+        let summaries = reprint_with(set(vec![change_to(&first, 0, 1, "J")]), opts()).unwrap();
+        assert_eq!(summaries[0].path, canonicalize(&file).unwrap());
+        assert_eq!(read(&file), "Jello\n");
+        assert_eq!(read(&with_suffix(&file, ".bk")), "Hello\n");
+        assert_eq!(fs::lstat(&first).unwrap().kind, FileType::Symlink);
+
+        // The second path names the same backup, which is in the way.
+        match reprint_with(set(vec![change_to(&second, 0, 1, "C")]), opts()) {
+            Err(ReprintError::AlreadyExists(ref path)) => {
+                assert_eq!(*path, with_suffix(&canonicalize(&file).unwrap(), ".bk"));
+            }
+            result => panic!("expected the backup to be in the way, got {:?}", result)
+        }
+        assert_eq!(read(&file), "Jello\n");
+        assert!(!with_suffix(&second, ".bk").exists());
+    }
+
+    #[test]
+    fn progress_counts_up_to_the_total() {
+        let total = 2 * PROGRESS_INTERVAL + 500;
+        let (_dir, file) = temp_file(&repeat("a").take(total).collect::<String>()[]);
+        let changes = (0..total as u32).map(|i| change_to(&file, i, i + 1, "b")).collect();
+
+        let calls = Rc::new(RefCell::new(vec![]));
+        let recorded = calls.clone();
+        let opts = ReprintOptions::builder().progress(Box::new(move |&: done: usize, total: usize| {
+            recorded.borrow_mut().push((done, total));
+        })).build();
+        reprint_with(set(changes), opts).unwrap();
+
+        let calls = calls.borrow();
+        assert_eq!(*calls, vec![(PROGRESS_INTERVAL, total),
+                                (2 * PROGRESS_INTERVAL, total),
+                                (total, total)]);
+    }
+
+    #[test]
+    fn changes_with_the_same_start() {
+        // Insertions and one replacement may share a start; the insertions come
+        // first, in order of their text.
+        let changes = vec![change(3, 3, "b"), change(3, 6, "x"), change(3, 3, "a")];
+        let changes = normalize(set(changes)).unwrap();
+        assert_eq!(changes.to_vec(), vec![change(3, 3, "a"), change(3, 3, "b"), change(3, 6, "x")]);
+        assert_eq!(apply("012345678", &changes).unwrap(), "012abx678");
+        assert!(normalize(set(vec![change(3, 6, "x"), change(3, 6, "x")])).is_ok());
+
+        // Two replacements conflict, whether or not their ranges are the same.
+        let conflicts = [vec![change(3, 6, "x"), change(3, 5, "y")],
+                         vec![change(3, 6, "x"), change(3, 6, "y")],
+                         vec![change(3, 3, "a"), change(3, 4, "x"), change(3, 6, "y")]];
+        for changes in conflicts.iter() {
+            match normalize(set(changes.clone())) {
+                Err(ReprintError::SameStartConflict { start: 3 }) => {}
+                result => panic!("expected a conflict at 3, got {:?}", result.map(|c| c.into_vec()))
+            }
+        }
+    }
+
+    #[test]
+    fn git_patches_apply() {
+        use std::io::process::Command;
+
+        let cases = [("one\ntwo\nthree\n", 4, 7, "TWO"),
+                     ("one\ntwo\nthree", 8, 13, "THREE"),
+                     ("one\ntwo\nthree", 13, 13, "\nfour\n"),
+                     ("one\ntwo\nthree\n", 0, 4, "")];
+        for &(input, start, end, text) in cases.iter() {
+            let (dir, file) = temp_file(input);
+            let patch = reprint_git_patch(&file, set(vec![change_to(&file, start, end, text)]))
+                .unwrap();
+            assert!(!patch.is_empty());
+            // The patch names the file by the path it was given, which is
+            // absolute; git wants it relative to where it is applied.
+            let patch = patch.replace(file.as_str().unwrap(), "file.txt");
+            write(&dir.path().join("edit.patch"), &patch[]);
+
+            let result = Command::new("git").arg("apply").arg("--check").arg("edit.patch")
+                                            .cwd(dir.path())
+                                            .output();
+            let output = match result {
+                Ok(output) => output,
+                // No git to check with.
+                Err(_) => return
+            };
+            assert!(output.status.success(),
+                    "git apply rejected:\n{}\n{}",
+                    patch,
+                    String::from_utf8_lossy(&output.error[]));
+
+            let output = Command::new("git").arg("apply").arg("edit.patch")
+                                            .cwd(dir.path())
+                                            .output()
+                                            .unwrap();
+            assert!(output.status.success());
+            let expected = apply(input, &set(vec![change(start, end, text)])).unwrap();
+            assert_eq!(read(&file), expected);
+        }
+    }
+
+    #[test]
+    fn collect_and_iterate_change_sets() {
+        // Collecting keeps the order, without sorting.
+        let mut changes: ChangeSet = [9u32, 2, 5, 4].iter()
+                                                  .filter(|&&i| i % 2 == 1)
+                                                  .map(|&i| change(i, i + 1, "x"))
+                                                  .collect();
+        assert_eq!(changes.to_vec(), vec![change(9, 10, "x"), change(5, 6, "x")]);
+
+        changes.extend(vec![change(0, 0, "y")].into_iter());
+        let mut starts = vec![];
+        for ch in &changes {
+            starts.push(ch.start_byte);
+        }
+        assert_eq!(starts, vec![9, 5, 0]);
+
+        let texts: Vec<String> = changes.into_iter().map(|c| c.text).collect();
+        assert_eq!(texts, vec!["x", "x", "y"]);
+    }
+
+    #[test]
+    fn filter_changes_by_source() {
+        let tagged = |&: start: u32, text: &str, source: &str| {
+            Change::with_source(path(), start, start + 1, text.to_string(), source.to_string())
+        };
+        let changes = set(vec![tagged(0, "a", "fmt"), change(1, 2, "b"), tagged(2, "c", "lint"),
+                               tagged(3, "d", "fmt")]);
+        let fmt = filter_by_source(set(changes.to_vec()), "fmt");
+        assert_eq!(fmt.to_vec(), vec![tagged(0, "a", "fmt"), tagged(3, "d", "fmt")]);
+        assert_eq!(fmt[0].source(), Some("fmt"));
+        assert_eq!(filter_by_source(set(changes.to_vec()), "lint").len(), 1);
+        assert!(filter_by_source(changes, "other").is_empty());
+
+        // The source doesn't change what a change does.
+        assert_eq!(apply("0123", &set(vec![tagged(1, "x", "fmt")])).unwrap(), "0x23");
+    }
+
+    #[test]
+    fn huge_deltas_are_an_error() {
+        use std::{i64, u32, u64};
+
+        let insert = [change(0, 0, "x")];
+        let max = i64::MAX as u64;
+        assert_eq!(verify_against(max, insert.iter()), Err(ReprintError::SizeOverflow));
+        assert_eq!(verify_against(max + 1, [].iter()), Err(ReprintError::SizeOverflow));
+        assert_eq!(verify_against(u64::MAX, insert.iter()), Err(ReprintError::SizeOverflow));
+        let len = u32::MAX as u64;
+        assert_eq!(verify_against(len, insert.iter()), Err(ReprintError::OutputTooLarge(len + 1)));
+        assert_eq!(verify_against(len, [change(0, 1, "x")].iter()), Ok(()));
+    }
+
+    #[test]
+    fn reindent_tabs_as_four_spaces() {
+        let (_dir, file) = temp_file("fn f() {\n}\n");
+        let changes = set(vec![change_to(&file, 9, 9, "\tlet x = 1;\n\t\tg(\"a\tb\");\n  \th();\n"),
+                               change_to(&file, 8, 8, "\t// f")]);
+        let opts = ReprintOptions::builder().indent_style(IndentStyle::Spaces(4)).build();
+        let summaries = reprint_with(changes, opts).unwrap();
+        // Tabs after the indent, and in a change which doesn't start a line, stay.
+        assert_eq!(read(&file),
+                   "fn f() {\t// f\n    let x = 1;\n        g(\"a\tb\");\n    h();\n}\n");
+        assert_eq!(summaries[0].reindented_lines, 3);
+    }
+
+    #[test]
+    fn reindent_spaces_as_tabs() {
+        let (_dir, file) = temp_file("");
+        let changes = set(vec![change_to(&file, 0, 0, "      x\n    y\n  z\n")]);
+        let opts = ReprintOptions::builder().indent_style(IndentStyle::Tabs(4)).build();
+        let summaries = reprint_with(changes, opts).unwrap();
+        assert_eq!(read(&file), "\t  x\n\ty\n  z\n");
+        assert_eq!(summaries[0].reindented_lines, 2);
+    }
+
+    #[test]
+    fn lint_long_touched_lines() {
+        let input = "short\nalso short\nan untouched line which is too long\n";
+        let opts = ReprintOptions::builder().max_line_length(12).build();
+        // Line 1 goes over the limit, line 2 stays under it.
+        let changes = set(vec![change(5, 5, " and long"), change(11, 16, "SHORT")]);
+        assert_eq!(lint(input, &changes, &opts), vec![Lint::LineTooLong { line: 1, len: 14 }]);
+        // Exactly at the limit is fine.
+        let changes = set(vec![change(5, 5, " and lo")]);
+        assert_eq!(lint(input, &changes, &opts), vec![]);
+    }
+
+    #[test]
+    fn strict_line_length_fails_the_edit() {
+        let (_dir, file) = temp_file("short\n");
+        let opts = ReprintOptions::builder().max_line_length(12).strict(true).build();
+        match reprint_with(set(vec![change_to(&file, 5, 5, " and long")]), opts) {
+            Err(ReprintError::LineTooLong { line: 1, len: 14 }) => {}
+            result => panic!("expected a long line, got {:?}", result)
+        }
+        assert_eq!(read(&file), "short\n");
+    }
+
+    #[test]
+    fn normalize_in_place_reports_what_it_did() {
+        let mut changes = set(vec![change(4, 6, "c"), change(0, 2, "a"), change(8, 8, ""),
+                                   change(0, 2, "a"), change(2, 4, "b"), change(9, 10, "d")]);
+        let opts = ReprintOptions::builder().drop_empty_changes(true).build();
+        let report = changes.normalize_in_place(&opts).unwrap();
+        assert!(report.reordered);
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(report.dropped, 1);
+        assert_eq!(report.coalesced, 2);
+        assert_eq!(changes.to_vec(), vec![change(0, 6, "abc"), change(9, 10, "d")]);
+
+        // A tidy set is left as it is.
+        let report = changes.normalize_in_place(&opts).unwrap();
+        assert!(!report.reordered);
+        assert_eq!((report.duplicates, report.dropped, report.coalesced), (0, 0, 0));
+        assert_eq!(changes.to_vec(), vec![change(0, 6, "abc"), change(9, 10, "d")]);
+    }
+
+    #[test]
+    fn post_validation_failure_leaves_the_file() {
+        let opts = |&:| {
+            ReprintOptions::builder().post_validate(Box::new(|&: output: &[u8]| {
+                if output.contains(&b'{') { Ok(()) } else { Err("unbalanced".to_string()) }
+            })).build()
+        };
+
+        let (_dir, file) = temp_file("fn f() {}\n");
+        match reprint_with(set(vec![change_to(&file, 7, 8, "")]), opts()) {
+            Err(ReprintError::PostValidationFailed(ref msg)) if *msg == "unbalanced" => {}
+            result => panic!("expected validation to fail, got {:?}", result)
+        }
+        assert_eq!(read(&file), "fn f() {}\n");
+        assert!(!with_suffix(&file, ".bk").exists());
+
+        reprint_with(set(vec![change_to(&file, 3, 4, "g")]), opts()).unwrap();
+        assert_eq!(read(&file), "fn g() {}\n");
+    }
+
+    #[test]
+    fn shift_changes() {
+        use std::{i64, u32};
+
+        let mut changes = set(vec![change(0, 2, "a"), change(5, 5, "b")]);
+        shift(&mut changes, 10).unwrap();
+        assert_eq!(changes.to_vec(), vec![change(10, 12, "a"), change(15, 15, "b")]);
+        shift(&mut changes, -10).unwrap();
+        assert_eq!(changes.to_vec(), vec![change(0, 2, "a"), change(5, 5, "b")]);
+
+        // Nothing moves if any change would go before the start, or past the
+        // largest offset.
+        assert!(shift(&mut changes, -1).is_err());
+        assert!(shift(&mut changes, u32::MAX as i64 - 4).is_err());
+        assert!(shift(&mut changes, i64::MIN).is_err());
+        assert_eq!(changes.to_vec(), vec![change(0, 2, "a"), change(5, 5, "b")]);
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn reprint_from_json_lines() {
+        use std::io::MemReader;
+        use super::reprint_from_jsonl;
+
+        let (_dir, file) = temp_file("Hello, world!");
+        let lines = "{\"start\": 7, \"end\": 12, \"text\": \"there\"}\n\n\
+                     {\"start\": 0, \"end\": 5, \"text\": \"Hi\"}\n";
+        reprint_from_jsonl(&file, MemReader::new(lines.as_bytes().to_vec())).unwrap();
+        assert_eq!(read(&file), "Hi, there!");
+
+        let lines = "{\"start\": 0, \"end\": 2, \"text\": \"Oh\"}\n{\"start\": 4,\n";
+        match reprint_from_jsonl(&file, MemReader::new(lines.as_bytes().to_vec())) {
+            Err(ReprintError::Verification(ref msg)) => assert!(msg.contains("line 2")),
+            result => panic!("expected a malformed line, got {:?}", result)
+        }
+        assert_eq!(read(&file), "Hi, there!");
+    }
+
+    #[test]
+    fn replace_between_markers() {
+        let input = "fn a() {}\n// BEGIN GENERATED\nold();\nolder();\n    // END GENERATED\n";
+        let (begin, end) = ("// BEGIN GENERATED", "// END GENERATED");
+        let ch = replace_between(&path(), input, begin, end, "new();\n").unwrap();
+        assert_eq!(ch, change(29, 45, "new();\n"));
+        assert_eq!(apply(input, &set(vec![ch])).unwrap(),
+                   "fn a() {}\n// BEGIN GENERATED\nnew();\n    // END GENERATED\n");
+
+        // Nothing between the markers yet.
+        let input = "// BEGIN GENERATED\n// END GENERATED\n";
+        assert_eq!(replace_between(&path(), input, begin, end, "x\n"), Ok(change(19, 19, "x\n")));
+
+        let malformed = ["fn a() {}\n", "// BEGIN GENERATED\n", "// END GENERATED\n",
+                         "// END GENERATED\n// BEGIN GENERATED\n"];
+        for input in malformed.iter() {
+            match replace_between(&path(), *input, begin, end, "x\n") {
+                Err(ReprintError::Verification(_)) => {}
+                result => panic!("expected missing markers in {:?}, got {:?}", input, result)
+            }
+        }
+    }
+
+    fn busy() -> IoError {
+        IoError {
+            kind: IoErrorKind::OtherIoError,
+            desc: "unknown error",
+            detail: Some("Device or resource busy (os error 16)".to_string()),
+        }
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        // Fails the first two attempts.
+        fn flaky(attempts: &mut u8) -> IoResult<u8> {
+            *attempts += 1;
+            if *attempts <= 2 { Err(busy()) } else { Ok(*attempts) }
+        }
+
+        let mut attempts = 0;
+        assert_eq!(with_retries(2, || flaky(&mut attempts)), Ok(3));
+        let mut attempts = 0;
+        assert!(with_retries(1, || flaky(&mut attempts)).is_err());
+        assert_eq!(attempts, 2);
+
+        // Other errors aren't retried.
+        let mut attempts = 0;
+        let result: IoResult<()> = with_retries(3, || {
+            attempts += 1;
+            Err(IoError { kind: IoErrorKind::PermissionDenied, desc: "denied", detail: None })
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn classify_changes() {
+        let kinds = |&: ch: Change| {
+            (ch.is_insertion(), ch.is_deletion(), ch.is_replacement(), ch.is_noop())
+        };
+        assert_eq!(kinds(change(3, 3, "x")), (true, false, false, false));
+        assert_eq!(kinds(change(3, 5, "")), (false, true, false, false));
+        assert_eq!(kinds(change(3, 5, "x")), (false, false, true, false));
+        assert_eq!(kinds(change(3, 3, "")), (false, false, false, true));
+        // Replacing text with the same text is still a replacement.
+        assert_eq!(kinds(change(0, 1, "a")), (false, false, true, false));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_reprint_matches_reprint() {
+        use super::reprint_parallel;
+
+        let line = "let x = some_function(argument, another_argument);\n";
+        let input: String = repeat(line).take(20000).collect();
+        let (_dir, file) = temp_file(&input[]);
+        let mut rng = Rng(0x85EBCA6B);
+        let changes: ChangeSet = (0..20000u32).filter(|_| rng.next(3) == 0).map(|i| {
+            let start = i * line.len() as u32 + 4;
+            change_to(&file, start, start + 1, "y")
+        }).collect();
+        let expected = apply(&input[], &changes).unwrap();
+
+        for threads in [1, 2, 7].iter() {
+            write(&file, &input[]);
+            let _ = fs::unlink(&with_suffix(&file, ".bk"));
+            reprint_parallel(&file, set(changes.to_vec()), *threads).unwrap();
+            assert_eq!(read(&file), expected);
+        }
+
+        // As for reprint, a no-op edit leaves the file alone, with no backup.
+        write(&file, &input[]);
+        let _ = fs::unlink(&with_suffix(&file, ".bk"));
+        let start = line.len() as u32 + 4;
+        let no_op = set(vec![change_to(&file, start, start + 1, "x")]);
+        let summary = reprint_parallel(&file, no_op, 2).unwrap();
+        assert!(!summary.written);
+        assert_eq!(summary.bytes_written, 0);
+        assert_eq!(read(&file), input);
+        assert!(!with_suffix(&file, ".bk").exists());
+    }
+
+    #[test]
+    fn expand_variables_in_text() {
+        let mut variables = HashMap::new();
+        variables.insert("HOST".to_string(), "example.com".to_string());
+        variables.insert("EMPTY".to_string(), "".to_string());
+
+        assert_eq!(expand_text("host = ${HOST}", &variables), Ok("host = example.com".to_string()));
+        assert_eq!(expand_text("port = ${PORT:-80}", &variables), Ok("port = 80".to_string()));
+        assert_eq!(expand_text("${HOST:-localhost}${EMPTY:-x}", &variables),
+                   Ok("example.com".to_string()));
+        // `$$` is a literal `$`, as is a `$` without a `{`.
+        assert_eq!(expand_text("cost = $$5, $HOST", &variables),
+                   Ok("cost = $5, $HOST".to_string()));
+        assert!(expand_text("port = ${PORT}", &variables).is_err());
+        assert!(expand_text("port = ${PORT", &variables).is_err());
+    }
+
+    #[test]
+    fn undefined_variables_fail_the_edit() {
+        let (_dir, file) = temp_file("host = \n");
+        let mut variables = HashMap::new();
+        variables.insert("HOST".to_string(), "example.com".to_string());
+        let opts = |&:| ReprintOptions::builder().variables(variables.clone()).build();
+
+        match reprint_with(set(vec![change_to(&file, 7, 7, "${USER}")]), opts()) {
+            Err(ReprintError::Verification(_)) => {}
+            result => panic!("expected an undefined variable, got {:?}", result)
+        }
+        assert_eq!(read(&file), "host = \n");
+
+        reprint_with(set(vec![change_to(&file, 7, 7, "${HOST}")]), opts()).unwrap();
+        assert_eq!(read(&file), "host = example.com\n");
+    }
+
+    #[test]
+    fn snapshots_apply_only_to_their_input() {
+        let (_dir, file) = temp_file("Hello, world!");
+        let changes = || set(vec![change_to(&file, 7, 12, "there")]);
+
+        for contents in ["Hello, World!", "Hello, world!!"].iter() {
+            write(&file, *contents);
+            match apply_snapshot(&file, snapshot("Hello, world!", &changes())) {
+                Err(ReprintError::InputChanged) => {}
+                result => panic!("expected the input to have changed, got {:?}", result)
+            }
+            assert_eq!(read(&file), *contents);
+        }
+
+        write(&file, "Hello, world!");
+        apply_snapshot(&file, snapshot("Hello, world!", &changes())).unwrap();
+        assert_eq!(read(&file), "Hello, there!");
+    }
+
+    #[cfg(feature = "serialize")]
+    #[test]
+    fn snapshots_round_trip_through_json() {
+        use super::EditSnapshot;
+
+        let snap = snapshot("Hello, world!", &set(vec![change(7, 12, "there")]));
+        let json = snap.to_json().unwrap();
+        let read_back = EditSnapshot::from_json(&path(), &json[]).unwrap();
+        assert_eq!(read_back.input_hash, snap.input_hash);
+        assert_eq!(read_back.input_len, 13);
+        assert_eq!(read_back.changes.to_vec(), vec![change(7, 12, "there")]);
+        assert!(EditSnapshot::from_json(&path(), "{}").is_err());
+    }
+
+    #[test]
+    fn confirmed_edits_fail_if_the_file_changes() {
+        let (_dir, file) = temp_file("Hello\n");
+        let changes = || set(vec![change_to(&file, 0, 1, "J")]);
+
+        let result = reprint_with_confirm(&file, changes(), |before: &str, after: &[u8]| {
+            assert_eq!(before, "Hello\n");
+            assert_eq!(after, &b"Jello\n"[]);
+            false
+        });
+        assert!(result.unwrap().is_none());
+        assert_eq!(read(&file), "Hello\n");
+
+        // Changed (to the same length) while the user looks at the preview.
+        let result = reprint_with_confirm(&file, changes(), |_, _| {
+            write(&file, "Hallo\n");
+            true
+        });
+        match result {
+            Err(ReprintError::InputChanged) => {}
+            result => panic!("expected the input to have changed, got {:?}", result)
+        }
+        assert_eq!(read(&file), "Hallo\n");
+
+        assert!(reprint_with_confirm(&file, changes(), |_, _| true).unwrap().is_some());
+        assert_eq!(read(&file), "Jallo\n");
+    }
+
+    #[test]
+    fn ranges_of_documents() {
+        let input = "a: 1\n---\nb: 2\n---\nc: 3\n";
+        assert_eq!(document_range(input, "---", 0), Some(0..5));
+        assert_eq!(document_range(input, "---", 1), Some(9..14));
+        assert_eq!(document_range(input, "---", 2), Some(18..23));
+        assert_eq!(document_range(input, "---", 3), None);
+        // A delimiter first makes an empty first document.
+        assert_eq!(document_range("---\r\na: 1\r\n", "---", 0), Some(0..0));
+        assert_eq!(document_range("---\r\na: 1\r\n", "---", 1), Some(5..11));
+    }
+
+    #[test]
+    fn edits_confined_to_a_document() {
+        let (_dir, file) = temp_file("a: 1\n---\nb: 2\n");
+        let region = || document_range("a: 1\n---\nb: 2\n", "---", 1).unwrap();
+        assert!(reprint_with(set(vec![change_to(&file, 3, 4, "9")]),
+                             ReprintOptions::builder().region(region()).build()).is_err());
+        assert_eq!(read(&file), "a: 1\n---\nb: 2\n");
+        reprint_with(set(vec![change_to(&file, 12, 13, "9")]),
+                     ReprintOptions::builder().region(region()).build()).unwrap();
+        assert_eq!(read(&file), "a: 1\n---\nb: 9\n");
+    }
+
+    #[test]
+    fn large_outputs_are_written_in_full() {
+        let input: String = repeat("0123456789abcdef").take(1 << 19).collect();
+        let (_dir, file) = temp_file(&input[]);
+        let len = input.len() as u32;
+        let changes = set(vec![change_to(&file, 0, 0, ">"), change_to(&file, len, len, "<")]);
+        let summaries = reprint_with(changes, ReprintOptions::default()).unwrap();
+        assert_eq!(summaries[0].bytes_written, len as u64 + 2);
+        let output = read(&file);
+        assert_eq!(output.len(), input.len() + 2);
+        assert!(output.starts_with(">0123") && output.ends_with("cdef<"));
+        assert_eq!(&output[1..output.len() - 1], &input[]);
+    }
+
+    #[test]
+    fn replace_in_a_tree() {
+        let dir = TempDir::new("reprint-test").unwrap();
+        let root = dir.path();
+        fs::mkdir(&root.join("sub"), USER_RWX).unwrap();
+        write(&root.join("a.rs"), "foo();\n");
+        write(&root.join("sub").join("b.rs"), "foo(foo);\n");
+        write(&root.join("c.txt"), "foo\n");
+        write(&root.join("d.rs"), "bar();\n");
+        File::create(&root.join("e.rs")).unwrap().write(b"foo\xff\n").unwrap();
+
+        let results = replace_in_tree(root, "*.rs", "foo", "bar");
+        let paths: Vec<Path> = results.iter().map(|&(ref path, _)| path.clone()).collect();
+        assert_eq!(paths, vec![root.join("a.rs"), root.join("sub").join("b.rs")]);
+        assert!(results.iter().all(|&(_, ref result)| result.is_ok()));
+        assert_eq!(read(&root.join("a.rs")), "bar();\n");
+        assert_eq!(read(&root.join("sub").join("b.rs")), "bar(bar);\n");
+        assert_eq!(read(&root.join("c.txt")), "foo\n");
+
+        // Only files under `sub` match, and the backup just made there is
+        // skipped.
+        write(&root.join("sub").join("f.txt"), "foo\n");
+        let results = replace_in_tree(root, "sub/*", "foo", "baz");
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, root.join("sub").join("f.txt"));
+        assert_eq!(read(&root.join("sub").join("f.txt")), "baz\n");
+        assert_eq!(read(&root.join("sub").join("b.rs.bk")), "foo(foo);\n");
+        assert_eq!(read(&root.join("c.txt")), "foo\n");
+    }
+
+    #[test]
+    fn changes_covering_required_ranges() {
+        let changes = set(vec![change(2, 6, "a"), change(8, 8, "b"), change(10, 14, "")]);
+        // Fully covered.
+        assert!(assert_covers(&changes, &[3..5, 8..8, 10..12, 12..14], false).is_ok());
+        assert!(assert_covers(&changes, &[2..6, 8..8, 10..14], true).is_ok());
+        // Meeting ranges merge, so one change may cover several.
+        assert!(assert_covers(&changes, &[2..4, 4..6, 8..8, 10..14], true).is_ok());
+        // Partly covered.
+        assert!(assert_covers(&changes, &[5..7], false).is_err());
+        assert!(assert_covers(&changes, &[9..9], false).is_err());
+        // Over-reaching, which only matters if the required ranges are exclusive.
+        assert!(assert_covers(&changes, &[2..6, 10..14], false).is_ok());
+        assert!(assert_covers(&changes, &[2..6, 10..14], true).is_err());
+        assert!(assert_covers(&changes, &[3..5, 8..8, 10..14], true).is_err());
+    }
+
+    #[test]
+    fn char_and_byte_offsets_round_trip() {
+        let input = "aé€😀z";
+        let bytes = [0u32, 1, 3, 6, 10, 11];
+        for (i, &byte) in bytes.iter().enumerate() {
+            assert_eq!(char_to_byte(input, i), Some(byte));
+            assert_eq!(byte_to_char(input, byte), Some(i));
+        }
+        assert_eq!(char_to_byte(input, 6), None);
+        assert_eq!(byte_to_char(input, 12), None);
+        // Inside a character.
+        for &byte in [2u32, 4, 5, 7, 8, 9].iter() {
+            assert_eq!(byte_to_char(input, byte), None);
+        }
+        assert_eq!(char_to_byte("", 0), Some(0));
+    }
+
+    #[test]
+    fn insert_aligned_at_various_columns() {
+        let input = "let a = 1;\nlet bb = 2;\nlet ccccccc = 3;\n";
+        // Ahead of the target column.
+        assert_eq!(insert_aligned(&path(), input, 5, "= 1;", 10), change(5, 5, "     = 1;"));
+        assert_eq!(insert_aligned(&path(), input, 17, "=", 10), change(17, 17, "    ="));
+        // At and behind it.
+        assert_eq!(insert_aligned(&path(), input, 20, "=", 9), change(20, 20, "="));
+        assert_eq!(insert_aligned(&path(), input, 34, "=", 9), change(34, 34, "="));
+        // Columns count characters, not bytes.
+        assert_eq!(insert_aligned(&path(), "é = 1", 2, "x", 3), change(2, 2, "  x"));
+    }
+
+    #[test]
+    fn try_merge_clean_sets() {
+        let a = set(vec![change(4, 5, "b"), change(0, 2, "a")]);
+        let b = set(vec![change(2, 2, "c"), change(4, 5, "b"), change(8, 9, "")]);
+        let merged = try_merge(a, b).unwrap();
+        assert_eq!(merged.to_vec(),
+                   vec![change(0, 2, "a"), change(2, 2, "c"), change(4, 5, "b"), change(8, 9, "")]);
+    }
+
+    #[test]
+    fn try_merge_conflicts() {
+        let conflict = |&: a: Vec<Change>, b: Vec<Change>| {
+            match try_merge(set(a), set(b)) {
+                Ok(_) => None,
+                Err(TryMergeError::Conflict(conflict)) => Some(conflict),
+                Err(e) => panic!("expected a conflict, got {:?}", e)
+            }
+        };
+        // Contained.
+        assert_eq!(conflict(vec![change(0, 10, "a")], vec![change(2, 4, "b")]),
+                   Some(MergeConflict { a_index: 0, b_index: 0, a_range: 0..10, b_range: 2..4 }));
+        // Partly overlapping, found after sorting.
+        assert_eq!(conflict(vec![change(9, 9, "x"), change(0, 5, "a")],
+                            vec![change(9, 10, "y"), change(3, 8, "b")]),
+                   Some(MergeConflict { a_index: 0, b_index: 0, a_range: 0..5, b_range: 3..8 }));
+        // The same range with different text.
+        assert_eq!(conflict(vec![change(2, 4, "a")], vec![change(2, 4, "b")]),
+                   Some(MergeConflict { a_index: 0, b_index: 0, a_range: 2..4, b_range: 2..4 }));
+        // Identical changes are kept once rather than conflicting.
+        assert_eq!(conflict(vec![change(2, 4, "a")], vec![change(2, 4, "a")]), None);
+    }
+
+    #[test]
+    fn try_merge_rejects_an_invalid_set() {
+        // Overlapping changes within one set, even with nothing to merge.
+        match try_merge(set(vec![change(0, 5, "a"), change(3, 8, "b")]), ChangeSet::new()) {
+            Err(TryMergeError::Invalid { in_a: true, error: ReprintError::Verification(_) }) => {}
+            result => panic!("expected an invalid set, got {:?}", result.map(|c| c.into_vec()))
+        }
+        match try_merge(set(vec![change(12, 12, "x")]),
+                        set(vec![change(2, 4, "b"), change(0, 10, "a")])) {
+            Err(TryMergeError::Invalid {
+                in_a: false,
+                error: ReprintError::ContainedChange { outer: 0, inner: 1 }
+            }) => {}
+            result => panic!("expected an invalid set, got {:?}", result.map(|c| c.into_vec()))
+        }
+        // A duplicate within a set is removed rather than being a conflict.
+        let merged = try_merge(set(vec![change(2, 4, "a"), change(2, 4, "a")]), ChangeSet::new());
+        assert_eq!(merged.unwrap().into_vec(), vec![change(2, 4, "a")]);
+    }
+
+    #[test]
+    fn fixtures_round_trip() {
+        let changes = set(vec![change(8, 8, "a \"quoted\"\n\ttab\\"), change(3, 8, "Goodbye cruel"),
+                               change(10, 12, "")]);
+        let fixture = to_fixture(&changes);
+        assert_eq!(fixture,
+                   "3..8=\"Goodbye cruel\"\n8..8=\"a \\\"quoted\\\"\\n\\ttab\\\\\"\n10..12=\"\"\n");
+        let read_back = from_fixture(&path(), &fixture[]).unwrap();
+        assert_eq!(read_back.to_vec(), normalize(changes).unwrap().to_vec());
+        assert!(from_fixture(&path(), "3..8 Goodbye\n").is_err());
+    }
+
+    #[test]
+    fn replacing_the_last_bytes() {
+        // The replaced bytes include any final newline, which isn't put back.
+        let cases = [("Hello, world", "Hello, wold!"),
+                     ("Hello, world\n", "Hello, world!"),
+                     ("Hello, world\r\n", "Hello, worlld!")];
+        for &(input, expected) in cases.iter() {
+            let len = input.len() as u32;
+            let text = "ld!";
+            let changes = set(vec![change(len - 3, len, text)]);
+            assert_eq!(apply(input, &changes).unwrap(), expected);
+
+            let (_dir, file) = temp_file(input);
+            reprint_with(set(vec![change_to(&file, len - 3, len, text)]), ReprintOptions::default())
+                .unwrap();
+            assert_eq!(read(&file), expected);
+        }
+
+        // Ending just before the end keeps the last byte.
+        assert_eq!(apply("Hello, world\n", &set(vec![change(9, 12, "LD")])).unwrap(),
+                   "Hello, woLD\n");
+        assert_eq!(apply("Hello, world", &set(vec![change(8, 11, "")])).unwrap(), "Hello, wd");
+    }
+
+    #[test]
+    fn overlap_extents() {
+        let overlap = |&: first: usize, second: usize, range: Range<u32>| {
+            Overlap { first: first, second: second, range: range }
+        };
+        // Three mutually overlapping changes.
+        let changes = set(vec![change(0, 10, "a"), change(2, 8, "b"), change(5, 12, "c")]);
+        assert_eq!(overlaps(&changes), vec![overlap(0, 1, 2..8), overlap(0, 2, 5..10),
+                                            overlap(1, 2, 5..8)]);
+        // A chain, where the first and last don't overlap.
+        let changes = set(vec![change(0, 6, "a"), change(4, 10, "b"), change(8, 12, "c")]);
+        assert_eq!(overlaps(&changes), vec![overlap(0, 1, 4..6), overlap(1, 2, 8..10)]);
+        // Insertions only overlap changes they are inside.
+        let changes = set(vec![change(2, 2, "a"), change(2, 6, "b"), change(4, 4, "c"),
+                               change(6, 6, "d")]);
+        assert_eq!(overlaps(&changes), vec![overlap(1, 2, 4..4)]);
+        assert!(overlaps(&set(vec![change(0, 2, "a"), change(2, 4, "b")])).is_empty());
+    }
+
+    #[test]
+    fn a_single_byte_insert_marks_one_line() {
+        let input = "one\ntwo\nthree\n";
+        assert_eq!(changed_line_mask(input, &set(vec![change(5, 5, "x")])),
+                   Ok(vec![false, true, false]));
+        assert_eq!(changed_line_mask(input, &ChangeSet::new()), Ok(vec![false, false, false]));
+        // A removal marks the line where the text was.
+        assert_eq!(changed_line_mask(input, &set(vec![change(4, 8, "")])),
+                   Ok(vec![false, true]));
+        // Inserted lines are marked, including a last line without a newline.
+        assert_eq!(changed_line_mask("one", &set(vec![change(3, 3, "\ntwo")])),
+                   Ok(vec![true, true]));
+    }
+
+    // Keeps what it is given to write, rather than writing it.
+    struct CapturingWriter(Rc<RefCell<Vec<(Path, Vec<u8>)>>>);
+
+    impl FileWriter for CapturingWriter {
+        fn write_file(&self,
+                      file: &Path,
+                      contents: Vec<u8>,
+                      _: &ReprintOptions)
+        -> Result<(), ReprintError> {
+            self.0.borrow_mut().push((file.clone(), contents));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn custom_writers_get_the_output() {
+        let (_dir, file) = temp_file("Hello\n");
+        let written = Rc::new(RefCell::new(vec![]));
+        let writer = Box::new(CapturingWriter(written.clone()));
+        let opts = ReprintOptions::builder().writer(writer).build();
+        reprint_with(set(vec![change_to(&file, 0, 1, "J")]), opts).unwrap();
+
+        assert_eq!(*written.borrow(), vec![(file.clone(), b"Jello\n".to_vec())]);
+        // The writer didn't write it, so nothing did.
+        assert_eq!(read(&file), "Hello\n");
+        assert!(!with_suffix(&file, ".bk").exists());
+    }
+
+    #[test]
+    fn validate_utf8_output() {
+        let (_dir, file) = temp_file("héllo\n");
+        let opts = ReprintOptions::builder().validate_utf8(true).build();
+        reprint_with(set(vec![change_to(&file, 3, 3, "€")]), opts).unwrap();
+        assert_eq!(read(&file), "hé€llo\n");
+
+        // Splicing bytes can cut a character in two, which reprint's checks on
+        // changes would refuse.
+        let input = "héllo".as_bytes();
+        let changes = [change(2, 3, "")];
+        let mut output = vec![];
+        splice(input, &changes, |kind, bytes| {
+            if kind != SpanKind::Remove {
+                output.push_all(bytes);
+            }
+        }, None).unwrap();
+        assert_eq!(check_utf8(&output[]), Err(ReprintError::InvalidUtf8 { pos: 1 }));
+        assert_eq!(check_utf8(&output[..1]), Ok(()));
+        assert_eq!(check_utf8(&[b'a', 0xC3][]), Err(ReprintError::InvalidUtf8 { pos: 1 }));
+    }
+
+    #[test]
+    fn snap_changes_to_whole_lines() {
+        let input = "one\ntwo\nthree\n";
+        let cases = [(vec![change(5, 6, "W")], vec![change(4, 8, "tWo\n")]),
+                     (vec![change(2, 6, "X")], vec![change(0, 8, "onXo\n")]),
+                     (vec![change(4, 4, "new\n")], vec![change(4, 8, "new\ntwo\n")]),
+                     // Changes sharing a line are merged.
+                     (vec![change(6, 7, "O"), change(4, 5, "T")], vec![change(4, 8, "TwO\n")]),
+                     (vec![change(1, 2, "N"), change(10, 11, "R")],
+                      vec![change(0, 4, "oNe\n"), change(8, 14, "thRee\n")])];
+        for &(ref changes, ref expected) in cases.iter() {
+            let snapped = snap_to_lines(input, set(changes.clone())).unwrap();
+            assert_eq!(snapped.to_vec(), *expected);
+            assert_eq!(apply(input, &snapped), normalize_and_apply(input, changes.clone()));
+        }
+    }
+
+    #[test]
+    fn verify_change_sets() {
+        assert!(ChangeSet::new().verify().is_ok());
+        let valid = set(vec![change(0, 2, "a"), change(2, 2, "b"), change(2, 4, "c")]);
+        assert!(valid.verify().is_ok());
+        // Overlapping.
+        assert!(set(vec![change(0, 3, "a"), change(2, 4, "b")]).verify().is_err());
+        // Inverted.
+        assert!(set(vec![change(4, 2, "a")]).verify().is_err());
+        // Changes to different files can't overlap.
+        let other = Change::new(Path::new("other.rs"), 1, 3, "b".to_string());
+        assert!(set(vec![other, change(0, 3, "a")]).verify().is_ok());
+    }
+
+    #[test]
+    fn undo_restores_the_backup() {
+        let (_dir, file) = temp_file("Hello\n");
+        edit_first_byte(&file, "J", ReprintOptions::default());
+        undo(&file, ReprintOptions::default()).unwrap();
+        assert_eq!(read(&file), "Hello\n");
+        assert!(!with_suffix(&file, ".bk").exists());
+        // Nothing left to undo.
+        assert!(undo(&file, ReprintOptions::default()).is_err());
+        assert_eq!(read(&file), "Hello\n");
+    }
+
+    #[test]
+    fn undo_rotated_backups() {
+        let (_dir, file) = temp_file("0\n");
+        let opts = || ReprintOptions::builder().backup_rotation(3).build();
+        for text in ["1", "2"].iter() {
+            edit_first_byte(&file, *text, opts());
+        }
+        undo(&file, opts()).unwrap();
+        assert_eq!(read(&file), "1\n");
+        undo(&file, opts()).unwrap();
+        assert_eq!(read(&file), "0\n");
+        assert!(undo(&file, opts()).is_err());
+    }
+
+    #[test]
+    fn identical_concurrent_edits_both_succeed() {
+        for _ in 0..20 {
+            let (dir, file) = temp_file("Hello\n");
+            let guards: Vec<_> = (0..2).map(|_| {
+                let file = file.clone();
+                Thread::scoped(move || {
+                    let opts = ReprintOptions::builder().content_addressed_temp(true).build();
+                    reprint_with(set(vec![change_to(&file, 0, 1, "J")]), opts)
+                })
+            }).collect();
+            for guard in guards.into_iter() {
+                assert!(guard.join().unwrap().is_ok());
+            }
+            assert_eq!(read(&file), "Jello\n");
+            // The backup is of the file before either edit, and no temp file
+            // is left.
+            assert_eq!(read(&with_suffix(&file, ".bk")), "Hello\n");
+            assert_eq!(fs::readdir(dir.path()).unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn replace_the_nth_occurrence() {
+        let input = "foo(foo, foofoo)";
+        assert_eq!(replace_nth(&path(), input, "foo", "bar", 1), Ok(change(0, 3, "bar")));
+        assert_eq!(replace_nth(&path(), input, "foo", "bar", 2), Ok(change(4, 7, "bar")));
+        assert_eq!(replace_nth(&path(), input, "foo", "bar", 4), Ok(change(12, 15, "bar")));
+        // Occurrences don't overlap.
+        assert_eq!(replace_nth(&path(), "aaaa", "aa", "b", 2), Ok(change(2, 4, "b")));
+        assert!(replace_nth(&path(), "aaaa", "aa", "b", 3).is_err());
+
+        assert!(replace_nth(&path(), input, "foo", "bar", 5).is_err());
+        assert!(replace_nth(&path(), input, "foo", "bar", 0).is_err());
+        assert!(replace_nth(&path(), input, "", "bar", 1).is_err());
+    }
+
+    #[test]
+    fn contained_changes_are_reported_as_such() {
+        for changes in [vec![change(0, 10, "a"), change(2, 4, "b")],
+                        vec![change(5, 10, "b"), change(0, 10, "a")],
+                        vec![change(12, 12, "c"), change(0, 10, "a"), change(2, 4, "b")]].iter() {
+            match normalize(set(changes.clone())) {
+                Err(ReprintError::ContainedChange { outer: 0, inner: 1 }) => {}
+                result => panic!("expected containment, got {:?}", result.map(|c| c.into_vec()))
+            }
+        }
+        // A partial overlap isn't containment.
+        match normalize(set(vec![change(0, 5, "a"), change(3, 8, "b")])) {
+            Err(ReprintError::Verification(_)) => {}
+            result => panic!("expected an overlap, got {:?}", result.map(|c| c.into_vec()))
+        }
+    }
+
+    #[test]
+    fn rebasing_pending_changes() {
+        // Replaces "bbbb" in "aaaabbbbcccc" with "xy", so moves later text back 2.
+        let applied = change(4, 8, "xy");
+        let rebased = |&: start: u32, end: u32| {
+            let mut pending = change(start, end, "z");
+            rebase_change(&applied, &mut pending).map(|()| (pending.start_byte, pending.end_byte))
+        };
+
+        // Before `applied`, including an insertion where it starts.
+        assert_eq!(rebased(0, 4), Ok((0, 4)));
+        assert_eq!(rebased(4, 4), Ok((4, 4)));
+        // After `applied`, including an insertion where it ends.
+        assert_eq!(rebased(10, 12), Ok((8, 10)));
+        assert_eq!(rebased(8, 8), Ok((6, 6)));
+        // Overlapping `applied`.
+        for &(start, end) in [(2, 5), (6, 10), (5, 6), (0, 12)].iter() {
+            match rebased(start, end) {
+                Err(ReprintError::Verification(_)) => {}
+                result => panic!("expected an overlap, got {:?}", result)
+            }
+        }
+    }
+
+    #[test]
+    fn max_lines_changed_just_under_and_over_the_limit() {
+        let (_dir, file) = temp_file("a\nb\nc\nd\n");
+        let changes = |&:| set(vec![change_to(&file, 0, 1, "A"), change_to(&file, 4, 5, "C")]);
+
+        let opts = ReprintOptions::builder().max_lines_changed(1).build();
+        match reprint_with(changes(), opts) {
+            Err(ReprintError::ThresholdExceeded { lines: 2, limit: 1 }) => {}
+            result => panic!("expected too many lines changed, got {:?}", result)
+        }
+        assert_eq!(read(&file), "a\nb\nc\nd\n");
+
+        let opts = ReprintOptions::builder().max_lines_changed(2).build();
+        reprint_with(changes(), opts).unwrap();
+        assert_eq!(read(&file), "A\nb\nC\nd\n");
+    }
+
+    #[cfg(all(feature = "fd", unix))]
+    #[test]
+    fn reprint_to_fd_writes_to_a_pipe() {
+        use libc;
+        use super::{read_fd, reprint_to_fd};
+
+        let (_dir, file) = temp_file("Hello world\n");
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (reader, writer) = (fds[0], fds[1]);
+
+        // The output is small enough to fit in the pipe's buffer, so this won't
+        // block before we read.
+        let result = reprint_to_fd(&file, set(vec![change_to(&file, 0, 5, "Goodbye")]), writer);
+        unsafe { libc::close(writer); }
+        result.unwrap();
+        let output = read_fd(reader);
+        unsafe { libc::close(reader); }
+
+        assert_eq!(output.unwrap(), b"Goodbye world\n".to_vec());
+        assert_eq!(read(&file), "Hello world\n");
+    }
+
+    #[test]
+    fn inserted_text_matches_the_line_ending_of_its_line() {
+        let (_dir, file) = temp_file("a\nb\r\nc\n");
+        let opts = ReprintOptions::builder().match_surrounding_eol(true).build();
+        // Into the LF line "a" and the CRLF line "b".
+        let changes = set(vec![change_to(&file, 1, 1, "X\r\nY"), change_to(&file, 2, 2, "P\nQ\n")]);
+        reprint_with(changes, opts).unwrap();
+        assert_eq!(read(&file), "aX\nY\nP\r\nQ\r\nb\r\nc\n");
+    }
+
+    #[test]
+    fn binary_patch_round_trip() {
+        let input = "Hello world\nbye\nend\n";
+        let (_dir, file) = temp_file(input);
+        let changes = set(vec![change_to(&file, 0, 5, "Goodbye"),
+                               change_to(&file, 12, 16, ""),
+                               change_to(&file, 20, 20, "more\n")]);
+        let (summary, patch) = reprint_binary_patch(&file, changes).unwrap();
+        assert!(summary.written);
+        assert_eq!(read(&file), "Goodbye world\nend\nmore\n");
+
+        assert_eq!(apply_binary_patch(input.as_bytes(), &patch[]),
+                   Ok(b"Goodbye world\nend\nmore\n".to_vec()));
+        match apply_binary_patch(input.as_bytes(), &patch[..patch.len() - 1]) {
+            Err(ReprintError::Process(_)) => {}
+            result => panic!("expected a malformed patch, got {:?}", result)
+        }
+    }
+
+    #[test]
+    fn estimating_memory_for_a_small_edit_to_a_huge_file() {
+        let huge = 1 << 30;
+        let estimate = estimate_memory(huge, &set(vec![change(100, 110, "abcdefghijkl")]));
+        assert_eq!(estimate.output_capacity, huge + 2);
+        assert_eq!(estimate.peak, 2 * huge + 2);
+        assert!(estimate.prefer_streaming);
+    }
+
+    #[test]
+    fn estimating_memory_for_rewriting_everything() {
+        let text: String = repeat('x').take(1200).collect();
+        let estimate = estimate_memory(1000, &set(vec![change(0, 1000, &text[])]));
+        assert_eq!(estimate.output_capacity, 1200);
+        assert_eq!(estimate.peak, 2200);
+        assert!(!estimate.prefer_streaming);
+    }
+
+    #[test]
+    fn a_transaction_edits_every_file() {
+        let (dir, first) = temp_file("first\n");
+        let second = dir.path().join("second.txt");
+        write(&second, "second\n");
+        let jobs = vec![(first.clone(), set(vec![change_to(&first, 0, 5, "FIRST")])),
+                        (second.clone(), set(vec![change_to(&second, 0, 6, "SECOND")]))];
+        let summaries = reprint_transaction(jobs).unwrap();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(read(&first), "FIRST\n");
+        assert_eq!(read(&second), "SECOND\n");
+    }
+
+    #[test]
+    fn a_transaction_leaves_files_it_would_not_change_alone() {
+        let (dir, first) = temp_file("first\n");
+        let second = dir.path().join("second.txt");
+        write(&second, "second\n");
+        let jobs = vec![(first.clone(), set(vec![change_to(&first, 0, 5, "first")])),
+                        (second.clone(), set(vec![change_to(&second, 0, 6, "SECOND")]))];
+        let summaries = reprint_transaction(jobs).unwrap();
+        assert_eq!(summaries.iter().map(|s| (s.written, s.bytes_written)).collect::<Vec<_>>(),
+                   vec![(false, 0), (true, 7)]);
+        assert_eq!(read(&first), "first\n");
+        assert_eq!(read(&second), "SECOND\n");
+        // Only the second file was backed up.
+        assert!(!with_suffix(&first, ".bk").exists());
+        assert_eq!(read(&with_suffix(&second, ".bk")), "second\n");
+    }
+
+    #[test]
+    fn rollback_failures_are_reported_with_their_cause() {
+        let e = ReprintError::RollbackFailed {
+            cause: Box::new(ReprintError::InputChanged),
+            rollback: vec![ReprintError::Io("a".to_string()), ReprintError::Io("b".to_string())],
+        };
+        assert_eq!(e.to_string(),
+                   "File has changed since the changes were computed; then rolling back failed: \
+                    I/O error: a; I/O error: b");
+    }
+
+    #[test]
+    fn a_failed_transaction_leaves_the_first_file_unchanged() {
+        let (dir, first) = temp_file("first\n");
+        let second = dir.path().join("second.txt");
+        write(&second, "second\n");
+        // Something else's temp file is in the way of the second file's.
+        let in_the_way = with_suffix(&second, ".tmp");
+        write(&in_the_way, "in the way");
+
+        let jobs = vec![(first.clone(), set(vec![change_to(&first, 0, 5, "FIRST")])),
+                        (second.clone(), set(vec![change_to(&second, 0, 6, "SECOND")]))];
+        match reprint_transaction(jobs) {
+            Err(ReprintError::AlreadyExists(_)) => {}
+            result => panic!("expected the temp file to exist, got {:?}", result)
+        }
+        assert_eq!(read(&first), "first\n");
+        assert_eq!(read(&second), "second\n");
+        // The first file's temp file is gone, and the other one is left alone.
+        assert_eq!(read(&in_the_way), "in the way");
+        assert_eq!(fs::readdir(dir.path()).unwrap().len(), 3);
+    }
+
+    // Lines "a" to "j", with "b" (line 2) and "g" (line 7) upper cased, which
+    // have four lines between them.
+    fn hunks_with_context(context: usize) -> (Vec<Hunk>, String) {
+        let (_dir, file) = temp_file("a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n");
+        let changes = |&:| set(vec![change_to(&file, 2, 3, "B"), change_to(&file, 12, 13, "G")]);
+        let opts = |&:| ReprintOptions::builder().context_lines(context).build();
+        (reprint_hunks_with(&file, changes(), opts()).unwrap(),
+         reprint_git_patch_with(&file, changes(), opts()).unwrap())
+    }
+
+    fn patch_hunks(patch: &str) -> usize {
+        patch.lines().filter(|l| l.starts_with("@@")).count()
+    }
+
+    #[test]
+    fn hunks_without_context() {
+        let (hunks, patch) = hunks_with_context(0);
+        assert_eq!(hunks, vec![Hunk {
+            original_range: 2..4,
+            original_text: "b\n".to_string(),
+            new_text: "B\n".to_string(),
+            line_range: 2..3,
+        }, Hunk {
+            original_range: 12..14,
+            original_text: "g\n".to_string(),
+            new_text: "G\n".to_string(),
+            line_range: 7..8,
+        }]);
+        assert_eq!(patch_hunks(&patch[]), 2);
+    }
+
+    #[test]
+    fn hunks_whose_context_does_not_meet_are_separate() {
+        let (hunks, patch) = hunks_with_context(1);
+        let ranges: Vec<_> = hunks.iter().map(|h| (h.original_range.clone(),
+                                                   h.line_range.clone())).collect();
+        assert_eq!(ranges, vec![(0..6, 1..4), (10..16, 6..9)]);
+        assert_eq!(hunks[0].new_text, "a\nB\nc\n");
+        assert_eq!(patch_hunks(&patch[]), 2);
+    }
+
+    #[test]
+    fn hunks_whose_context_meets_are_merged() {
+        // Two lines of context after "b" and two before "g" cover the four
+        // lines between them.
+        for &context in [2, 3].iter() {
+            let (hunks, patch) = hunks_with_context(context);
+            assert_eq!(hunks.len(), 1);
+            assert_eq!(hunks[0].original_range.start, 0);
+            assert!(hunks[0].new_text.starts_with("a\nB\nc\nd\ne\nf\nG\nh\n"),
+                    "{}", hunks[0].new_text);
+            assert_eq!(patch_hunks(&patch[]), 1);
+        }
+    }
+
+    #[test]
+    fn previewing_several_files() {
+        let (dir, hello) = temp_file("Hello world\n");
+        let (numbers, same) = (dir.path().join("numbers.txt"), dir.path().join("same.txt"));
+        write(&numbers, "one\ntwo\n");
+        write(&same, "same\n");
+        let jobs = vec![(hello.clone(), set(vec![change_to(&hello, 0, 5, "Goodbye")])),
+                        (numbers.clone(), set(vec![change_to(&numbers, 0, 4, ""),
+                                                   change_to(&numbers, 8, 8, "three\n")])),
+                        (same.clone(), set(vec![]))];
+
+        let summary = preview_all(&jobs[]).unwrap();
+        assert_eq!(summary.files_affected, 2);
+        assert_eq!((summary.inserted_bytes, summary.removed_bytes), (13, 9));
+        assert_eq!(summary.changes, 3);
+        let files: Vec<_> = summary.files.iter().map(|&(ref file, stats)| {
+            (file.clone(), stats.inserted_bytes, stats.removed_bytes, stats.kept_bytes)
+        }).collect();
+        assert_eq!(files, vec![(hello.clone(), 7, 5, 7),
+                               (numbers.clone(), 6, 4, 4),
+                               (same.clone(), 0, 0, 5)]);
+
+        // Nothing is written.
+        assert_eq!(read(&hello), "Hello world\n");
+        assert_eq!(read(&numbers), "one\ntwo\n");
+        assert_eq!(fs::readdir(dir.path()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn sorting_is_deterministic() {
+        let sorted = vec![change(2, 2, "y"),
+                          change(4, 4, "a"),
+                          change(4, 4, "b"),
+                          change(4, 4, "c"),
+                          change(4, 6, "x")];
+        let mut rng = Rng(0x2545F491);
+        for _ in 0..100 {
+            // A random permutation of `sorted`.
+            let mut changes = sorted.clone();
+            for i in (1..changes.len()).rev() {
+                let j = rng.next(i as u32 + 1) as usize;
+                changes.swap(i, j);
+            }
+
+            let mut resorted = changes.clone();
+            resorted.sort();
+            assert_eq!(resorted, sorted);
+            assert_eq!(normalize(set(changes.clone())).unwrap().into_vec(), sorted);
+            assert_eq!(normalize_and_apply("aaaabbbb", changes), Ok("aayaaabcxbb".to_string()));
+        }
+    }
+
+    #[test]
+    fn a_matching_guard_applies() {
+        let (_dir, file) = temp_file("Hello world\n");
+        let guarded =
+            Change::guarded(file.clone(), 0, 5, "Hello".to_string(), "Goodbye".to_string());
+        reprint_with(set(vec![guarded]), ReprintOptions::default()).unwrap();
+        assert_eq!(read(&file), "Goodbye world\n");
+    }
+
+    #[test]
+    fn a_failed_guard_leaves_the_file_untouched() {
+        let (_dir, file) = temp_file("Hello world\n");
+        let guarded =
+            Change::guarded(file.clone(), 6, 11, "earth".to_string(), "there".to_string());
+        match reprint_with(set(vec![guarded, change_to(&file, 0, 5, "Hi")]),
+                           ReprintOptions::default()) {
+            Err(ReprintError::GuardFailed { index: 1, ref expected, ref found })
+                if *expected == "earth" && *found == "world" => {}
+            result => panic!("expected the guard to fail, got {:?}", result)
+        }
+        assert_eq!(read(&file), "Hello world\n");
+    }
+
+    #[test]
+    fn wrapping_every_occurrence() {
+        let input = "foo bar foofoo";
+        let changes = wrap_all(&path(), input, "foo", "<", ">");
+        // Including the adjacent occurrences at the end.
+        assert_eq!(changes.to_vec(), vec![change(0, 3, "<foo>"),
+                                          change(8, 11, "<foo>"),
+                                          change(11, 14, "<foo>")]);
+        assert_eq!(normalize(changes).and_then(|changes| apply(input, &changes)),
+                   Ok("<foo> bar <foo><foo>".to_string()));
+    }
+
+    #[test]
+    fn wrapping_overlapping_occurrences_wraps_the_first() {
+        assert_eq!(wrap_all(&path(), "aaa", "aa", "(", ")").to_vec(), vec![change(0, 2, "(aa)")]);
+    }
+
+    #[test]
+    fn a_no_op_edit_keeps_the_backup_identical_to_the_file() {
+        let (dir, file) = temp_file("Hello, world");
+        let backup = with_suffix(&file, ".bk.1");
+        write(&backup, "Hello, world");
+        let opts = |&:| ReprintOptions::builder().backup_rotation(2).build();
+
+        // An empty set has no files to summarize.
+        assert!(reprint_with(ChangeSet::new(), opts()).unwrap().is_empty());
+        assert_eq!(read(&backup), read(&file));
+
+        let summaries = reprint_with(set(vec![change_to(&file, 0, 5, "Hello")]), opts()).unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert!(!summaries[0].written);
+        assert_eq!(read(&file), "Hello, world");
+        assert_eq!(read(&backup), read(&file));
+        // No backup was made or rotated.
+        assert_eq!(fs::readdir(dir.path()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn a_no_op_edit_leaves_an_older_backup_alone() {
+        let (_dir, file) = temp_file("Hello, world");
+        let opts = |&:| ReprintOptions::builder().backup_rotation(2).build();
+        reprint_with(set(vec![change_to(&file, 0, 5, "Goodbye")]), opts()).unwrap();
+        let backup = with_suffix(&file, ".bk.1");
+
+        let summaries = reprint_with(set(vec![change_to(&file, 0, 7, "Goodbye")]), opts()).unwrap();
+        assert!(summaries.iter().all(|s| !s.written));
+        assert_eq!(read(&file), "Goodbye, world");
+        assert_eq!(read(&backup), "Hello, world");
+        assert!(!with_suffix(&file, ".bk.2").exists());
+    }
+
+    #[test]
+    fn same_range_different_text_conflicts_with_either_key() {
+        for &key in [DedupKey::Full, DedupKey::RangeOnly].iter() {
+            let changes = set(vec![change(0, 5, "a"), change(7, 8, "c"), change(0, 5, "b")]);
+            match normalize_with(changes, key) {
+                Err(ReprintError::SameStartConflict { start: 0 }) => {}
+                result => panic!("expected a conflict with {:?}, got {:?}",
+                                 key,
+                                 result.map(|c| c.into_vec()))
+            }
+        }
+    }
+
+    #[test]
+    fn range_only_keeps_the_first_change_to_a_range() {
+        let guarded = Change::guarded(path(), 0, 5, "Hello".to_string(), "Bye".to_string());
+        let changes = vec![change(0, 5, "Bye"),
+                           change(5, 5, "x"),
+                           guarded.clone(),
+                           change(5, 5, "y"),
+                           change(5, 5, "x")];
+
+        // Two replacements of the same range which differ only in their guard
+        // aren't duplicates with Full.
+        match normalize_with(set(changes.clone()), DedupKey::Full) {
+            Err(ReprintError::SameStartConflict { start: 0 }) => {}
+            result => panic!("expected a conflict, got {:?}", result.map(|c| c.into_vec()))
+        }
+
+        // Identical insertions are duplicates, but ones with different text
+        // aren't.
+        let normalized = normalize_with(set(changes.clone()), DedupKey::RangeOnly).unwrap();
+        assert_eq!(normalized.into_vec(),
+                   vec![change(0, 5, "Bye"), change(5, 5, "x"), change(5, 5, "y")]);
+        let mut reversed = changes;
+        reversed.reverse();
+        let normalized = normalize_with(set(reversed), DedupKey::RangeOnly).unwrap();
+        assert_eq!(normalized.into_vec(), vec![guarded, change(5, 5, "x"), change(5, 5, "y")]);
+    }
+
+    #[cfg(all(feature = "fd", unix))]
+    #[test]
+    fn editing_a_file_through_its_descriptor() {
+        use std::io::{FileAccess, FileMode};
+        use std::os::unix::AsRawFd;
+        use super::reprint_fd;
+
+        let (dir, file) = temp_file("Hello, world\n");
+        let f = File::open_mode(&file, FileMode::Open, FileAccess::ReadWrite).unwrap();
+        let fd = f.as_raw_fd();
+
+        // The changes' paths are ignored.
+        let summary = reprint_fd(fd, set(vec![change(0, 5, "Goodbye")])).unwrap();
+        assert_eq!(summary.path, Path::new(format!("/dev/fd/{}", fd)));
+        assert_eq!(summary.bytes_written, 15);
+        assert_eq!(read(&file), "Goodbye, world\n");
+
+        // A shorter output truncates the file. The descriptor is still open.
+        reprint_fd(fd, set(vec![change(0, 7, "Hi")])).unwrap();
+        assert_eq!(read(&file), "Hi, world\n");
+        // Written in place, with no temp file or backup.
+        assert_eq!(fs::readdir(dir.path()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn skipped_changes_are_written_to_the_rejects_file() {
+        let (dir, file) = temp_file("let a = 1;\n    // let b = 2;\nlet c = 3;\n");
+        let rejects = dir.path().join("edits.rej");
+        let opts = |&:| {
+            ReprintOptions::builder()
+                .skip_line_prefix("//".to_string())
+                .write_rejects(rejects.clone())
+                .build()
+        };
+
+        // Nothing is skipped, so there are no rejects.
+        reprint_with(set(vec![change_to(&file, 4, 5, "x")]), opts()).unwrap();
+        assert!(!rejects.exists());
+
+        let changes = set(vec![change_to(&file, 22, 23, "y"),
+                               change_to(&file, 33, 34, "z"),
+                               change_to(&file, 26, 26, "\"b\"")]);
+        reprint_with(changes, opts()).unwrap();
+        assert_eq!(read(&file), "let x = 1;\n    // let b = 2;\nlet z = 3;\n");
+        let reason = "# touches a line starting with `//`\n";
+        assert_eq!(read(&rejects),
+                   format!("--- {}\n{}22..23=\"y\"\n{}26..26=\"\\\"b\\\"\"\n",
+                           file.display(),
+                           reason,
+                           reason));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn replace_regex_in_a_tree() {
+        use regex::Regex;
+        use super::replace_regex_in_tree;
+
+        let dir = TempDir::new("reprint-test").unwrap();
+        let root = dir.path();
+        fs::mkdir(&root.join("sub"), USER_RWX).unwrap();
+        write(&root.join("a.rs"), "let x = foo(1, 2);\n");
+        write(&root.join("sub").join("b.rs"), "foo(3, 4); foo(5, 6);\n");
+        write(&root.join("c.txt"), "foo(7, 8)\n");
+        write(&root.join("d.rs"), "foo(9, 10);\n");
+        // Something else's temp file is in the way of editing d.rs.
+        write(&root.join("d.rs.tmp"), "in the way");
+        write(&root.join("e.rs"), "bar();\n");
+
+        let re = Regex::new(r"foo\((\d+), (\d+)\)").unwrap();
+        let results = replace_regex_in_tree(root, "*.rs", &re, "foo($2, $1)");
+        let paths: Vec<Path> = results.iter().map(|&(ref path, _)| path.clone()).collect();
+        assert_eq!(paths,
+                   vec![root.join("a.rs"), root.join("d.rs"), root.join("sub").join("b.rs")]);
+        assert!(results[0].1.is_ok());
+        match results[1].1 {
+            Err(ReprintError::AlreadyExists(_)) => {}
+            ref result => panic!("expected the temp file to exist, got {:?}", result)
+        }
+        assert!(results[2].1.is_ok());
+
+        assert_eq!(read(&root.join("a.rs")), "let x = foo(2, 1);\n");
+        assert_eq!(read(&root.join("sub").join("b.rs")), "foo(4, 3); foo(6, 5);\n");
+        assert_eq!(read(&root.join("c.txt")), "foo(7, 8)\n");
+        assert_eq!(read(&root.join("d.rs")), "foo(9, 10);\n");
+        assert_eq!(read(&root.join("e.rs")), "bar();\n");
+    }
+
+    #[test]
+    fn round_trip_of_a_clean_change_set() {
+        let input = "one\ntwo\nthree\n";
+        assert_eq!(verify_roundtrip(input, &set(vec![change(4, 7, "TWO"),
+                                                     change(14, 14, "four\n")])),
+                   Ok(()));
+        assert_eq!(verify_roundtrip(input, &ChangeSet::new()), Ok(()));
+
+        let input = "abcdefghij\nklm\n";
+        let mut rng = Rng(0x2545F491);
+        for _ in 0..200 {
+            if let Some(changes) = random_changes(&mut rng, input.len() as u32, 4) {
+                assert_eq!(verify_roundtrip(input, &changes), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip_of_a_redundant_change_set() {
+        // A duplicate, a change to the same text, and two changes which the
+        // diff finds as one.
+        let input = "one\ntwo\nthree\n";
+        let changes = set(vec![change(8, 13, "3"),
+                               change(0, 3, "one"),
+                               change(8, 13, "3"),
+                               change(4, 5, "T"),
+                               change(5, 7, "WO")]);
+        assert_eq!(verify_roundtrip(input, &changes), Ok(()));
+
+        // Overlapping changes still fail.
+        match verify_roundtrip(input, &set(vec![change(0, 5, "a"), change(3, 8, "b")])) {
+            Err(ReprintError::Verification(_)) => {}
+            result => panic!("expected an overlap, got {:?}", result)
+        }
+    }
 }
@@ -0,0 +1,122 @@
+// Copyright 2015 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![feature(slicing_syntax)]
+#![allow(unstable)]
+
+extern crate reprint;
+
+use reprint::{Change, ChangeSet, ReprintOptions};
+
+fn usage(program: &str, diag: &mut Writer) {
+    let _ = writeln!(diag, "Usage: {} - <changes>", program);
+    let _ = writeln!(diag, "       {} <file> --edit <change> [--edit <change> ...]", program);
+}
+
+// Runs the command line `args`, writing any errors (and the usage) to `diag`
+// rather than straight to stderr, so they can be captured. Whether it
+// succeeded.
+fn run(args: &[String], diag: &mut Writer) -> bool {
+    // `reprint - <changes>` edits stdin to stdout, taking the changes as a JSON
+    // array of `{"start": _, "end": _, "text": _}` objects.
+    if args.len() > 1 && &args[1][] == "-" {
+        if args.len() != 3 {
+            usage(&args[0][], diag);
+            return false;
+        }
+
+        let result = reprint::decode_changes(&Path::new("-"), &args[2][]).and_then(|changes| {
+            reprint::reprint_stream(&mut std::io::stdin(), &mut std::io::stdout(), changes)
+        });
+        if let Err(e) = result {
+            let _ = writeln!(diag, "{}", e);
+            return false;
+        }
+        return true;
+    }
+
+    // `reprint <file> --edit <change> ...` edits a file in place, taking each
+    // change in the form accepted by Change::parse.
+    if args.len() > 2 && &args[2][] == "--edit" {
+        let path = Path::new(&args[1][]);
+        let mut changes = ChangeSet::new();
+        let mut i = 2;
+        while i < args.len() {
+            if &args[i][] != "--edit" || i + 1 == args.len() {
+                usage(&args[0][], diag);
+                return false;
+            }
+            match Change::parse(path.clone(), &args[i + 1][]) {
+                Ok(change) => changes.push(change),
+                Err(e) => {
+                    let _ = writeln!(diag, "{}", e);
+                    return false;
+                }
+            }
+            i += 2;
+        }
+
+        if let Err(e) = reprint::reprint_with(changes, ReprintOptions::default()) {
+            let _ = writeln!(diag, "{}", e);
+            return false;
+        }
+        return true;
+    }
+
+    usage(&args[0][], diag);
+    false
+}
+
+fn main() {
+    let args = std::os::args();
+    if !run(&args[], &mut std::io::stderr()) {
+        std::os::set_exit_status(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{File, TempDir};
+
+    use super::run;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn usage_goes_to_the_diagnostic_sink() {
+        let mut diag = Vec::new();
+        assert!(!run(&args(&["reprint", "-"])[], &mut diag));
+        let diag = String::from_utf8(diag).unwrap();
+        assert!(diag.starts_with("Usage: reprint - <changes>\n"), "{}", diag);
+    }
+
+    #[test]
+    fn errors_go_to_the_diagnostic_sink() {
+        let mut diag = Vec::new();
+        assert!(!run(&args(&["reprint", "foo.rs", "--edit", "3..8"])[], &mut diag));
+        assert_eq!(String::from_utf8(diag).unwrap(),
+                   "Verification error: Malformed change '3..8': expected `=`\n");
+    }
+
+    #[test]
+    fn a_successful_edit_writes_no_diagnostics() {
+        let dir = TempDir::new("reprint").unwrap();
+        let path = dir.path().join("foo.rs");
+        File::create(&path).write_str("Hello world").unwrap();
+
+        let mut diag = Vec::new();
+        let path_arg = path.as_str().unwrap();
+        assert!(run(&args(&["reprint", path_arg, "--edit", "0..5=\"Goodbye\""])[], &mut diag));
+        assert!(diag.is_empty());
+        assert_eq!(File::open(&path).read_to_string().unwrap(), "Goodbye world");
+    }
+}